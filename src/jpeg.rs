@@ -1,7 +1,226 @@
-use crate::{pixel::{Pixel, Pixels, ColorType}, png::DecPng};
+use crate::{pixel::{ColorType, Pixel, Pixels, Subsampling}, png::{DecPng, PngReader, WriteToPPM}};
+use crate::utils::{self, Bits, Huffman, JpegSymbol, ProgressiveAcCoder, ProgressiveAcDecoder, ScanCode};
 use std::fs;
-use crate::utils;
+use std::io::{BufWriter, Write};
 
+// Typical huffman tables from Annex K.3 of the JPEG specification. Each table is stored as the
+// count of codes with each length from 1 to 16 bits, followed by the symbols in code order.
+const LUMA_DC_BITS : [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const LUMA_DC_VALUES : [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const CHROM_DC_BITS : [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const CHROM_DC_VALUES : [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const LUMA_AC_BITS : [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+const LUMA_AC_VALUES : [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+const CHROM_AC_BITS : [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const CHROM_AC_VALUES : [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+    0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+    0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+    0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+    0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+    0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+/// A huffman table as it is stored in a DHT segment, along with the code it describes.
+pub struct JpegHuffmanTable {
+    /// bits[n] is the number of codes that are n + 1 bits long.
+    bits : [u8; 16],
+
+    /// Symbols in code order - shorter codes first.
+    values : Vec<u8>,
+    huffman : Huffman,
+}
+
+impl JpegHuffmanTable {
+    pub fn new(bits: [u8; 16], values: &[u8]) -> Result<JpegHuffmanTable, &'static str> {
+        if bits.iter().map(|&x| x as usize).sum::<usize>() != values.len() {
+            return Err("JPEG huffman table has a different number of codes and symbols!");
+        }
+
+        let mut lengths_with_symbols : Vec<Vec<u32>> = vec![vec![]; 17];
+        let mut symbols = values.iter();
+
+        for (index, &count) in bits.iter().enumerate() {
+            lengths_with_symbols[index + 1] = symbols.by_ref().take(count as usize).map(|&x| x as u32).collect();
+        }
+
+        Ok(
+            JpegHuffmanTable {
+                bits,
+                values : values.to_vec(),
+                huffman : Huffman::generate_from_ordered_length_symbols(lengths_with_symbols)?,
+            }
+        )
+    }
+
+    /// Builds the optimal table for symbols that appear frequencies[n] times, as described in
+    /// Annex K.2. Codes are limited to 16 bits and no symbol gets a code of only 1 bits, which
+    /// is done by giving a reserved symbol the longest code and leaving it out of the table.
+    pub fn from_frequencies(frequencies: &[u32]) -> Result<JpegHuffmanTable, &'static str> {
+        if frequencies.len() > 256 {
+            return Err("JPEG huffman symbols must fit in a byte!");
+        }
+
+        let mut frequencies = frequencies.to_vec();
+        frequencies.resize(257, 0);
+        frequencies[256] = 1;
+
+        let lengths = Huffman::code_lengths_from_frequencies(&frequencies, 16);
+        let mut symbols : Vec<usize> = (0..256).filter(|&x| lengths[x] > 0).collect();
+        symbols.sort_by_key(|&x| (lengths[x], x));
+
+        let mut bits = [0u8; 16];
+        for &symbol in symbols.iter() {
+            bits[lengths[symbol] as usize - 1] += 1;
+        }
+
+        let values : Vec<u8> = symbols.into_iter().map(|x| x as u8).collect();
+
+        Self::new(bits, &values)
+    }
+
+    pub fn luma_dc() -> JpegHuffmanTable {
+        Self::new(LUMA_DC_BITS, &LUMA_DC_VALUES).expect("Annex K tables are valid")
+    }
+
+    pub fn luma_ac() -> JpegHuffmanTable {
+        Self::new(LUMA_AC_BITS, &LUMA_AC_VALUES).expect("Annex K tables are valid")
+    }
+
+    pub fn chrom_dc() -> JpegHuffmanTable {
+        Self::new(CHROM_DC_BITS, &CHROM_DC_VALUES).expect("Annex K tables are valid")
+    }
+
+    pub fn chrom_ac() -> JpegHuffmanTable {
+        Self::new(CHROM_AC_BITS, &CHROM_AC_VALUES).expect("Annex K tables are valid")
+    }
+
+    pub fn get_huffman(&self) -> &Huffman {
+        &self.huffman
+    }
+
+    /// Appends a DHT segment to bytes defining this table. class_and_id holds the table class
+    /// (0 for DC, 1 for AC) in the high four bits and the table destination in the low four bits.
+    pub fn append_dht(&self, bytes: &mut Vec<u8>, class_and_id: u8) {
+        let length = 2 + 1 + self.bits.len() + self.values.len();
+
+        // DHT
+        bytes.append(&mut vec![0xFF, 0xC4]);
+        // Lh (length [2])
+        bytes.append(&mut (length as u16).to_be_bytes().to_vec());
+        // Tc, Th [1]
+        bytes.push(class_and_id);
+        // L1..L16 [16]
+        bytes.extend_from_slice(&self.bits);
+        // V1..Vn
+        bytes.extend_from_slice(&self.values);
+    }
+}
+
+/// Appends a DQT segment to bytes defining one 8 bit quantization table with the given destination.
+fn append_dqt(bytes: &mut Vec<u8>, id: u8, table: Vec<Vec<i32>>) {
+    // DQT
+    bytes.append(&mut vec![0xFF, 0xDB]);
+    // Lq (length [2]) (67)
+    bytes.append(&mut vec![0, 67]);
+    // Pq, Tq [1]
+    bytes.push(id);
+    // Q0..Q63 [64] in zig-zag order
+    bytes.append(&mut utils::zig_zag(table).into_iter().map(|x| x as u8).collect());
+}
+
+/// Returns the class (0 for DC, 1 for AC) and destination of a table from the order huffman
+/// tables are kept in - luma DC, luma AC, chroma DC and chroma AC.
+fn dht_class_and_id(index: usize) -> u8 {
+    (((index % 2) << 4) | (index / 2)) as u8
+}
+
+/// Pads the final byte of an entropy coded segment with 1 bits and stuffs it.
+fn finish_scan(mut stream: Bits) -> Vec<u8> {
+    let padding = ((8 - stream.bit_count() % 8) % 8) as u32;
+    stream.write_bits((1 << padding) - 1, padding);
+
+    stuff_bytes(stream.into_bytes())
+}
+
+/// Appends an SOS header to bytes for a scan of the given components, which are numbered from 0.
+fn append_sos(bytes: &mut Vec<u8>, scan: &JpegScan) {
+    // SOS
+    bytes.append(&mut vec![0xFF, 0xDA]);
+    // Length [2] (6 + 2 per component)
+    bytes.append(&mut (6 + 2 * scan.components.len() as u16).to_be_bytes().to_vec());
+    // Ns [1]
+    bytes.push(scan.components.len() as u8);
+
+    for &component in scan.components.iter() {
+        // Csj, Tdj, Taj [2] (luma uses tables 0, chroma uses tables 1)
+        bytes.push(component as u8 + 1);
+        bytes.push(match component {
+            0 => 0,
+            _ => 0b00010001,
+        });
+    }
+
+    // Ss, Se, Ah, Al [3]
+    bytes.push(scan.spectral_start as u8);
+    bytes.push(scan.spectral_end as u8);
+    bytes.push(((scan.approx_high << 4) | scan.approx_low) as u8);
+}
+
+/// Joins the entropy coded segments of a scan's restart intervals with RST markers, which count
+/// from RST0 to RST7 and then wrap around.
+fn join_restart_intervals(intervals: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut bytes = vec![];
+
+    for (index, mut interval) in intervals.into_iter().enumerate() {
+        if index > 0 {
+            bytes.append(&mut vec![0xFF, 0xD0 + ((index - 1) % 8) as u8]);
+        }
+
+        bytes.append(&mut interval);
+    }
+
+    bytes
+}
+
+/// Inserts a 0x00 byte after every 0xFF byte in entropy coded data so it can't be mistaken for a
+/// marker.
+fn stuff_bytes(bytes: Vec<u8>) -> Vec<u8> {
+    let mut stuffed = vec![];
+
+    for byte in bytes {
+        stuffed.push(byte);
+
+        if byte == 0xFF {
+            stuffed.push(0x00);
+        }
+    }
+
+    stuffed
+}
+
+/// A minimum coded unit (MCU) of YCbCr samples. With 4:2:0 subsampling the luma samples cover a
+/// 16x16 area of the image while each chroma component covers the same area with 8x8 samples.
 pub struct JpegBlock {
     luma : Vec<Vec<u8>>,
     diff_blue : Vec<Vec<u8>>,
@@ -10,38 +229,597 @@ pub struct JpegBlock {
 
 impl JpegBlock {
     pub fn new(luma : Vec<Vec<u8>>, diff_blue : Vec<Vec<u8>>, diff_red : Vec<Vec<u8>>) -> JpegBlock {
-        //4 : 2 : 0 only
         JpegBlock {
             luma,
             diff_blue,
             diff_red
         }
-    } 
+    }
+
+    /// Splits the luma samples into 8x8 blocks in the order they are coded within the MCU - left
+    /// to right, then top to bottom.
+    pub fn get_luma_blocks(&self) -> Vec<Vec<Vec<u8>>> {
+        let mut blocks = vec![];
+
+        for block_row in (0..self.luma.len()).step_by(8) {
+            for block_col in (0..self.luma[block_row].len()).step_by(8) {
+                blocks.push(
+                    self.luma[block_row..block_row + 8]
+                        .iter()
+                        .map(|row| row[block_col..block_col + 8].to_vec())
+                        .collect()
+                );
+            }
+        }
+
+        blocks
+    }
 }
 
 pub struct JpegBlocks(Vec<JpegBlock>);
 
-impl From<Pixels> for JpegBlocks {
-    
-    fn from(pixels : Pixels) -> JpegBlocks {
-        //expects 4:2:0 compression
-        let blocks = vec![];
+impl JpegBlocks {
+    /// Splits subsampled YCbCr pixels into MCUs. Each MCU covers 8 pixels for every luma sampling
+    /// factor in each direction, and its chroma blocks are taken from the top left pixel of every
+    /// box of pixels that share a chroma sample.
+    pub fn from_pixels(pixels : Pixels, subsampling : Subsampling) -> JpegBlocks {
+        let (h_factor, v_factor) = subsampling.get_factors();
+        let mut blocks = vec![];
+        let height = pixels.len();
+        let width = pixels[0].len();
+
+        for mcu_row in (0..height).step_by(8 * v_factor) {
+            for mcu_col in (0..width).step_by(8 * h_factor) {
+                // MCUs that hang over the edge of the image repeat the last row and column of
+                // pixels, which compresses better than padding with a constant.
+                let sample = |y : usize, x : usize, channel : usize| {
+                    pixels[(mcu_row + y).min(height - 1)][(mcu_col + x).min(width - 1)].get_color_values()[channel]
+                };
+
+                let luma = (0..8 * v_factor).map(|y| (0..8 * h_factor).map(|x| sample(y, x, 0)).collect()).collect();
+                let diff_blue = (0..8).map(|y| (0..8).map(|x| sample(v_factor * y, h_factor * x, 1)).collect()).collect();
+                let diff_red = (0..8).map(|y| (0..8).map(|x| sample(v_factor * y, h_factor * x, 2)).collect()).collect();
+
+                blocks.push(JpegBlock::new(luma, diff_blue, diff_red));
+            }
+        }
+
         JpegBlocks(blocks)
     }
 }
 
+/// One scan of a progressive JPEG, which sends part of the coefficients of some of the
+/// components. Components are numbered from 0 in frame order - luma, then blue and red chroma.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JpegScan {
+    components : Vec<usize>,
+
+    /// First and last zig-zag coefficient in the scan (Ss and Se).
+    spectral_start : usize,
+    spectral_end : usize,
+
+    /// Point transform of the previous scan of these coefficients (Ah) and of this one (Al).
+    approx_high : u32,
+    approx_low : u32,
+}
+
+impl JpegScan {
+    pub fn new(components: Vec<usize>, spectral_start: usize, spectral_end: usize, approx_high: u32, approx_low: u32) -> JpegScan {
+        JpegScan {
+            components,
+            spectral_start,
+            spectral_end,
+            approx_high,
+            approx_low,
+        }
+    }
+
+    /// Returns the scan script used when no other is given. It is the same as the one libjpeg
+    /// uses - DC first, then the low luma frequencies, and the rest of the bits refined last.
+    pub fn default_script(component_count: usize) -> Vec<JpegScan> {
+        match component_count {
+            1 => vec![
+                JpegScan::new(vec![0], 0, 0, 0, 1),
+                JpegScan::new(vec![0], 1, 5, 0, 2),
+                JpegScan::new(vec![0], 6, 63, 0, 2),
+                JpegScan::new(vec![0], 1, 63, 2, 1),
+                JpegScan::new(vec![0], 0, 0, 1, 0),
+                JpegScan::new(vec![0], 1, 63, 1, 0),
+            ],
+            _ => vec![
+                JpegScan::new(vec![0, 1, 2], 0, 0, 0, 1),
+                JpegScan::new(vec![0], 1, 5, 0, 2),
+                JpegScan::new(vec![2], 1, 63, 0, 1),
+                JpegScan::new(vec![1], 1, 63, 0, 1),
+                JpegScan::new(vec![0], 6, 63, 0, 2),
+                JpegScan::new(vec![0], 1, 63, 2, 1),
+                JpegScan::new(vec![0, 1, 2], 0, 0, 1, 0),
+                JpegScan::new(vec![2], 1, 63, 1, 0),
+                JpegScan::new(vec![1], 1, 63, 1, 0),
+                JpegScan::new(vec![0], 1, 63, 1, 0),
+            ],
+        }
+    }
+
+    /// Reads a scan script in the format libjpeg's cjpeg uses, where each scan is written as
+    /// "components: Ss-Se, Ah, Al;" - for example "0,1,2: 0-0, 0, 1;". Anything after a '#' on
+    /// a line is a comment.
+    pub fn parse_script(script: &str) -> Result<Vec<JpegScan>, &'static str> {
+        let script : String = script
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or(""))
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        let numbers = |text: &str| -> Result<Vec<usize>, &'static str> {
+            text.split(|x: char| !x.is_ascii_digit())
+                .filter(|x| !x.is_empty())
+                .map(|x| x.parse().map_err(|_| "Scan script number is too large!"))
+                .collect()
+        };
+
+        let mut scans = vec![];
+
+        for scan in script.split(';').filter(|x| !x.trim().is_empty()) {
+            let (components, parameters) = scan.split_once(':').ok_or("Scan is missing a ':' after its components!")?;
+
+            match numbers(parameters)?.as_slice() {
+                &[start, end, high, low] => scans.push(JpegScan::new(numbers(components)?, start, end, high as u32, low as u32)),
+                _ => return Err("Scan must have Ss-Se, Ah and Al after its components!"),
+            }
+        }
+
+        Ok(scans)
+    }
+
+    /// Checks that a scan script is valid for a progressive image and that it sends every bit of
+    /// every coefficient. Coefficients have to be sent in order - the first scan of a coefficient
+    /// has an Ah of 0 and every later scan has an Ah equal to the Al of the scan before it, and
+    /// no AC coefficient can be sent before the first DC scan of its component.
+    pub fn validate_script(scans: &[JpegScan], component_count: usize) -> Result<(), &'static str> {
+        // The Al each coefficient of each component was last sent with.
+        let mut sent : Vec<[Option<u32>; 64]> = vec![[None; 64]; component_count];
+
+        for scan in scans {
+            if scan.components.is_empty() || scan.components.len() > 4 {
+                return Err("Scans must have between 1 and 4 components!");
+            }
+
+            if scan.components.windows(2).any(|x| x[0] >= x[1]) || scan.components.iter().any(|&x| x >= component_count) {
+                return Err("Scan components must exist and be in frame order!");
+            }
+
+            if scan.spectral_start > scan.spectral_end || scan.spectral_end > 63 {
+                return Err("Scan spectral selection must be within 0 to 63!");
+            }
+
+            if (scan.spectral_start == 0) != (scan.spectral_end == 0) {
+                return Err("DC and AC coefficients can't be in the same progressive scan!");
+            }
+
+            if scan.spectral_start > 0 && scan.components.len() > 1 {
+                return Err("AC scans can only have one component!");
+            }
+
+            if scan.approx_low > 13 || (scan.approx_high != 0 && scan.approx_high != scan.approx_low + 1) {
+                return Err("Successive approximation must lower Al by one bit at a time!");
+            }
+
+            for &component in scan.components.iter() {
+                if scan.spectral_start > 0 && sent[component][0].is_none() {
+                    return Err("AC coefficients can't be sent before the DC coefficient!");
+                }
+
+                for coefficient in sent[component][scan.spectral_start..=scan.spectral_end].iter_mut() {
+                    let expected = match scan.approx_high {
+                        0 => None,
+                        x => Some(x),
+                    };
+
+                    if *coefficient != expected {
+                        return Err("Scan sends coefficient bits out of order!");
+                    }
+
+                    *coefficient = Some(scan.approx_low);
+                }
+            }
+        }
+
+        if sent.iter().flatten().any(|&x| x != Some(0)) {
+            return Err("Scan script doesn't send every bit of every coefficient!");
+        }
+
+        Ok(())
+    }
+}
+
 pub struct EncJpeg {
+    height : u16,
+    width : u16,
+
+    /// YCbCr pixels of the image. They are turned into MCUs when the file is written, so the
+    /// settings below can be changed after the encoder is made.
+    pixels : Pixels,
+
+    /// IJG quality from 1 to 100 that the quantization tables are scaled by.
+    quality : u32,
+    subsampling : Subsampling,
 
+    /// Whether only the luma component is written.
+    grayscale : bool,
+
+    /// Whether the huffman tables are built from the symbols in this image instead of using the
+    /// typical tables from Annex K.
+    optimize_huffman : bool,
+
+    /// Scans of a progressive image. Baseline images are written when there are none.
+    scan_script : Option<Vec<JpegScan>>,
+
+    /// Number of MCUs between restart markers, or 0 to write none.
+    restart_interval : u16,
 }
 
 impl EncJpeg {
-    pub fn write_to_path(&self, path : String) {
-        let mut write_bytes : Vec<u8> = vec![];
-        let lines_in_image = 0;
-        let samples_per_line = 0;
+    pub fn set_optimize_huffman(&mut self, optimize_huffman: bool) {
+        self.optimize_huffman = optimize_huffman;
+    }
+
+    pub fn set_quality(&mut self, quality: u32) -> Result<(), &'static str> {
+        if !(1..=100).contains(&quality) {
+            return Err("JPEG quality must be between 1 and 100!");
+        }
+
+        self.quality = quality;
+        Ok(())
+    }
+
+    pub fn set_subsampling(&mut self, subsampling: Subsampling) {
+        self.subsampling = subsampling;
+    }
+
+    /// Makes the encoder write a progressive image with the given scans, or a baseline image if
+    /// there are none.
+    pub fn set_scan_script(&mut self, scan_script: Option<Vec<JpegScan>>) -> Result<(), &'static str> {
+        if let Some(scans) = scan_script.as_ref() {
+            JpegScan::validate_script(scans, self.get_component_count())?;
+        }
+
+        self.scan_script = scan_script;
+        Ok(())
+    }
+
+    /// Makes the encoder write a DRI segment and an RST marker every restart_interval MCUs,
+    /// or no markers when it is 0.
+    pub fn set_restart_interval(&mut self, restart_interval: u16) {
+        self.restart_interval = restart_interval;
+    }
+
+    /// Makes the encoder write a progressive image with the default scan script.
+    pub fn set_progressive(&mut self) {
+        self.scan_script = Some(JpegScan::default_script(self.get_component_count()));
+    }
+
+    /// Grayscale images have no chroma to subsample, and a scan with a single component always
+    /// has MCUs of one block, so they are treated as 4:4:4.
+    fn get_subsampling(&self) -> Subsampling {
+        match self.grayscale {
+            true => Subsampling::S444,
+            false => self.subsampling,
+        }
+    }
+
+    fn get_component_count(&self) -> usize {
+        match self.grayscale {
+            true => 1,
+            false => 3,
+        }
+    }
+
+    /// Returns the number of luma blocks in each MCU.
+    fn luma_blocks_per_mcu(&self) -> usize {
+        let (h_factor, v_factor) = self.get_subsampling().get_factors();
+        h_factor * v_factor
+    }
+
+    fn luma_quant_table(&self) -> Vec<Vec<i32>> {
+        utils::scale_quant_table(utils::luma_quant_table(), self.quality)
+    }
+
+    fn chrom_quant_table(&self) -> Vec<Vec<i32>> {
+        utils::scale_quant_table(utils::chrom_quant_table(), self.quality)
+    }
+
+    /// Returns the quantized, zig-zag ordered coefficients of every MCU covering pixels, which is
+    /// either the whole image or a row of MCUs from it. Each MCU holds its luma blocks followed
+    /// by its blue and red chroma blocks, unless the image is grayscale.
+    fn mcus(&self, pixels: &Pixels) -> Vec<Vec<Vec<i32>>> {
+        let luma_table = self.luma_quant_table();
+        let chrom_table = self.chrom_quant_table();
+
+        //subsample
+        let subsampling = self.get_subsampling();
+        let subsampled = pixels.subsample_ycbcr(subsampling);
+        let blocks = JpegBlocks::from_pixels(subsampled, subsampling);
+
+        //DCT, quantize and reorder every block so it is ready to be entropy coded
+        blocks.0
+            .into_iter()
+            .map(|block| {
+                let mut mcu : Vec<Vec<i32>> = block
+                    .get_luma_blocks()
+                    .into_iter()
+                    .map(|luma| utils::zig_zag(utils::quantize(utils::dct(luma), &luma_table)))
+                    .collect();
+
+                if !self.grayscale {
+                    mcu.push(utils::zig_zag(utils::quantize(utils::dct(block.diff_blue), &chrom_table)));
+                    mcu.push(utils::zig_zag(utils::quantize(utils::dct(block.diff_red), &chrom_table)));
+                }
+
+                mcu
+            })
+            .collect()
+    }
+
+    /// Appends the DHT segments of the huffman tables (DC 0, AC 0 - Luma, DC 1, AC 1 - Chroma).
+    /// Grayscale images only need the luma tables.
+    fn append_dhts(&self, bytes: &mut Vec<u8>, tables: &[JpegHuffmanTable; 4]) {
+        let table_count = match self.grayscale {
+            true => 2,
+            false => 4,
+        };
+
+        for (index, table) in tables.iter().enumerate().take(table_count) {
+            table.append_dht(bytes, dht_class_and_id(index));
+        }
+    }
+
+    /// Returns the number of MCUs in each restart interval of a scan with mcu_count MCUs.
+    fn restart_interval_length(&self, mcu_count: usize) -> usize {
+        match self.restart_interval {
+            0 => mcu_count.max(1),
+            x => x as usize,
+        }
+    }
+
+    /// Returns the symbols of every block of a baseline scan in scan order, along with the
+    /// component each block belongs to. DC coefficients are predicted from prev_dc, which is
+    /// left holding the last DC coefficient of each component - it starts again from 0 in each
+    /// restart interval.
+    fn block_symbols(&self, mcus: &[Vec<Vec<i32>>], prev_dc: &mut [i32; 3]) -> Vec<(usize, Vec<JpegSymbol>)> {
+        let mut symbols = vec![];
+        let luma_blocks = self.luma_blocks_per_mcu();
+
+        for mcu in mcus.iter() {
+            for (index, block) in mcu.iter().enumerate() {
+                // The luma blocks come first, followed by blue chroma and then red chroma.
+                let component = match index {
+                    x if x < luma_blocks => 0,
+                    x => x + 1 - luma_blocks,
+                };
+
+                symbols.push((component, utils::jpeg_block_symbols(block, prev_dc[component])));
+                prev_dc[component] = block[0];
+            }
+        }
+
+        symbols
+    }
+
+    /// Returns the MCU index and the index within the MCU of every block of a component, in the
+    /// order a scan of only that component codes them - left to right, then top to bottom.
+    ///
+    /// Such a scan only covers the blocks that hold part of the component, which can be fewer
+    /// than the MCUs hold when they hang over the edge of the image.
+    fn component_block_order(&self, component: usize) -> Vec<(usize, usize)> {
+        let (h_factor, v_factor) = self.get_subsampling().get_factors();
+        let (width, height) = (self.width as usize, self.height as usize);
+        let mcus_per_row = width.div_ceil(8 * h_factor);
+        let mut order = vec![];
+
+        match component {
+            0 => {
+                for block_row in 0..height.div_ceil(8) {
+                    for block_col in 0..width.div_ceil(8) {
+                        let mcu = (block_row / v_factor) * mcus_per_row + block_col / h_factor;
+                        order.push((mcu, (block_row % v_factor) * h_factor + block_col % h_factor));
+                    }
+                }
+            },
+            _ => {
+                for block_row in 0..height.div_ceil(v_factor).div_ceil(8) {
+                    for block_col in 0..width.div_ceil(h_factor).div_ceil(8) {
+                        order.push((block_row * mcus_per_row + block_col, h_factor * v_factor + component - 1));
+                    }
+                }
+            },
+        }
+
+        order
+    }
+
+    /// Returns the codes of each restart interval of one progressive scan, along with the
+    /// component each belongs to.
+    fn progressive_scan_codes(&self, mcus: &[Vec<Vec<i32>>], scan: &JpegScan) -> Vec<Vec<(usize, ScanCode)>> {
+        let luma_blocks = self.luma_blocks_per_mcu();
+        let block_indices = |component: usize| match component {
+            0 => (0..luma_blocks).collect::<Vec<usize>>(),
+            x => vec![luma_blocks + x - 1],
+        };
+
+        // Scans with several components are interleaved and code every block of the MCU in turn.
+        // Scans with one component code its blocks in raster order, and each block is an MCU.
+        let scan_mcus : Vec<Vec<(usize, &Vec<i32>)>> = match scan.components.as_slice() {
+            &[component] => self.component_block_order(component)
+                .into_iter()
+                .map(|(mcu, index)| vec![(component, &mcus[mcu][index])])
+                .collect(),
+            components => mcus
+                .iter()
+                .map(|mcu| components.iter().flat_map(|&x| block_indices(x).into_iter().map(move |index| (x, &mcu[index]))).collect())
+                .collect(),
+        };
+
+        // Only a single EOB can be coded with the typical tables.
+        let max_eob_run = match self.optimize_huffman {
+            true => 0x7FFF,
+            false => 1,
+        };
+
+        // DC prediction and EOB runs start over in each restart interval.
+        scan_mcus.chunks(self.restart_interval_length(scan_mcus.len())).map(|interval| {
+            let mut ac_coder = ProgressiveAcCoder::new(scan.spectral_start, scan.spectral_end, scan.approx_high, scan.approx_low, max_eob_run);
+            let mut prev_dc = [0i32; 3];
+            let mut codes = vec![];
+
+            for &(component, block) in interval.iter().flatten() {
+                let mut block_codes = vec![];
+
+                match (scan.spectral_start, scan.approx_high) {
+                    // DC coefficients are divided with an arithmetic shift
+                    (0, 0) => {
+                        let dc = block[0] >> scan.approx_low;
+                        block_codes.push(ScanCode::Dc(JpegSymbol::dc(dc - prev_dc[component])));
+                        prev_dc[component] = dc;
+                    },
+                    (0, _) => block_codes.push(ScanCode::Bits(((block[0] >> scan.approx_low) & 1) as u32, 1)),
+                    _ => ac_coder.code_block(block, &mut block_codes),
+                };
+
+                codes.extend(block_codes.into_iter().map(|x| (component, x)));
+            }
+
+            let mut block_codes = vec![];
+            ac_coder.finish(&mut block_codes);
+            codes.extend(block_codes.into_iter().map(|x| (scan.components[0], x)));
+
+            codes
+        }).collect()
+    }
+
+    /// Counts how many times each symbol of each huffman table is used by a baseline scan.
+    fn baseline_frequencies(intervals: &[Vec<(usize, Vec<JpegSymbol>)>]) -> [[u32; 256]; 4] {
+        let mut frequencies = [[0u32; 256]; 4];
+
+        for (component, block) in intervals.iter().flatten() {
+            let table = match component {
+                0 => 0,
+                _ => 2,
+            };
+
+            for (index, symbol) in block.iter().enumerate() {
+                match index {
+                    0 => frequencies[table][symbol.symbol as usize] += 1,
+                    _ => frequencies[table + 1][symbol.symbol as usize] += 1,
+                };
+            }
+        }
+
+        frequencies
+    }
+
+    /// Counts how many times each symbol of each huffman table is used by a progressive scan.
+    fn progressive_frequencies(intervals: &[Vec<(usize, ScanCode)>]) -> [[u32; 256]; 4] {
+        let mut frequencies = [[0u32; 256]; 4];
+
+        for (component, code) in intervals.iter().flatten() {
+            let table = match component {
+                0 => 0,
+                _ => 2,
+            };
+
+            match code {
+                ScanCode::Dc(symbol) => frequencies[table][symbol.symbol as usize] += 1,
+                ScanCode::Ac(symbol) => frequencies[table + 1][symbol.symbol as usize] += 1,
+                ScanCode::Bits(_, _) => (),
+            };
+        }
+
+        frequencies
+    }
+
+    /// Returns the luma DC, luma AC, chroma DC and chroma AC huffman tables, in that order.
+    ///
+    /// Optimized tables take two passes - the symbols of every scan are counted first, then
+    /// each table is built from the counts of the blocks that use it.
+    fn huffman_tables(&self, frequencies: &[[u32; 256]; 4]) -> Result<[JpegHuffmanTable; 4], &'static str> {
+        if !self.optimize_huffman {
+            return Ok([
+                JpegHuffmanTable::luma_dc(),
+                JpegHuffmanTable::luma_ac(),
+                JpegHuffmanTable::chrom_dc(),
+                JpegHuffmanTable::chrom_ac(),
+            ]);
+        }
+
+        Ok([
+            JpegHuffmanTable::from_frequencies(&frequencies[0])?,
+            JpegHuffmanTable::from_frequencies(&frequencies[1])?,
+            JpegHuffmanTable::from_frequencies(&frequencies[2])?,
+            JpegHuffmanTable::from_frequencies(&frequencies[3])?,
+        ])
+    }
+
+    /// Entropy codes the MCUs of one restart interval of a baseline scan, including padding and
+    /// byte stuffing.
+    fn encode_scan(&self, symbols: &[(usize, Vec<JpegSymbol>)], tables: &[JpegHuffmanTable; 4]) -> Result<Vec<u8>, &'static str> {
+        let mut stream = Bits::new(vec![], false, 0);
+        Self::code_symbols(symbols, tables, &mut stream)?;
+
+        Ok(finish_scan(stream))
+    }
+
+    /// Writes the huffman codes of the symbols of baseline blocks into stream.
+    fn code_symbols(symbols: &[(usize, Vec<JpegSymbol>)], tables: &[JpegHuffmanTable; 4], stream: &mut Bits) -> Result<(), &'static str> {
+        let [luma_dc, luma_ac, chrom_dc, chrom_ac] = tables.each_ref().map(|x| x.get_huffman());
+
+        for (component, block) in symbols.iter() {
+            let (dc_huff, ac_huff) = match component {
+                0 => (luma_dc, luma_ac),
+                _ => (chrom_dc, chrom_ac),
+            };
+
+            utils::create_jpeg_stream(block, dc_huff, ac_huff, stream)?;
+        }
+
+        Ok(())
+    }
+
+    /// Entropy codes one restart interval of a progressive scan, including padding and byte
+    /// stuffing.
+    fn encode_progressive_scan(&self, codes: &[(usize, ScanCode)], tables: &[JpegHuffmanTable; 4]) -> Result<Vec<u8>, &'static str> {
+        let mut stream = Bits::new(vec![], false, 0);
+
+        for (component, code) in codes.iter() {
+            let table = match component {
+                0 => 0,
+                _ => 2,
+            };
+
+            let (huffman, symbol) = match code {
+                ScanCode::Dc(symbol) => (tables[table].get_huffman(), symbol),
+                ScanCode::Ac(symbol) => (tables[table + 1].get_huffman(), symbol),
+                ScanCode::Bits(bits, length) => {
+                    stream.write_bits(*bits, *length);
+                    continue;
+                },
+            };
+
+            huffman.write_one_code(symbol.symbol as u32, &mut stream)?;
+            stream.write_bits(symbol.amplitude, symbol.amplitude_length);
+        }
+
+        Ok(finish_scan(stream))
+    }
+
+    /// Appends everything before the first scan to bytes - SOI, APP0, the quantization tables,
+    /// DRI and the frame header.
+    fn append_headers(&self, write_bytes: &mut Vec<u8>) {
+        let lines_in_image = self.height;
+        let samples_per_line = self.width;
         // SOI
         write_bytes.append(&mut vec![0xFF, 0xD8]);
-        
+
         // APP0
         write_bytes.append(&mut vec![0xFF, 0xE0]);
         // Length [2] (16)
@@ -59,119 +837,1236 @@ impl EncJpeg {
         // X,Y Thumbnail [1] + [1]
         write_bytes.append(&mut vec![0, 0]);
 
+        // Tables :
+        // Quantization tables (0 - Luma, 1 - Chroma)
+        append_dqt(write_bytes, 0, self.luma_quant_table());
+
+        if !self.grayscale {
+            append_dqt(write_bytes, 1, self.chrom_quant_table());
+        }
+
+        if self.restart_interval > 0 {
+            // DRI
+            write_bytes.append(&mut vec![0xFF, 0xDD]);
+            // Lr (length [2]) (4)
+            write_bytes.append(&mut vec![0, 4]);
+            // Ri (MCUs per restart interval [2])
+            write_bytes.append(&mut self.restart_interval.to_be_bytes().to_vec());
+        }
+
         // Frame :
-        let mut frame_bytes : Vec<u8> = vec![];
+        let frame_bytes = write_bytes;
 
-        // Frame -> Header (SOF0)
-        frame_bytes.append(&mut vec![0xFF, 0xC0]);
-        // Lf (length [2]) (17)
-        frame_bytes.append(&mut vec![0,17]);
+        // Frame -> Header (SOF0 for baseline, SOF2 for progressive)
+        let sof_marker = match self.scan_script {
+            Some(_) => 0xC2,
+            None => 0xC0,
+        };
+        frame_bytes.append(&mut vec![0xFF, sof_marker]);
+        let components = self.get_component_count();
+        // Lf (length [2]) (8 + 3 per component)
+        frame_bytes.append(&mut (8 + 3 * components as u16).to_be_bytes().to_vec());
         // P (sample precision[1])
         frame_bytes.push(8);
         // Y (lines in image [2])
-        frame_bytes.push(lines_in_image);
+        frame_bytes.append(&mut lines_in_image.to_be_bytes().to_vec());
         // X (samples per line [2])
-        frame_bytes.push(samples_per_line);
+        frame_bytes.append(&mut samples_per_line.to_be_bytes().to_vec());
         // Nf (components [1])
-        frame_bytes.push(3);
+        frame_bytes.push(components as u8);
 
         // Frame -> Header (SOF0) -> COMPONENTS :
-        // C0 (Luma)
-        frame_bytes.push(0);
-        // Hi, Vi (4,4)
-        frame_bytes.push(0b01000100);
+        // C1 (Luma)
+        frame_bytes.push(1);
+        // Hi, Vi (the subsampling factors)
+        let (h_factor, v_factor) = self.get_subsampling().get_factors();
+        frame_bytes.push(((h_factor << 4) | v_factor) as u8);
         // Tqi (Luma quantization table - 0)
         frame_bytes.push(0);
-        
-        // C1 (Chroma B)
-        frame_bytes.push(1);
-        // Hi, Vi (2,2)
-        frame_bytes.push(0b00100010);
-        // Tqi (Chroma quantization table - 1)
-        frame_bytes.push(1);
-        
-        // C2 (Chroma R)
-        frame_bytes.push(1);
-        // Hi, Vi (2,2)
-        frame_bytes.push(0b00100010);
-        // Tqi (Chroma quantization table - 1)
-        frame_bytes.push(1);
-        
-        // Scan 1 (only 1 scan in baseline jpeg)
-        let mut scan_bytes : Vec<u8> = vec![];
-        
-        // SOS
-        scan_bytes.append(&mut vec![0xFF, 0xDA]);
-        // Length [2] (14)
-        scan_bytes.append(&mut vec![0, 14]);
-        // Ns [1] (3)
-        scan_bytes.push(3);
-        // Cs1, Td1, Ta1 [2]
-        scan_bytes.push(0);
-        scan_bytes.push(0);
-        // Cs2, Td2, Ta2 [2];
-        scan_bytes.push(1);
-        scan_bytes.push(0b00010001);
-        // Cs3, Td3, Ta3 [2];
-        scan_bytes.push(2);
-        scan_bytes.push(0b00010001);
-        // SS, Se , AH, Al [3]
-        scan_bytes.append(&mut vec![0, 63, 0]);
-
-        // Entropy Coded MCU segment 
-
-        frame_bytes.append(&mut scan_bytes);
-        write_bytes.append(&mut frame_bytes);
 
-        // EOI
-        write_bytes.append(&mut vec![0xFF, 0xD9]);
-        
-        std::fs::write(path, write_bytes);
+        // C2 (Chroma B), C3 (Chroma R)
+        for component in 2..=components as u8 {
+            frame_bytes.push(component);
+            // Hi, Vi (1,1)
+            frame_bytes.push(0b00010001);
+            // Tqi (Chroma quantization table - 1)
+            frame_bytes.push(1);
+        }
     }
-}
 
-impl TryFrom<DecJpeg> for EncJpeg {
-    type Error = &'static str;
+    pub fn to_bytes(&self) -> Result<Vec<u8>, &'static str> {
+        let mut write_bytes : Vec<u8> = vec![];
+        self.append_headers(&mut write_bytes);
+        let components = self.get_component_count();
 
-    fn try_from(decpng: DecJpeg) -> Result<Self, Self::Error> {
-        //subsample
-        let subsampled = decpng.pixels.subsample_ycbcr();
-        let blocks = JpegBlocks::from(subsampled);
-        //DCT
-        //quantize
-        //RLE wiht entropy coding
-        //let entropy = blocks.dct().quantize().to_entropy()
-        //encode(entropy)
-        //Huffman code
-        Err("Not implmented yet!") 
-    }
-}
+        // Frame -> Tables and scans :
+        // Scans are coded before the tables are written so that optimized tables can be built
+        // from their symbols.
+        let mcus = self.mcus(&self.pixels);
+        let mut scan_bytes : Vec<u8> = vec![];
 
-pub struct DecJpeg {
-    pixels : Pixels
-}
+        match self.scan_script.as_ref() {
+            // Scan 1 (only 1 scan in baseline jpeg)
+            None => {
+                let intervals : Vec<_> = mcus
+                    .chunks(self.restart_interval_length(mcus.len()))
+                    .map(|x| self.block_symbols(x, &mut [0; 3]))
+                    .collect();
+                let tables = self.huffman_tables(&Self::baseline_frequencies(&intervals))?;
 
-impl TryFrom<DecPng> for DecJpeg {
-    type Error = &'static str;
+                self.append_dhts(&mut scan_bytes, &tables);
+                append_sos(&mut scan_bytes, &JpegScan::new((0..components).collect(), 0, 63, 0, 0));
 
-    fn try_from(value: DecPng) -> Result<Self, Self::Error> {
-        let pixels = value.get_scanlines().to_ycbcr();
+                // Entropy Coded MCU segment
+                let segments = intervals.iter().map(|x| self.encode_scan(x, &tables)).collect::<Result<Vec<_>, _>>()?;
+                scan_bytes.append(&mut join_restart_intervals(segments));
+            },
+            // Progressive scans. The typical tables are written once, but optimized tables are
+            // built for each scan since DC, AC and refinement scans use very different symbols.
+            Some(scans) => {
+                if !self.optimize_huffman {
+                    self.append_dhts(&mut scan_bytes, &self.huffman_tables(&[[0; 256]; 4])?);
+                }
 
-        Ok(
+                for scan in scans {
+                    let codes = self.progressive_scan_codes(&mcus, scan);
+                    let frequencies = Self::progressive_frequencies(&codes);
+                    let tables = self.huffman_tables(&frequencies)?;
+
+                    if self.optimize_huffman {
+                        // Only the tables this scan uses are written
+                        for (index, table) in tables.iter().enumerate() {
+                            if frequencies[index].iter().any(|&x| x > 0) {
+                                table.append_dht(&mut scan_bytes, dht_class_and_id(index));
+                            }
+                        }
+                    }
+
+                    append_sos(&mut scan_bytes, scan);
+                    let segments = codes.iter().map(|x| self.encode_progressive_scan(x, &tables)).collect::<Result<Vec<_>, _>>()?;
+                    scan_bytes.append(&mut join_restart_intervals(segments));
+                }
+            },
+        };
+
+        write_bytes.append(&mut scan_bytes);
+
+        // EOI
+        write_bytes.append(&mut vec![0xFF, 0xD9]);
+
+        Ok(write_bytes)
+    }
+
+    pub fn write_to_path(&self, path : String) -> Result<(), &'static str> {
+        let write_bytes = self.to_bytes()?;
+
+        match fs::write(path, write_bytes) {
+            Ok(_) => Ok(()),
+            Err(_) => Err("Unable to write file"),
+        }
+    }
+
+    /// Makes an encoder for a width by height image whose pixels are given later, a row at a
+    /// time, to write_rows(). The defaults are the same as TryFrom<DecJpeg>.
+    pub fn for_rows(width: usize, height: usize, grayscale: bool) -> Result<EncJpeg, &'static str> {
+        let height : u16 = match height.try_into() {
+            Ok(0) | Err(_) => return Err("Image height must be between 1 and 65535 to be written as a JPEG!"),
+            Ok(x) => x,
+        };
+
+        let width : u16 = match width.try_into() {
+            Ok(0) | Err(_) => return Err("Image width must be between 1 and 65535 to be written as a JPEG!"),
+            Ok(x) => x,
+        };
+
+        // The base IJG tables are used as they are at quality 50.
+        Ok(
+            EncJpeg {
+                height,
+                width,
+                pixels : Pixels::new(),
+                quality : 50,
+                subsampling : Subsampling::S420,
+                grayscale,
+                scan_script : None,
+                optimize_huffman : false,
+                restart_interval : 0,
+            }
+        )
+    }
+
+    /// Makes an encoder for the image a PngReader decodes, which write_png_to_path() then
+    /// encodes as it is decoded.
+    pub fn for_png_reader(pngreader: &PngReader) -> Result<EncJpeg, &'static str> {
+        let grayscale = png_is_grayscale(pngreader.get_pixel_color_type(), pngreader.get_background());
+
+        EncJpeg::for_rows(pngreader.get_width() as usize, pngreader.get_height() as usize, grayscale)
+    }
+
+    /// Encodes the rows of YCbCr pixels given by rows and writes the JPEG to writer.
+    ///
+    /// Baseline images are encoded one row of MCUs at a time - 8 or 16 rows of pixels - and
+    /// the entropy coded data is written out as each row is finished, so only a row of MCUs is
+    /// ever held. Progressive images and optimized huffman tables need every MCU before the first
+    /// scan can be written, so for those the rows are all collected first.
+    pub fn write_rows<W: Write>(self, rows: impl Iterator<Item = Result<Vec<Pixel>, &'static str>>, writer: W) -> Result<(), &'static str> {
+        let mut writer = writer;
+        let mut rows = rows;
+
+        if self.scan_script.is_some() || self.optimize_huffman {
+            let pixels : Vec<Vec<Pixel>> = rows.collect::<Result<_, _>>()?;
+            let encoder = EncJpeg { pixels: pixels.into(), ..self };
+            return writer.write_all(&encoder.to_bytes()?).map_err(|_| "Unable to write file");
+        }
+
+        let mut write = |bytes: &[u8]| writer.write_all(bytes).map_err(|_| "Unable to write file");
+
+        let components = self.get_component_count();
+        let tables = self.huffman_tables(&[[0; 256]; 4])?;
+        let mut header_bytes = vec![];
+        self.append_headers(&mut header_bytes);
+        self.append_dhts(&mut header_bytes, &tables);
+        append_sos(&mut header_bytes, &JpegScan::new((0..components).collect(), 0, 63, 0, 0));
+        write(&header_bytes)?;
+
+        let (h_factor, v_factor) = self.get_subsampling().get_factors();
+        let (width, height) = (self.width as usize, self.height as usize);
+        let mcu_count = width.div_ceil(8 * h_factor) * height.div_ceil(8 * v_factor);
+        let interval_length = self.restart_interval_length(mcu_count);
+
+        let mut stream = Bits::new(vec![], false, 0);
+        let mut prev_dc = [0i32; 3];
+        let mut mcu_index = 0;
+        let mut mcu_row = Pixels::new();
+
+        for row_num in 0..height {
+            let row = rows.next().ok_or("Image ended before its last row!")??;
+            if row.len() != width {
+                return Err("Image row is a different width than the image!");
+            }
+
+            mcu_row.push(row);
+            if mcu_row.len() < 8 * v_factor && row_num + 1 < height {
+                continue;
+            }
+
+            for mcu in self.mcus(&mcu_row) {
+                // Each restart interval is padded to a byte and followed by an RST marker, and
+                // DC prediction starts over after it.
+                if mcu_index > 0 && mcu_index % interval_length == 0 {
+                    write(&finish_scan(std::mem::replace(&mut stream, Bits::new(vec![], false, 0))))?;
+                    write(&[0xFF, 0xD0 + ((mcu_index / interval_length - 1) % 8) as u8])?;
+                    prev_dc = [0; 3];
+                }
+
+                Self::code_symbols(&self.block_symbols(&[mcu], &mut prev_dc), &tables, &mut stream)?;
+                mcu_index += 1;
+            }
+
+            write(&stuff_bytes(stream.take_written_bytes()))?;
+            mcu_row.clear();
+        }
+
+        write(&finish_scan(stream))?;
+
+        // EOI
+        write(&[0xFF, 0xD9])
+    }
+
+    /// Encodes the image a PngReader decodes as it is decoded, flattening transparent pixels
+    /// onto the reader's background, and writes the JPEG to path.
+    pub fn write_png_to_path(self, pngreader: PngReader<'_>, path: String) -> Result<(), &'static str> {
+        let background = pngreader.get_background();
+        let rows = pngreader.map(|row| row.map(|x| x.iter().map(|pixel| pixel.flatten_alpha(background).to_ycbcr()).collect()));
+
+        let file = fs::File::create(path).map_err(|_| "Unable to write file")?;
+        let mut writer = BufWriter::new(file);
+        self.write_rows(rows, &mut writer)?;
+
+        writer.flush().map_err(|_| "Unable to write file")
+    }
+}
+
+/// Whether a PNG whose pixels are of color_type can be written as a grayscale JPEG. Grayscale
+/// images with alpha stay gray as long as the background they are blended with is gray too.
+fn png_is_grayscale(color_type: ColorType, background: [u8; 3]) -> bool {
+    match color_type {
+        ColorType::GS => true,
+        ColorType::GSA => background[0] == background[1] && background[1] == background[2],
+        _ => false,
+    }
+}
+
+impl TryFrom<DecJpeg> for EncJpeg {
+    type Error = &'static str;
+
+    fn try_from(decjpeg: DecJpeg) -> Result<Self, Self::Error> {
+        let (height, width) = (decjpeg.pixels.len(), decjpeg.pixels.first().map_or(0, |x| x.len()));
+        let encoder = EncJpeg::for_rows(width, height, decjpeg.grayscale)?;
+
+        Ok(EncJpeg { pixels: decjpeg.pixels, ..encoder })
+    }
+}
+
+pub struct DecJpeg {
+    pixels : Pixels,
+
+    /// Whether every pixel is gray, so the chroma can be left out.
+    grayscale : bool,
+}
+
+impl TryFrom<DecPng> for DecJpeg {
+    type Error = &'static str;
+
+    fn try_from(value: DecPng) -> Result<Self, Self::Error> {
+        let scanlines = value.get_scanlines();
+        let background = value.get_background();
+        let first_pixel = scanlines.first().and_then(|x| x.first()).ok_or("Image has no pixels to encode!")?;
+        let grayscale = png_is_grayscale(first_pixel.get_color_type(), background);
+
+        let pixels = scanlines.flatten_alpha(background).to_ycbcr();
+
+        Ok(
             DecJpeg {
-                pixels
+                pixels,
+                grayscale,
             }
         )
     }
-} 
+}
+
+impl TryFrom<Vec<u8>> for DecJpeg {
+    type Error = &'static str;
+
+    /// Decodes a baseline or progressive JPEG file.
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        if !bytes.starts_with(&[0xFF, 0xD8]) {
+            return Err("JPEG is missing its SOI marker!");
+        }
+
+        let mut reader = JpegReader::new();
+        let mut position = 2;
+
+        loop {
+            let marker = read_marker(&bytes, &mut position)?;
+
+            // EOI ends the image, RSTn and TEM have no segment
+            match marker {
+                0xD9 => break,
+                0x01 | 0xD0..=0xD7 => continue,
+                _ => (),
+            };
+
+            let segment = read_segment(&bytes, &mut position)?;
+
+            match marker {
+                0xC0 | 0xC1 => reader.read_sof(segment, false)?,
+                0xC2 => reader.read_sof(segment, true)?,
+                0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => return Err("Only huffman coded sequential JPEGs can be decoded!"),
+                0xC4 => reader.read_dht(segment)?,
+                0xDB => reader.read_dqt(segment)?,
+                0xDD => reader.read_dri(segment)?,
+                0xDA => {
+                    let scan = reader.read_sos(segment)?;
+                    let intervals = read_entropy_coded_data(&bytes, &mut position);
+                    reader.decode_scan(&scan, intervals)?;
+                },
+                // APPn, COM and any other segments don't change how the image is decoded
+                _ => (),
+            };
+        }
+
+        reader.into_dec_jpeg()
+    }
+}
+
+impl DecJpeg {
+    /// Returns whether the JPEG only has a luma component.
+    pub fn is_grayscale(&self) -> bool {
+        self.grayscale
+    }
+}
+
+impl WriteToPPM for DecJpeg {
+    fn get_rgb_pixels(&self) -> Pixels {
+        self.pixels.to_rgb()
+    }
+}
+
+/// Reads the marker at position, skipping any 0xFF fill bytes in front of it, and moves position
+/// past it.
+fn read_marker(bytes: &[u8], position: &mut usize) -> Result<u8, &'static str> {
+    if bytes.get(*position) != Some(&0xFF) {
+        return Err("Expected a JPEG marker!");
+    }
+
+    while bytes.get(*position) == Some(&0xFF) {
+        *position += 1;
+    }
+
+    let marker = *bytes.get(*position).ok_or("JPEG ended before its EOI marker!")?;
+    *position += 1;
+
+    Ok(marker)
+}
+
+/// Reads the length of the segment at position and returns its contents without the length,
+/// moving position past it.
+fn read_segment<'a>(bytes: &'a [u8], position: &mut usize) -> Result<&'a [u8], &'static str> {
+    let length = match bytes.get(*position..*position + 2) {
+        Some(&[high, low]) => u16::from_be_bytes([high, low]) as usize,
+        _ => return Err("JPEG ended in the middle of a segment!"),
+    };
+
+    let segment = bytes.get(*position + 2..*position + length).ok_or("JPEG ended in the middle of a segment!")?;
+    *position += length;
+
+    Ok(segment)
+}
+
+/// Inverse of join_restart_intervals() - reads the entropy coded data at position until the next
+/// marker that isn't an RST marker, and returns each restart interval. Byte stuffing is left for
+/// Bits::new_unstuffed() to skip. Position is left at the marker.
+fn read_entropy_coded_data(bytes: &[u8], position: &mut usize) -> Vec<Vec<u8>> {
+    let mut intervals = vec![vec![]];
+
+    while let Some(&byte) = bytes.get(*position) {
+        if byte != 0xFF {
+            intervals.last_mut().unwrap().push(byte);
+            *position += 1;
+            continue;
+        }
+
+        match bytes.get(*position + 1) {
+            Some(0x00) => {
+                intervals.last_mut().unwrap().extend_from_slice(&[0xFF, 0x00]);
+                *position += 2;
+            },
+            // Fill byte in front of a marker
+            Some(0xFF) => *position += 1,
+            Some(0xD0..=0xD7) => {
+                intervals.push(vec![]);
+                *position += 2;
+            },
+            _ => break,
+        };
+    }
+
+    intervals
+}
+
+/// A component of a frame being decoded, along with its coefficients.
+struct FrameComponent {
+    id : u8,
+    h_factor : usize,
+    v_factor : usize,
+    quant_table : usize,
+
+    /// Huffman tables the current scan uses for this component.
+    dc_table : usize,
+    ac_table : usize,
+
+    /// Size of the component in blocks, padded out to whole MCUs.
+    blocks_per_line : usize,
+    block_lines : usize,
+
+    /// Size of the component in blocks without padding, which is what a scan of only this
+    /// component covers.
+    scan_blocks_per_line : usize,
+    scan_block_lines : usize,
+
+    /// Quantized coefficients of every block in zig-zag order, left to right then top to bottom.
+    coefficients : Vec<Vec<i32>>,
+}
+
+/// The frame header (SOF) of a JPEG being decoded.
+struct JpegFrame {
+    /// Whether the coefficients are spread over several scans (SOF2).
+    progressive : bool,
+    height : usize,
+    width : usize,
+    max_h_factor : usize,
+    max_v_factor : usize,
+    mcus_per_line : usize,
+    mcu_lines : usize,
+    components : Vec<FrameComponent>,
+}
+
+impl JpegFrame {
+    /// Returns the component index and block index of every block in each MCU of a scan of the
+    /// given components, in the order they are coded.
+    fn scan_mcus(&self, components: &[usize]) -> Vec<Vec<(usize, usize)>> {
+        let mut mcus = vec![];
+
+        // A scan of one component isn't interleaved and each of its blocks is an MCU.
+        if let &[index] = components {
+            let component = &self.components[index];
+
+            for block_row in 0..component.scan_block_lines {
+                for block_col in 0..component.scan_blocks_per_line {
+                    mcus.push(vec![(index, block_row * component.blocks_per_line + block_col)]);
+                }
+            }
+
+            return mcus;
+        }
+
+        for mcu_row in 0..self.mcu_lines {
+            for mcu_col in 0..self.mcus_per_line {
+                let mut mcu = vec![];
+
+                for &index in components {
+                    let component = &self.components[index];
+
+                    for v in 0..component.v_factor {
+                        for h in 0..component.h_factor {
+                            let block_row = mcu_row * component.v_factor + v;
+                            let block_col = mcu_col * component.h_factor + h;
+                            mcu.push((index, block_row * component.blocks_per_line + block_col));
+                        }
+                    }
+                }
+
+                mcus.push(mcu);
+            }
+        }
+
+        mcus
+    }
+}
+
+/// Tables and settings read from the segments of a JPEG while it is decoded.
+struct JpegReader {
+    /// Quantization tables in zig-zag order.
+    quant_tables : [Option<Vec<i32>>; 4],
+    dc_tables : [Option<JpegHuffmanTable>; 4],
+    ac_tables : [Option<JpegHuffmanTable>; 4],
+
+    /// Number of MCUs between restart markers, or 0 if there are none.
+    restart_interval : usize,
+    frame : Option<JpegFrame>,
+}
+
+impl JpegReader {
+    fn new() -> JpegReader {
+        JpegReader {
+            quant_tables : Default::default(),
+            dc_tables : Default::default(),
+            ac_tables : Default::default(),
+            restart_interval : 0,
+            frame : None,
+        }
+    }
+
+    /// Reads the quantization tables in a DQT segment.
+    fn read_dqt(&mut self, mut segment: &[u8]) -> Result<(), &'static str> {
+        while let Some(&precision_and_id) = segment.first() {
+            let (precision, id) = ((precision_and_id >> 4) as usize, (precision_and_id & 0x0F) as usize);
+
+            if precision > 1 || id > 3 {
+                return Err("Invalid JPEG quantization table!");
+            }
+
+            // Entries are 8 bits, or 16 bits when the precision is 1
+            let table_length = 64 * (precision + 1);
+            let entries = segment.get(1..1 + table_length).ok_or("JPEG quantization table is too short!")?;
+
+            self.quant_tables[id] = Some(match precision {
+                0 => entries.iter().map(|&x| x as i32).collect(),
+                _ => entries.chunks(2).map(|x| u16::from_be_bytes([x[0], x[1]]) as i32).collect(),
+            });
+
+            segment = &segment[1 + table_length..];
+        }
+
+        Ok(())
+    }
+
+    /// Reads the huffman tables in a DHT segment.
+    fn read_dht(&mut self, mut segment: &[u8]) -> Result<(), &'static str> {
+        while let Some(&class_and_id) = segment.first() {
+            let (class, id) = (class_and_id >> 4, (class_and_id & 0x0F) as usize);
+
+            if class > 1 || id > 3 {
+                return Err("Invalid JPEG huffman table!");
+            }
+
+            let bits : [u8; 16] = segment.get(1..17).ok_or("JPEG huffman table is too short!")?.try_into().unwrap();
+            let value_count = bits.iter().map(|&x| x as usize).sum::<usize>();
+            let values = segment.get(17..17 + value_count).ok_or("JPEG huffman table is too short!")?;
+            let table = JpegHuffmanTable::new(bits, values)?;
+
+            match class {
+                0 => self.dc_tables[id] = Some(table),
+                _ => self.ac_tables[id] = Some(table),
+            };
+
+            segment = &segment[17 + value_count..];
+        }
+
+        Ok(())
+    }
+
+    /// Reads the restart interval in a DRI segment.
+    fn read_dri(&mut self, segment: &[u8]) -> Result<(), &'static str> {
+        match segment {
+            &[high, low] => self.restart_interval = u16::from_be_bytes([high, low]) as usize,
+            _ => return Err("Invalid JPEG DRI segment!"),
+        };
+
+        Ok(())
+    }
+
+    /// Reads the frame header in an SOF segment and makes room for the coefficients of every
+    /// component.
+    fn read_sof(&mut self, segment: &[u8], progressive: bool) -> Result<(), &'static str> {
+        if self.frame.is_some() {
+            return Err("JPEG has more than one frame!");
+        }
+
+        if segment.len() < 6 || segment.len() != 6 + 3 * segment[5] as usize {
+            return Err("Invalid JPEG SOF segment!");
+        }
+
+        if segment[0] != 8 {
+            return Err("Only JPEGs with 8 bit samples can be decoded!");
+        }
+
+        let height = u16::from_be_bytes([segment[1], segment[2]]) as usize;
+        let width = u16::from_be_bytes([segment[3], segment[4]]) as usize;
+
+        if height == 0 || width == 0 {
+            return Err("JPEGs without a height or width in their frame header can't be decoded!");
+        }
+
+        // Grayscale images have only luma, color images are YCbCr
+        if segment[5] != 1 && segment[5] != 3 {
+            return Err("Only JPEGs with 1 or 3 components can be decoded!");
+        }
+
+        let factors : Vec<(usize, usize)> = segment[6..]
+            .chunks(3)
+            .map(|x| ((x[1] >> 4) as usize, (x[1] & 0x0F) as usize))
+            .collect();
+
+        if factors.iter().any(|&(h, v)| !(1..=4).contains(&h) || !(1..=4).contains(&v)) {
+            return Err("JPEG sampling factors must be between 1 and 4!");
+        }
+
+        let max_h_factor = factors.iter().map(|x| x.0).max().unwrap();
+        let max_v_factor = factors.iter().map(|x| x.1).max().unwrap();
+        let mcus_per_line = width.div_ceil(8 * max_h_factor);
+        let mcu_lines = height.div_ceil(8 * max_v_factor);
+        let mut components = vec![];
+
+        for (component, (h_factor, v_factor)) in segment[6..].chunks(3).zip(factors) {
+            if component[2] > 3 {
+                return Err("Invalid JPEG quantization table destination!");
+            }
+
+            let blocks_per_line = mcus_per_line * h_factor;
+            let block_lines = mcu_lines * v_factor;
+
+            components.push(FrameComponent {
+                id : component[0],
+                h_factor,
+                v_factor,
+                quant_table : component[2] as usize,
+                dc_table : 0,
+                ac_table : 0,
+                blocks_per_line,
+                block_lines,
+                scan_blocks_per_line : (width * h_factor).div_ceil(max_h_factor).div_ceil(8),
+                scan_block_lines : (height * v_factor).div_ceil(max_v_factor).div_ceil(8),
+                coefficients : vec![vec![0; 64]; blocks_per_line * block_lines],
+            });
+        }
+
+        self.frame = Some(JpegFrame { progressive, height, width, max_h_factor, max_v_factor, mcus_per_line, mcu_lines, components });
+        Ok(())
+    }
+
+    /// Reads a scan header in an SOS segment, choosing the huffman tables of its components.
+    fn read_sos(&mut self, segment: &[u8]) -> Result<JpegScan, &'static str> {
+        let frame = self.frame.as_mut().ok_or("JPEG scan comes before its frame header!")?;
+
+        if segment.is_empty() || segment.len() != 4 + 2 * segment[0] as usize {
+            return Err("Invalid JPEG SOS segment!");
+        }
+
+        if !(1..=4).contains(&segment[0]) {
+            return Err("JPEG scan must have between 1 and 4 components!");
+        }
+
+        let mut components = vec![];
+
+        for selector in segment[1..segment.len() - 3].chunks(2) {
+            let index = frame.components
+                .iter()
+                .position(|x| x.id == selector[0])
+                .ok_or("JPEG scan uses a component that isn't in the frame!")?;
+
+            let component = &mut frame.components[index];
+            component.dc_table = (selector[1] >> 4) as usize;
+            component.ac_table = (selector[1] & 0x0F) as usize;
+
+            if component.dc_table > 3 || component.ac_table > 3 {
+                return Err("Invalid JPEG huffman table destination!");
+            }
+
+            components.push(index);
+        }
+
+        let spectral = &segment[segment.len() - 3..];
+        let scan = JpegScan::new(components, spectral[0] as usize, spectral[1] as usize, (spectral[2] >> 4) as u32, (spectral[2] & 0x0F) as u32);
+
+        // Baseline scans always cover every coefficient. Progressive scans either cover the DC
+        // coefficients or a band of AC coefficients of one component.
+        let valid = match (frame.progressive, scan.spectral_start) {
+            (false, _) => scan.spectral_start == 0 && scan.spectral_end == 63 && scan.approx_high == 0 && scan.approx_low == 0,
+            (true, 0) => scan.spectral_end == 0 && scan.approx_low < 14,
+            (true, _) => scan.spectral_start <= scan.spectral_end && scan.spectral_end < 64 && scan.components.len() == 1 && scan.approx_low < 14,
+        };
+
+        match (valid, frame.progressive) {
+            (false, false) => return Err("Invalid JPEG baseline scan!"),
+            (false, true) => return Err("Invalid JPEG progressive scan!"),
+            _ => (),
+        };
+
+        Ok(scan)
+    }
+
+    /// Decodes the coefficients of every block in a scan. DC prediction and end of band runs
+    /// start again in each restart interval.
+    fn decode_scan(&mut self, scan: &JpegScan, intervals: Vec<Vec<u8>>) -> Result<(), &'static str> {
+        let frame = self.frame.as_mut().ok_or("JPEG scan comes before its frame header!")?;
+        let mcus = frame.scan_mcus(&scan.components);
+        let missing_table = "JPEG scan uses a huffman table that isn't defined!";
+
+        let interval_length = match self.restart_interval {
+            0 => mcus.len(),
+            x => x,
+        };
+
+        if intervals.len() < mcus.len().div_ceil(interval_length) {
+            return Err("JPEG scan has fewer restart intervals than it should!");
+        }
+
+        for (interval, bytes) in mcus.chunks(interval_length).zip(intervals) {
+            let mut stream = Bits::new_unstuffed(bytes);
+            let mut prev_dc = vec![0i32; frame.components.len()];
+            let mut ac_decoder = ProgressiveAcDecoder::new(scan.spectral_start, scan.spectral_end, scan.approx_high, scan.approx_low);
+
+            for &(index, block) in interval.iter().flatten() {
+                let component = &mut frame.components[index];
+
+                if frame.progressive {
+                    let coefficients = &mut component.coefficients[block];
+
+                    match (scan.spectral_start, scan.approx_high) {
+                        // DC coefficients are multiplied back with a shift
+                        (0, 0) => {
+                            let dc_table = self.dc_tables[component.dc_table].as_ref().ok_or(missing_table)?;
+                            let category = dc_table.get_huffman().read_one_code(&mut stream)?;
+                            if category > 11 {
+                                return Err("JPEG DC difference category is too large!");
+                            }
+
+                            let amplitude = stream.read_bits(category).ok_or("JPEG scan ended in the middle of a block!")?;
+
+                            prev_dc[index] += utils::jpeg_value(amplitude, category);
+                            coefficients[0] = prev_dc[index] << scan.approx_low;
+                        },
+                        (0, _) => {
+                            let bit = stream.read_bits(1).ok_or("JPEG scan ended in the middle of a block!")?;
+                            coefficients[0] |= (bit << scan.approx_low) as i32;
+                        },
+                        _ => {
+                            let ac_table = self.ac_tables[component.ac_table].as_ref().ok_or(missing_table)?;
+                            ac_decoder.read_block(&mut stream, ac_table.get_huffman(), coefficients)?;
+                        },
+                    };
+
+                    continue;
+                }
+
+                let dc_table = self.dc_tables[component.dc_table].as_ref().ok_or(missing_table)?;
+                let ac_table = self.ac_tables[component.ac_table].as_ref().ok_or(missing_table)?;
+
+                let coefficients = utils::read_jpeg_block(&mut stream, dc_table.get_huffman(), ac_table.get_huffman(), prev_dc[index])?;
+                prev_dc[index] = coefficients[0];
+                component.coefficients[block] = coefficients;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Dequantizes and inverse transforms the coefficients of every component, then upsamples
+    /// the chroma to make YCbCr pixels.
+    fn into_dec_jpeg(self) -> Result<DecJpeg, &'static str> {
+        let frame = self.frame.ok_or("JPEG has no frame header!")?;
+        let mut planes : Vec<Vec<Vec<u8>>> = vec![];
+
+        for component in frame.components.iter() {
+            let table = self.quant_tables[component.quant_table].as_ref().ok_or("JPEG uses a quantization table that isn't defined!")?;
+            let mut plane = vec![vec![0u8; component.blocks_per_line * 8]; component.block_lines * 8];
+
+            for (index, coefficients) in component.coefficients.iter().enumerate() {
+                let dequantized : Vec<i32> = coefficients.iter().zip(table).map(|(x, y)| x * y).collect();
+                let samples = utils::idct(utils::un_zig_zag(&dequantized));
+                let (block_row, block_col) = (index / component.blocks_per_line, index % component.blocks_per_line);
+
+                for (y, row) in samples.into_iter().enumerate() {
+                    plane[block_row * 8 + y][block_col * 8..block_col * 8 + 8].copy_from_slice(&row);
+                }
+            }
+
+            planes.push(plane);
+        }
+
+        // Each chroma sample is repeated over every pixel it covers.
+        let sample = |index: usize, x: usize, y: usize| {
+            let component = &frame.components[index];
+            planes[index][y * component.v_factor / frame.max_v_factor][x * component.h_factor / frame.max_h_factor]
+        };
+
+        let grayscale = frame.components.len() == 1;
+        let mut pixels = Pixels::new();
+
+        for y in 0..frame.height {
+            pixels.push(vec![]);
+
+            for x in 0..frame.width {
+                let ycbcr = match grayscale {
+                    true => vec![sample(0, x, y), 128, 128],
+                    false => vec![sample(0, x, y), sample(1, x, y), sample(2, x, y)],
+                };
+
+                pixels[y].push(Pixel::new(ColorType::YCbCr, ycbcr));
+            }
+        }
+
+        Ok(DecJpeg { pixels, grayscale })
+    }
+}
 
 #[cfg(test)]
 mod test {
-    use super::EncJpeg;
+    use super::*;
+    use crate::png::EncPng;
 
-    //#[test]
+    #[test]
     fn write_jpeg_test() {
-        let enc = EncJpeg {};
-        enc.write_to_path("test.jpeg".to_string());
+        let png_bytes = fs::read("src/testimg/comp1.png").expect("Couldn't read test image");
+        let png : EncPng = png_bytes.try_into().unwrap();
+        let jpeg : DecJpeg = png.decompress().unwrap().try_into().unwrap();
+        let enc : EncJpeg = jpeg.try_into().unwrap();
+        let bytes = enc.to_bytes().unwrap();
+
+        assert_eq!(bytes[0..2], [0xFF, 0xD8]);
+        assert_eq!(bytes[bytes.len() - 2..], [0xFF, 0xD9]);
+        // 32x32 at 4:2:0 is 4 MCUs
+        assert_eq!(enc.mcus(&enc.pixels).len(), 4);
+    }
+
+    #[test]
+    fn dht_segment_test() {
+        let mut bytes = vec![];
+        JpegHuffmanTable::luma_dc().append_dht(&mut bytes, 0x00);
+
+        assert_eq!(bytes[0..5], [0xFF, 0xC4, 0, 31, 0x00]);
+        assert_eq!(bytes[5..21], LUMA_DC_BITS);
+        assert_eq!(bytes[21..], LUMA_DC_VALUES);
+        assert!(JpegHuffmanTable::new(LUMA_DC_BITS, &LUMA_DC_VALUES[1..]).is_err());
+    }
+
+    #[test]
+    fn annex_k_codes_test() {
+        // Code lengths and first codes from tables K.3 and K.5
+        let mut stream = Bits::new(vec![], false, 0);
+        JpegHuffmanTable::luma_dc().get_huffman().write_one_code(0, &mut stream).unwrap();
+        JpegHuffmanTable::luma_dc().get_huffman().write_one_code(11, &mut stream).unwrap();
+        JpegHuffmanTable::luma_ac().get_huffman().write_one_code(0x00, &mut stream).unwrap();
+        JpegHuffmanTable::luma_ac().get_huffman().write_one_code(0xF0, &mut stream).unwrap();
+
+        assert_eq!(stream.read_bits(2), Some(0b00));
+        assert_eq!(stream.read_bits(9), Some(0b111111110));
+        assert_eq!(stream.read_bits(4), Some(0b1010));
+        assert_eq!(stream.read_bits(11), Some(0b11111111001));
+    }
+
+    #[test]
+    fn optimized_huffman_test() {
+        let png_bytes = fs::read("src/testimg/comp0.png").expect("Couldn't read test image");
+        let png : EncPng = png_bytes.try_into().unwrap();
+        let jpeg : DecJpeg = png.decompress().unwrap().try_into().unwrap();
+        let mut enc : EncJpeg = jpeg.try_into().unwrap();
+
+        let typical_length = enc.to_bytes().unwrap().len();
+        enc.set_optimize_huffman(true);
+        let optimized_length = enc.to_bytes().unwrap().len();
+
+        assert!(optimized_length < typical_length);
+
+        // Every symbol that was counted has a code, and none of them is all 1 bits.
+        let table = JpegHuffmanTable::from_frequencies(&[0, 5, 5]).unwrap();
+        assert_eq!(table.bits[..2], [1, 1]);
+        assert_eq!(table.values, vec![1, 2]);
+    }
+
+    #[test]
+    fn quality_test() {
+        let png_bytes = fs::read("src/testimg/comp0.png").expect("Couldn't read test image");
+        let png : EncPng = png_bytes.try_into().unwrap();
+        let jpeg : DecJpeg = png.decompress().unwrap().try_into().unwrap();
+        let mut enc : EncJpeg = jpeg.try_into().unwrap();
+
+        let mut lengths = vec![];
+        for quality in [10, 50, 90] {
+            enc.set_quality(quality).unwrap();
+            lengths.push(enc.to_bytes().unwrap().len());
+        }
+
+        assert!(lengths[0] < lengths[1] && lengths[1] < lengths[2]);
+        assert!(enc.set_quality(0).is_err());
+        assert!(enc.set_quality(101).is_err());
+
+        // The first DQT entry is the scaled DC quantizer - 16 at quality 50, 8 at quality 75
+        enc.set_quality(75).unwrap();
+        let bytes = enc.to_bytes().unwrap();
+        let dqt = bytes.windows(2).position(|x| x == [0xFF, 0xDB]).unwrap();
+        assert_eq!(bytes[dqt + 5], 8);
+    }
+
+    #[test]
+    fn subsampling_test() {
+        let png_bytes = fs::read("src/testimg/comp1.png").expect("Couldn't read test image");
+        let png : EncPng = png_bytes.try_into().unwrap();
+        let jpeg : DecJpeg = png.decompress().unwrap().try_into().unwrap();
+        let mut enc : EncJpeg = jpeg.try_into().unwrap();
+
+        // The 32x32 image is split into MCUs of 8x8, 16x8 and 32x8 pixels
+        for (subsampling, mcu_count, mcu_length, factors) in [
+            (Subsampling::S444, 16, 3, 0x11),
+            (Subsampling::S422, 8, 4, 0x21),
+            (Subsampling::S411, 4, 6, 0x41),
+        ] {
+            enc.set_subsampling(subsampling);
+            let mcus = enc.mcus(&enc.pixels);
+
+            assert_eq!(mcus.len(), mcu_count);
+            assert_eq!(mcus[0].len(), mcu_length);
+
+            let bytes = enc.to_bytes().unwrap();
+            let sof = bytes.windows(2).position(|x| x == [0xFF, 0xC0]).unwrap();
+            assert_eq!(bytes[sof + 11], factors);
+        }
+    }
+
+    #[test]
+    fn grayscale_test() {
+        let png_bytes = fs::read("src/testimg/test_2.png").expect("Couldn't read test image");
+        let png : EncPng = png_bytes.try_into().unwrap();
+        let jpeg : DecJpeg = png.decompress().unwrap().try_into().unwrap();
+        let mut enc : EncJpeg = jpeg.try_into().unwrap();
+        enc.set_subsampling(Subsampling::S420);
+
+        let bytes = enc.to_bytes().unwrap();
+        let count_markers = |marker : u8| bytes.windows(2).filter(|x| *x == [0xFF, marker]).count();
+
+        // One component with a single 8x8 block per MCU and one set of tables
+        let sof = bytes.windows(2).position(|x| x == [0xFF, 0xC0]).unwrap();
+        assert_eq!(bytes[sof + 2..sof + 13], [0, 11, 8, 0, 2, 0, 2, 1, 1, 0x11, 0]);
+        assert_eq!(count_markers(0xDB), 1);
+        assert_eq!(count_markers(0xC4), 2);
+        assert_eq!(enc.mcus(&enc.pixels).len(), 1);
+        assert_eq!(enc.mcus(&enc.pixels)[0].len(), 1);
+    }
+
+    #[test]
+    fn empty_png_test() {
+        assert_eq!(DecJpeg::try_from(DecPng::new()).err(), Some("Image has no pixels to encode!"));
+    }
+
+    #[test]
+    fn scan_script_test() {
+        let script = "# DC first\n0,1,2: 0-0, 0, 0;\n0: 1-63, 0, 1;\n1: 1-63, 0, 0; 2: 1-63, 0, 0;\n0: 1-63, 1, 0;";
+        let scans = JpegScan::parse_script(script).unwrap();
+
+        assert_eq!(scans.len(), 5);
+        assert_eq!(scans[0], JpegScan::new(vec![0, 1, 2], 0, 0, 0, 0));
+        assert_eq!(scans[4], JpegScan::new(vec![0], 1, 63, 1, 0));
+        assert!(JpegScan::validate_script(&scans, 3).is_ok());
+
+        // Missing the last refinement, AC before DC, AC with several components and skipping a bit
+        assert!(JpegScan::validate_script(&scans[..4], 3).is_err());
+        assert!(JpegScan::validate_script(&[JpegScan::new(vec![0], 1, 63, 0, 0), JpegScan::new(vec![0], 0, 0, 0, 0)], 1).is_err());
+        assert!(JpegScan::validate_script(&[JpegScan::new(vec![0], 0, 0, 0, 0), JpegScan::new(vec![0, 1], 1, 63, 0, 0)], 2).is_err());
+        assert!(JpegScan::validate_script(&[JpegScan::new(vec![0], 0, 0, 0, 2), JpegScan::new(vec![0], 0, 0, 1, 0)], 1).is_err());
+        assert!(JpegScan::parse_script("0: 1-63, 0;").is_err());
+
+        assert!(JpegScan::validate_script(&JpegScan::default_script(1), 1).is_ok());
+        assert!(JpegScan::validate_script(&JpegScan::default_script(3), 3).is_ok());
+    }
+
+    #[test]
+    fn progressive_test() {
+        let png_bytes = fs::read("src/testimg/comp1.png").expect("Couldn't read test image");
+        let png : EncPng = png_bytes.try_into().unwrap();
+        let jpeg : DecJpeg = png.decompress().unwrap().try_into().unwrap();
+        let mut enc : EncJpeg = jpeg.try_into().unwrap();
+        enc.set_progressive();
+
+        let bytes = enc.to_bytes().unwrap();
+        let count_markers = |marker : u8| bytes.windows(2).filter(|x| *x == [0xFF, marker]).count();
+
+        assert_eq!(count_markers(0xC2), 1);
+        assert_eq!(count_markers(0xC0), 0);
+        assert_eq!(count_markers(0xDA), 10);
+
+        // Every scan of a progressive image with optimized tables gets its own tables
+        enc.set_optimize_huffman(true);
+        let bytes = enc.to_bytes().unwrap();
+        assert!(bytes.windows(2).filter(|x| *x == [0xFF, 0xC4]).count() > 4);
+    }
+
+    #[test]
+    fn restart_interval_test() {
+        let png_bytes = fs::read("src/testimg/comp1.png").expect("Couldn't read test image");
+        let png : EncPng = png_bytes.try_into().unwrap();
+        let jpeg : DecJpeg = png.decompress().unwrap().try_into().unwrap();
+        let mut enc : EncJpeg = jpeg.try_into().unwrap();
+        let count_rst = |bytes : &Vec<u8>| bytes.windows(2).filter(|x| x[0] == 0xFF && (0xD0..=0xD7).contains(&x[1])).count();
+
+        let bytes = enc.to_bytes().unwrap();
+        assert!(!bytes.windows(2).any(|x| x == [0xFF, 0xDD]));
+        assert_eq!(count_rst(&bytes), 0);
+
+        // 32x32 at 4:2:0 is 4 MCUs, so there is a marker between each pair
+        enc.set_restart_interval(1);
+        let bytes = enc.to_bytes().unwrap();
+        assert!(bytes.windows(6).any(|x| x == [0xFF, 0xDD, 0, 4, 0, 1]));
+        assert_eq!(count_rst(&bytes), 3);
+
+        // Scans of one component count each 8x8 block as an MCU. The 2 interleaved DC scans have
+        // 4 MCUs, the 4 luma AC scans have 16 and the 4 chroma AC scans have 4.
+        enc.set_restart_interval(2);
+        enc.set_progressive();
+        let bytes = enc.to_bytes().unwrap();
+        assert_eq!(count_rst(&bytes), 2 + 4 * 7 + 4);
+    }
+
+    #[test]
+    fn write_rows_test() {
+        let settings : [fn(&mut EncJpeg); 5] = [
+            |_| (),
+            |enc| enc.set_subsampling(Subsampling::S444),
+            |enc| enc.set_restart_interval(3),
+            |enc| { enc.set_subsampling(Subsampling::S411); enc.set_restart_interval(5); },
+            |enc| enc.set_optimize_huffman(true),
+        ];
+
+        // Restart intervals of 3 MCUs cross rows of MCUs in comp1.png, adam7.png is interlaced and
+        // ends with part of a row of MCUs, and gs4_trns.png is grayscale with a color key
+        for path in ["src/testimg/comp1.png", "src/testimg/adam7.png", "src/testimg/gs4_trns.png", "src/testimg/rgba_bkgd.png"] {
+            for setup in settings {
+                let png : EncPng = fs::read(path).unwrap().try_into().unwrap();
+                let mut whole : EncJpeg = DecJpeg::try_from(png.decompress().unwrap()).unwrap().try_into().unwrap();
+                setup(&mut whole);
+
+                let reader = PngReader::new(fs::File::open(path).unwrap()).unwrap();
+                let background = reader.get_background();
+                let mut streamed = EncJpeg::for_png_reader(&reader).unwrap();
+                setup(&mut streamed);
+
+                let rows = reader.map(|row| row.map(|x| x.iter().map(|pixel| pixel.flatten_alpha(background).to_ycbcr()).collect()));
+                let mut bytes = vec![];
+                streamed.write_rows(rows, &mut bytes).unwrap();
+
+                assert!(bytes == whole.to_bytes().unwrap(), "{path}");
+            }
+        }
+
+        // Rows that run out early, or are the wrong width
+        let enc = EncJpeg::for_rows(2, 2, false).unwrap();
+        let row = || Ok(vec![Pixel::new(ColorType::YCbCr, vec![0, 128, 128]); 2]);
+        assert_eq!(enc.write_rows([row()].into_iter(), vec![]), Err("Image ended before its last row!"));
+
+        let enc = EncJpeg::for_rows(3, 2, false).unwrap();
+        assert_eq!(enc.write_rows([row(), row()].into_iter(), vec![]), Err("Image row is a different width than the image!"));
+    }
+
+    /// Encodes comp1.png with the given settings and decodes it again, returning the pixels of the
+    /// PNG and the decoded JPEG as RGB values.
+    fn round_trip(setup: impl Fn(&mut EncJpeg)) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let png_bytes = fs::read("src/testimg/comp1.png").expect("Couldn't read test image");
+        let png : EncPng = png_bytes.try_into().unwrap();
+        let dec_png = png.decompress().unwrap();
+        let original = dec_png.get_rgb_pixels().iter().flatten().map(|x| x.get_color_values()).collect();
+
+        let jpeg : DecJpeg = dec_png.try_into().unwrap();
+        let mut enc : EncJpeg = jpeg.try_into().unwrap();
+        setup(&mut enc);
+
+        let decoded = DecJpeg::try_from(enc.to_bytes().unwrap()).unwrap();
+        let decoded = decoded.get_rgb_pixels().iter().flatten().map(|x| x.get_color_values()).collect();
+
+        (original, decoded)
+    }
+
+    #[test]
+    fn decode_baseline_test() {
+        let (original, decoded) = round_trip(|enc| {
+            enc.set_quality(100).unwrap();
+            enc.set_subsampling(Subsampling::S444);
+        });
+
+        assert_eq!(decoded.len(), 32 * 32);
+        for (x, y) in original.iter().zip(decoded.iter()) {
+            assert!(x.iter().zip(y).all(|(a, b)| a.abs_diff(*b) <= 8), "{x:?} {y:?}");
+        }
+
+        // Restart markers and optimized tables only change how the coefficients are stored
+        let (_, plain) = round_trip(|enc| enc.set_subsampling(Subsampling::S422));
+        let (_, restarted) = round_trip(|enc| {
+            enc.set_subsampling(Subsampling::S422);
+            enc.set_restart_interval(3);
+            enc.set_optimize_huffman(true);
+        });
+
+        assert_eq!(plain, restarted);
+    }
+
+    #[test]
+    fn decode_progressive_test() {
+        let (_, baseline) = round_trip(|enc| enc.set_subsampling(Subsampling::S444));
+        let (_, progressive) = round_trip(|enc| {
+            enc.set_subsampling(Subsampling::S444);
+            enc.set_progressive();
+        });
+
+        assert_eq!(baseline, progressive);
+
+        // Successive approximation with end of band runs across restart intervals
+        let (_, baseline) = round_trip(|enc| enc.set_quality(90).unwrap());
+        let (_, progressive) = round_trip(|enc| {
+            let script = "0,1,2: 0-0, 0, 1; 0: 1-5, 0, 2; 0: 6-63, 0, 2; 1: 1-63, 0, 1; 2: 1-63, 0, 0;
+                0: 1-63, 2, 1; 0,1,2: 0-0, 1, 0; 1: 1-63, 1, 0; 0: 1-63, 1, 0;";
+            enc.set_quality(90).unwrap();
+            enc.set_scan_script(Some(JpegScan::parse_script(script).unwrap())).unwrap();
+            enc.set_optimize_huffman(true);
+            enc.set_restart_interval(3);
+        });
+
+        assert_eq!(baseline, progressive);
+
+        // 2x2 progressive image with a different color in each pixel
+        let jpeg = DecJpeg::try_from(fs::read("src/testimg/4pixel.jpg").unwrap()).unwrap();
+        let pixels : Vec<Vec<u8>> = jpeg.get_rgb_pixels().iter().flatten().map(|x| x.get_color_values()).collect();
+        assert_eq!(pixels, vec![vec![84, 0, 103], vec![180, 27, 0], vec![0, 183, 198], vec![9, 255, 0]]);
+    }
+
+    #[test]
+    fn decode_bad_dc_category_test() {
+        let png : EncPng = fs::read("src/testimg/comp1.png").unwrap().try_into().unwrap();
+        let jpeg : DecJpeg = png.decompress().unwrap().try_into().unwrap();
+        let mut enc : EncJpeg = jpeg.try_into().unwrap();
+
+        // Replaces the symbols of the DC tables with categories no DC difference can have
+        let corrupt = |mut bytes: Vec<u8>| {
+            let mut position = 0;
+            while let Some(offset) = bytes[position..].windows(2).position(|x| x == [0xFF, 0xC4]) {
+                let start = position + offset + 4;
+                let end = start + u16::from_be_bytes([bytes[start - 2], bytes[start - 1]]) as usize - 2;
+                let mut table = start;
+
+                while table < end {
+                    let value_count = bytes[table + 1..table + 17].iter().map(|&x| x as usize).sum::<usize>();
+                    if bytes[table] >> 4 == 0 {
+                        for (value, category) in bytes[table + 17..table + 17 + value_count].iter_mut().zip(20..) {
+                            *value = category;
+                        }
+                    }
+                    table += 17 + value_count;
+                }
+
+                position = end;
+            }
+
+            bytes
+        };
+
+        let baseline = corrupt(enc.to_bytes().unwrap());
+        assert_eq!(DecJpeg::try_from(baseline).err(), Some("JPEG DC difference category is too large!"));
+
+        enc.set_progressive();
+        let progressive = corrupt(enc.to_bytes().unwrap());
+        assert_eq!(DecJpeg::try_from(progressive).err(), Some("JPEG DC difference category is too large!"));
+    }
+
+    #[test]
+    fn decode_bad_scan_header_test() {
+        let png : EncPng = fs::read("src/testimg/comp1.png").unwrap().try_into().unwrap();
+        let jpeg : DecJpeg = png.decompress().unwrap().try_into().unwrap();
+        let enc : EncJpeg = jpeg.try_into().unwrap();
+        let bytes = enc.to_bytes().unwrap();
+
+        // Swaps the contents of the first SOS segment for segment
+        let sos = bytes.windows(2).position(|x| x == [0xFF, 0xDA]).unwrap() + 2;
+        let sos_end = sos + u16::from_be_bytes([bytes[sos], bytes[sos + 1]]) as usize;
+        let with_sos = |segment: &[u8]| {
+            let mut changed = bytes[..sos].to_vec();
+            changed.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+            changed.extend_from_slice(segment);
+            changed.extend_from_slice(&bytes[sos_end..]);
+            DecJpeg::try_from(changed).err()
+        };
+
+        let original = bytes[sos + 2..sos_end].to_vec();
+        assert_eq!(with_sos(&original), None);
+
+        // Baseline scans have to cover every coefficient at full precision
+        let spectral = original.len() - 3;
+        for (index, value) in [(0, 1), (1, 62), (2, 0x10), (2, 0x01)] {
+            let mut segment = original.clone();
+            segment[spectral + index] = value;
+            assert_eq!(with_sos(&segment), Some("Invalid JPEG baseline scan!"));
+        }
+
+        assert_eq!(with_sos(&[0, 0, 63, 0]), Some("JPEG scan must have between 1 and 4 components!"));
+        assert_eq!(with_sos(&[5, 1, 0, 2, 0x11, 3, 0x11, 1, 0, 2, 0x11, 0, 63, 0]), Some("JPEG scan must have between 1 and 4 components!"));
+    }
+
+    #[test]
+    fn decode_jpeg_file_test() {
+        // 256x256 baseline image whose tables come after its frame header
+        let jpeg = DecJpeg::try_from(fs::read("src/testimg/test.jpg").unwrap()).unwrap();
+        assert_eq!(jpeg.pixels.len(), 256);
+        assert_eq!(jpeg.pixels[0].len(), 256);
+        assert!(!jpeg.grayscale);
+
+        assert!(DecJpeg::try_from(vec![0xFF, 0xD8, 0xFF, 0xD9]).is_err());
+        assert!(DecJpeg::try_from(fs::read("src/testimg/comp1.png").unwrap()).is_err());
+    }
+
+    #[test]
+    fn stuff_bytes_test() {
+        assert_eq!(stuff_bytes(vec![0x12, 0xFF, 0xFF, 0x00]), vec![0x12, 0xFF, 0x00, 0xFF, 0x00, 0x00]);
+    }
+
+    // Timing rather than correctness - run with "cargo test --release -- --ignored --nocapture".
+    #[test]
+    #[ignore]
+    fn bench_decode_horse() {
+        let bytes = fs::read("src/testimg/horse.jpg").unwrap();
+        let start = std::time::Instant::now();
+
+        for _ in 0..10 {
+            DecJpeg::try_from(bytes.clone()).unwrap();
+        }
+
+        println!("Decoding horse.jpg took {:?}", start.elapsed() / 10);
     }
 }
@@ -1,38 +1,211 @@
+
 use std::fs::File;
 use std::io;
-use std::io::{BufReader, Read};
+use std::io::{BufRead, BufReader, Read};
 
 mod png;
-use png::{EncPng, DecPng, WriteToPPM};
+use png::{EncPng, DecPng, PngReader, WriteToPPM};
 mod jpeg;
-use jpeg::{EncJpeg, DecJpeg};
+use jpeg::{EncJpeg, DecJpeg, JpegScan};
 mod utils;
+use utils::{CompressionLevel, FilterStrategy};
 mod pixel;
+use pixel::Subsampling;
 
 struct Cli {
     input_path: String,
     output_path : String,
+    background : Option<[u8; 3]>,
+    optimize_huffman : bool,
+    quality : Option<u32>,
+    subsampling : Option<Subsampling>,
+    progressive : bool,
+
+    /// Path of a file holding a scan script for progressive images.
+    scans_path : Option<String>,
+
+    /// Number of MCUs between restart markers.
+    restart_interval : Option<u16>,
+
+    /// How hard to compress PNG output.
+    png_compression : CompressionLevel,
+
+    /// How each scanline of PNG output is filtered.
+    png_filter : FilterStrategy,
+}
+
+impl Cli {
+    /// Reads the input and output paths followed by any options, such as
+    /// "--background RRGGBB", "--quality N", "--subsampling 444", "--optimize-huffman",
+    /// "--progressive", "--scans FILE", "--restart N", "--png-compression LEVEL" or "--png-filter FILTER".
+    fn from_args() -> Result<Cli, &'static str> {
+        let mut args = std::env::args().skip(1);
+        let input_path = args.next().ok_or("No input filename specified!")?;
+        let output_path = args.next().ok_or("No output jpeg filename specified!")?;
+        let mut background = None;
+        let mut optimize_huffman = false;
+        let mut quality = None;
+        let mut subsampling = None;
+        let mut progressive = false;
+        let mut scans_path = None;
+        let mut restart_interval = None;
+        let mut png_compression = CompressionLevel::default();
+        let mut png_filter = FilterStrategy::default();
+
+        while let Some(option) = args.next() {
+            match option.as_str() {
+                "--background" => {
+                    let color = args.next().ok_or("No color given for --background!")?;
+                    background = Some(parse_hex_color(&color)?);
+                },
+                "--quality" => {
+                    let value = args.next().ok_or("No value given for --quality!")?;
+                    quality = Some(value.parse().map_err(|_| "Quality must be a number from 1 to 100!")?);
+                },
+                "--subsampling" => {
+                    let value = args.next().ok_or("No value given for --subsampling!")?;
+                    subsampling = Some(Subsampling::try_from(value.as_str())?);
+                },
+                "--optimize-huffman" => optimize_huffman = true,
+                "--progressive" => progressive = true,
+                "--scans" => scans_path = Some(args.next().ok_or("No file given for --scans!")?),
+                "--restart" => {
+                    let value = args.next().ok_or("No value given for --restart!")?;
+                    restart_interval = Some(value.parse().map_err(|_| "Restart interval must be a number of MCUs from 0 to 65535!")?);
+                },
+                "--png-compression" => {
+                    let value = args.next().ok_or("No value given for --png-compression!")?;
+                    png_compression = CompressionLevel::try_from(value.as_str())?;
+                },
+                "--png-filter" => {
+                    let value = args.next().ok_or("No value given for --png-filter!")?;
+                    png_filter = FilterStrategy::try_from(value.as_str())?;
+                },
+                _ => return Err("Unknown option!"),
+            }
+        }
+
+        Ok(Cli { input_path, output_path, background, optimize_huffman, quality, subsampling, progressive, scans_path, restart_interval, png_compression, png_filter })
+    }
+}
+
+/// Parses a color written as six hex digits, optionally starting with a '#'.
+fn parse_hex_color(color: &str) -> Result<[u8; 3], &'static str> {
+    let digits = color.strip_prefix('#').unwrap_or(color);
+
+    if digits.len() != 6 || !digits.is_ascii() {
+        return Err("Colors must be given as RRGGBB!");
+    }
+
+    let mut rgb = [0u8; 3];
+    for (i, value) in rgb.iter_mut().enumerate() {
+        *value = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).map_err(|_| "Colors must be given as RRGGBB!")?;
+    }
+
+    Ok(rgb)
+}
+
+/// Applies the JPEG options from the command line to an encoder.
+fn configure_jpeg(enc_jpeg_file: &mut EncJpeg, args: &Cli) -> io::Result<()> {
+    enc_jpeg_file.set_optimize_huffman(args.optimize_huffman);
+
+    if let Some(subsampling) = args.subsampling {
+        enc_jpeg_file.set_subsampling(subsampling);
+    }
+
+    if args.progressive {
+        enc_jpeg_file.set_progressive();
+    }
+
+    if let Some(scans_path) = args.scans_path.as_ref() {
+        let script = std::fs::read_to_string(scans_path)?;
+        let scans = JpegScan::parse_script(&script).expect("Couldn't read scan script!");
+        enc_jpeg_file.set_scan_script(Some(scans)).expect("Invalid scan script!");
+    }
+
+    if let Some(restart_interval) = args.restart_interval {
+        enc_jpeg_file.set_restart_interval(restart_interval);
+    }
+
+    if let Some(quality) = args.quality {
+        enc_jpeg_file.set_quality(quality).expect("Couldn't set JPEG quality!");
+    }
+
+    Ok(())
+}
+
+/// PNGs are decoded as they are read, so PPM and JPEG output never hold the whole image.
+fn convert_png(reader: BufReader<File>, args: Cli) -> io::Result<()> {
+    let mut png_reader = PngReader::new(reader).expect("Couldn't read PNG file!");
+
+    png_reader.print_chunks();
+
+    if let Some(background) = args.background {
+        png_reader.set_background(background);
+    }
+
+    if args.output_path.ends_with(".ppm") {
+        png_reader.write_to_p3(args.output_path).expect("Couldn't decompress PNG file");
+        return Ok(());
+    }
+
+    if args.output_path.ends_with(".png") {
+        let dec_png_file : DecPng = png_reader.try_into().expect("Couldn't decompress PNG file");
+        let png_file = EncPng::from_dec_png(&dec_png_file, args.png_filter, args.png_compression).expect("Couldn't convert PNG file!");
+        png_file.write_to_path(args.output_path).expect("Couldn't write PNG file!");
+        return Ok(());
+    }
+
+    let mut enc_jpeg_file = EncJpeg::for_png_reader(&png_reader).expect("Couldn't convert PNG file to JPEG!");
+    configure_jpeg(&mut enc_jpeg_file, &args)?;
+    enc_jpeg_file.write_png_to_path(png_reader, args.output_path).expect("Couldn't write JPEG file!");
+
+    Ok(())
 }
 
 fn main() -> io::Result<()> {
-    let args = Cli {
-        input_path: std::env::args().nth(1).expect("No input png filename specified!"),
-        output_path: std::env::args().nth(2).expect("No output jpeg filename specified!")
-    };
+    let args = Cli::from_args().expect("Couldn't read command line arguments!");
 
-    let f = File::open(args.input_path)?;
+    let f = File::open(&args.input_path)?;
     let mut reader = BufReader::new(f);
-    let mut buffer = vec![];
 
+    // JPEGs start with an SOI marker, anything else is read as a PNG.
+    if !reader.fill_buf()?.starts_with(&[0xFF, 0xD8]) {
+        return convert_png(reader, args);
+    }
+
+    let mut buffer = vec![];
     reader.read_to_end(&mut buffer)?;
-    let buffer = buffer;
 
-    let png_file: EncPng = buffer.try_into().expect("Couldn't read PNG file!");
+    let dec_jpeg_file = DecJpeg::try_from(buffer).expect("Couldn't decode JPEG file!");
 
-    png_file.print_chunks();
-    let dec_png_file : DecPng = png_file.decompress().expect("Couldn't decompress PNG file");
-    dec_png_file.write_to_p3(args.output_path);
+    if args.output_path.ends_with(".ppm") {
+        dec_jpeg_file.write_to_p3(args.output_path);
+        return Ok(());
+    }
 
+    if args.output_path.ends_with(".png") {
+        let png_file = EncPng::from_dec_jpeg(&dec_jpeg_file, args.png_filter, args.png_compression).expect("Couldn't convert JPEG file to PNG!");
+        png_file.write_to_path(args.output_path).expect("Couldn't write PNG file!");
+        return Ok(());
+    }
+
+    let mut enc_jpeg_file : EncJpeg = dec_jpeg_file.try_into().expect("Couldn't encode JPEG file!");
+    configure_jpeg(&mut enc_jpeg_file, &args)?;
+    enc_jpeg_file.write_to_path(args.output_path).expect("Couldn't write JPEG file!");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_background_color() {
+        assert_eq!(parse_hex_color("#FF8000"), Ok([255, 128, 0]));
+        assert_eq!(parse_hex_color("0a0B0c"), Ok([10, 11, 12]));
+        assert!(parse_hex_color("FF80").is_err());
+        assert!(parse_hex_color("GG0000").is_err());
+    }
+}
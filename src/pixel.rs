@@ -1,5 +1,7 @@
 use std::ops::{Deref, DerefMut};
 
+// Variants are named after the color types in the PNG specification.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, PartialEq)]
 pub enum ColorType {
     GS,
@@ -22,7 +24,7 @@ impl ColorType {
         }
     }
 
-    pub fn to_channels(&self) -> usize {
+    pub fn to_channels(self) -> usize {
         match self {
             ColorType::GS => 1,
             ColorType::GSA => 2,
@@ -34,62 +36,202 @@ impl ColorType {
     }
 }
 
+/// How much the chroma of an image is reduced compared to its luma when it is written as a JPEG.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Subsampling {
+    /// Full resolution chroma.
+    S444,
+    /// Half horizontal resolution chroma.
+    S422,
+    /// Half horizontal and half vertical resolution chroma.
+    S420,
+    /// Quarter horizontal resolution chroma.
+    S411,
+}
+
+impl Subsampling {
+    /// Returns the number of luma samples that share one chroma sample, horizontally and
+    /// vertically. These are the luma sampling factors of the JPEG frame header.
+    pub fn get_factors(self) -> (usize, usize) {
+        match self {
+            Subsampling::S444 => (1, 1),
+            Subsampling::S422 => (2, 1),
+            Subsampling::S420 => (2, 2),
+            Subsampling::S411 => (4, 1),
+        }
+    }
+}
+
+impl TryFrom<&str> for Subsampling {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "444" => Ok(Subsampling::S444),
+            "422" => Ok(Subsampling::S422),
+            "420" => Ok(Subsampling::S420),
+            "411" => Ok(Subsampling::S411),
+            _ => Err("Subsampling must be one of 444, 422, 420 or 411!"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Pixel {
     color_type : ColorType,
-    channels : usize,
-    color_values : Vec<u8>
+
+    /// Number of bits in each of the color values - either 8 or 16.
+    bit_depth : u32,
+    color_values : Vec<u16>
 }
 
 impl Pixel {
     pub fn new(color_type: ColorType, data: Vec<u8>) -> Pixel {
+        Pixel::with_bit_depth(color_type, 8, data.into_iter().map(|x| x as u16).collect())
+    }
+
+    /// Creates a pixel whose color values are bit_depth bits long. Only bit depths of 8 and 16 are
+    /// stored - smaller samples are scaled up to 8 bits when they are unpacked.
+    pub fn with_bit_depth(color_type: ColorType, bit_depth: u32, data: Vec<u16>) -> Pixel {
         let channels = color_type.to_channels();
         let color_values = data[0..channels].to_vec();
 
         Pixel {
             color_type, 
-            channels,
+            bit_depth,
             color_values 
         } 
     }
 
+    /// Returns the color values scaled to 8 bits. 16 bit values are rounded to the nearest 8 bit
+    /// value.
     pub fn get_color_values(&self) -> Vec<u8> {
-        self.color_values.clone()
+        self.color_values
+            .iter()
+            .map(|&x| match self.bit_depth {
+                16 => Self::sample_to_eight_bit(x),
+                _ => x as u8,
+            })
+            .collect()
+    }
+
+    fn sample_to_eight_bit(sample: u16) -> u8 {
+        // 65535 maps to 255, so each 8 bit step is 257 16 bit steps wide.
+        ((sample as u32 * 255 + 32767) / 65535) as u8
     }
 
     pub fn get_color_type(&self) -> ColorType {
         self.color_type
     }
+
+    pub fn get_bit_depth(&self) -> u32 {
+        self.bit_depth
+    }
+
+    /// Returns the samples of the pixel as color_type - GS, GSA, RGB or RGBA - scaled to
+    /// bit_depth bits. Color pixels become gray by their luma, and alpha is dropped if color_type
+    /// has none or is fully opaque if the pixel has none.
+    pub fn to_samples(&self, color_type : ColorType, bit_depth : u32) -> Vec<u16> {
+        let (rgb, depth) = match self.color_type {
+            ColorType::YCbCr => (self.to_rgb().color_values, 8),
+            _ => (self.to_rgb().color_values, self.bit_depth),
+        };
+
+        let max = (1u32 << depth) - 1;
+        let alpha = match self.color_type {
+            ColorType::GSA => self.color_values[1] as u32,
+            ColorType::RGBA => self.color_values[3] as u32,
+            _ => max,
+        };
+
+        let gray = match rgb[..] {
+            [r, g, b] if r == g && g == b => r as u32,
+            [r, g, b] => (299 * r as u32 + 587 * g as u32 + 114 * b as u32 + 500) / 1000,
+            _ => panic!("RGB conversion invalid"),
+        };
+
+        let samples = match color_type {
+            ColorType::GS => vec![gray],
+            ColorType::GSA => vec![gray, alpha],
+            ColorType::RGB => rgb.iter().map(|&x| x as u32).collect(),
+            ColorType::RGBA => rgb.iter().map(|&x| x as u32).chain([alpha]).collect(),
+            _ => panic!("Pixels can only be turned into GS, GSA, RGB or RGBA samples!"),
+        };
+
+        // Rounds to the nearest value at the new bit depth
+        let (max, new_max) = (max as u64, (1u64 << bit_depth) - 1);
+        samples
+            .into_iter()
+            .map(|x| ((x as u64 * new_max * 2 + max) / (max * 2)) as u16)
+            .collect()
+    }
     
     fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> Vec<u8> {
         // SEE: https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-rdprfx/b550d1b5-f7d9-4a0c-9141-b3dca9d7f525
         // This function uses the above method but with the Cr and Cb values shifted by +128 to
         // make all three values fit into a u8.
         let y_value_f64 = 0.299 * (r as f64) + 0.587 * (g as f64) + 0.114 * (b as f64);
-        let y_value_u8 = y_value_f64 as u8;
+        let y_value_u8 = y_value_f64.round().clamp(0.0, 255.0) as u8;
 
         let cb_value_f64 = 128f64 -0.1687 * (r as f64) -0.3313 * (g as f64)  + 0.5 * (b as f64);
-        let cb_value_u8 = cb_value_f64 as u8;
+        let cb_value_u8 = cb_value_f64.round().clamp(0.0, 255.0) as u8;
 
         let cr_value_f64 = 128f64 + 0.5 * (r as f64) -0.4187 * (g as f64)  + -0.0813 * (b as f64);
-        let cr_value_u8 = cr_value_f64 as u8;
+        let cr_value_u8 = cr_value_f64.round().clamp(0.0, 255.0) as u8;
         
         vec![y_value_u8,cb_value_u8,cr_value_u8]
     }
 
-    fn decode_plte(&self, plte_bytes : &Vec<u8>) -> Pixel {
-        
-        let plte_index = (self.color_values[0] * 3) as usize;
-        let color_values = vec![plte_bytes[plte_index], 
-            plte_bytes[plte_index + 1], 
-            plte_bytes[plte_index + 2]
-        ];
+    fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> Vec<u8> {
+        // Inverse of rgb_to_ycbcr() using the JFIF conversion, with the +128 shift of Cb and Cr
+        // removed first.
+        let (y, cb, cr) = (y as f64, cb as f64 - 128.0, cr as f64 - 128.0);
 
-        Pixel {
-            color_type : ColorType::RGB,
-            channels : 3,
-            color_values : color_values,
+        let r_value_f64 = y + 1.402 * cr;
+        let g_value_f64 = y - 0.344136 * cb - 0.714136 * cr;
+        let b_value_f64 = y + 1.772 * cb;
+
+        [r_value_f64, g_value_f64, b_value_f64]
+            .into_iter()
+            .map(|x| x.round().clamp(0.0, 255.0) as u8)
+            .collect()
+    }
+
+    fn decode_plte(&self, plte_bytes : &[u8], trns_bytes : &[u8]) -> Result<Pixel, &'static str> {
+        let plte_index = self.color_values[0] as usize * 3;
+        let mut color_values = match plte_bytes.get(plte_index..plte_index + 3) {
+            Some(entry) => entry.to_vec(),
+            None => return Err("Palette index is past the end of the palette!"),
+        };
+
+        if trns_bytes.is_empty() {
+            return Ok(Pixel::new(ColorType::RGB, color_values));
         }
+
+        // Palette entries past the end of tRNS are fully opaque.
+        color_values.push(*trns_bytes.get(self.color_values[0] as usize).unwrap_or(&255));
+
+        Ok(Pixel::new(ColorType::RGBA, color_values))
+    }
+
+    /// Adds an alpha channel to a GS or RGB pixel, which is fully transparent if the pixel
+    /// matches the color key and fully opaque otherwise.
+    fn apply_color_key(&self, color_key : &[u16]) -> Pixel {
+        let color_type = match self.color_type {
+            ColorType::GS => ColorType::GSA,
+            ColorType::RGB => ColorType::RGBA,
+            _ => return self.clone(),
+        };
+
+        let alpha = match self.color_values == color_key {
+            true => 0,
+            false => ((1u32 << self.bit_depth) - 1) as u16,
+        };
+
+        let mut color_values = self.color_values.clone();
+        color_values.push(alpha);
+
+        Pixel::with_bit_depth(color_type, self.bit_depth, color_values)
     }
     
     
@@ -98,10 +240,40 @@ impl Pixel {
             ColorType::RGB => self.color_values.clone(),
             ColorType::RGBA => self.color_values.clone()[0..3].to_vec(),
             ColorType::GS | ColorType::GSA => vec![self.color_values[0]; 3],
+            ColorType::YCbCr => match self.get_color_values()[0..3] {
+                [y, cb, cr] => Self::ycbcr_to_rgb(y, cb, cr).into_iter().map(|x| x as u16).collect(),
+                _ => panic!("YCbCr conversion invalid"),
+            },
             _ => panic!("Cannot change pixel type into RGB!"),
         };
 
-        Pixel::new(ColorType::RGB, rgb_data)
+        Pixel::with_bit_depth(ColorType::RGB, self.bit_depth, rgb_data)
+    }
+
+    /// Blends the pixel with an 8 bit RGB background according to its alpha channel and returns
+    /// it as RGB. Pixels without an alpha channel are only converted to RGB.
+    pub fn flatten_alpha(&self, background : [u8; 3]) -> Pixel {
+        let rgb = self.to_rgb();
+
+        let alpha = match self.color_type {
+            ColorType::RGBA => self.color_values[3] as u32,
+            ColorType::GSA => self.color_values[1] as u32,
+            _ => return rgb,
+        };
+
+        // Background values are scaled to the bit depth of the pixel, so 16 bit pixels are
+        // blended without losing precision.
+        let max = (1u32 << self.bit_depth) - 1;
+        let color_values = rgb.color_values
+            .iter()
+            .zip(background)
+            .map(|(&color, background)| {
+                let background = background as u32 * max / 255;
+                ((color as u32 * alpha + background * (max - alpha) + max / 2) / max) as u16
+            })
+            .collect();
+
+        Pixel::with_bit_depth(ColorType::RGB, self.bit_depth, color_values)
     }
 
     pub fn to_ycbcr(&self) -> Pixel {
@@ -110,7 +282,7 @@ impl Pixel {
             _ => self.to_rgb(),
         };
         
-        let ycbcr_data : Vec<u8> = match rgb_self.color_values[0..3] {
+        let ycbcr_data : Vec<u8> = match rgb_self.get_color_values()[0..3] {
             [r,g,b] => Self::rgb_to_ycbcr(r, g, b),
             _ => panic!("RGB conversion invalid")
         };
@@ -127,24 +299,40 @@ impl Pixels {
         Pixels(vec![])
     }
 
-    pub fn decode_plte(&self, plte_bytes : Vec<u8>) -> Pixels { 
+    /// Looks up every palette index in plte_bytes. If trns_bytes isn't empty the pixels also get
+    /// the alpha value of their palette entry and become RGBA. Indices past the end of the palette
+    /// are an error.
+    pub fn decode_plte(&self, plte_bytes : Vec<u8>, trns_bytes : Vec<u8>) -> Result<Pixels, &'static str> { 
         let mut rgb_pixels = Pixels::new();
         
         for (row_num, row) in self.iter().enumerate() {
             rgb_pixels.push(vec![]);
 
             for pixel in row {
-                rgb_pixels[row_num].push(pixel.decode_plte(&plte_bytes));
+                rgb_pixels[row_num].push(pixel.decode_plte(&plte_bytes, &trns_bytes)?);
             }
         }
 
-        rgb_pixels
+        Ok(rgb_pixels)
+    }
+
+    pub fn apply_color_key(&self, color_key : &[u16]) -> Pixels {
+        let mut keyed_pixels = Pixels::new();
+
+        for (row_num, row) in self.iter().enumerate() {
+            keyed_pixels.push(vec![]);
+
+            for pixel in row {
+                keyed_pixels[row_num].push(pixel.apply_color_key(color_key));
+            }
+        }
 
+        keyed_pixels
     }
 
     pub fn to_rgb(&self) -> Pixels {
         let mut rgb_pixels = Pixels::new();
-        
+
         for (row_num, row) in self.iter().enumerate() {
             rgb_pixels.push(vec![]);
 
@@ -156,6 +344,20 @@ impl Pixels {
         rgb_pixels
     }
 
+    pub fn flatten_alpha(&self, background : [u8; 3]) -> Pixels {
+        let mut rgb_pixels = Pixels::new();
+
+        for (row_num, row) in self.iter().enumerate() {
+            rgb_pixels.push(vec![]);
+
+            for pixel in row {
+                rgb_pixels[row_num].push(pixel.flatten_alpha(background));
+            }
+        }
+
+        rgb_pixels
+    }
+
     pub fn to_ycbcr(&self) -> Pixels {
         let mut ycbcr_pixels = Pixels::new();
 
@@ -170,25 +372,47 @@ impl Pixels {
         ycbcr_pixels
     }
 
-    pub fn subsample_ycbcr(&self) -> Pixels {
-        //4 : 2 : 0 subsampling. 
-
+    /// Every pixel keeps its own luma but takes the average chroma of the box of pixels that share
+    /// a chroma sample. Boxes at the right and bottom edges only average the pixels inside the
+    /// image.
+    pub fn subsample_ycbcr(&self, subsampling : Subsampling) -> Pixels {
+        let (box_width, box_height) = subsampling.get_factors();
         let mut subsampled_pixels = Pixels::new();
 
-        for y in 0..self.len() {
-            let mut row = vec![];
-            for x in 0..self[y].len() {
-                match (y % 2, x % 2) {
-                    (0, 0) => row.push(self[y][x].clone()),
-                    (0, 1) => row.push(self[y][x-1].clone()),
-                    (1, 0) => row.push(self[y-1][x].clone()),
-                    (1, 1) => row.push(self[y-1][x-1].clone()),
-                    _ => (),
-                }
+        for box_row in (0..self.len()).step_by(box_height) {
+            let rows = &self[box_row..(box_row + box_height).min(self.len())];
+
+            let chroma : Vec<[u8; 2]> = (0..self[box_row].len())
+                .step_by(box_width)
+                .map(|box_col| {
+                    let mut sums = [0u32; 2];
+                    let mut count = 0u32;
+
+                    for row in rows {
+                        for pixel in &row[box_col..(box_col + box_width).min(row.len())] {
+                            let values = pixel.get_color_values();
+                            sums[0] += values[1] as u32;
+                            sums[1] += values[2] as u32;
+                            count += 1;
+                        }
+                    }
+
+                    sums.map(|x| ((x + count / 2) / count) as u8)
+                })
+                .collect();
+
+            for row in rows {
+                subsampled_pixels.push(
+                    row.iter()
+                        .enumerate()
+                        .map(|(x, pixel)| {
+                            let [blue, red] = chroma[x / box_width];
+                            Pixel::new(ColorType::YCbCr, vec![pixel.get_color_values()[0], blue, red])
+                        })
+                        .collect()
+                );
             }
-
-            subsampled_pixels.push(row);
-        } 
+        }
 
         subsampled_pixels
     }
@@ -221,7 +445,77 @@ mod tests {
     
     #[test]
     fn test_rgb_ycbcr() {
-        assert_eq!(vec![0, 255, 255], Pixel::rgb_to_ycbcr(255, 255, 255))
+        assert_eq!(vec![255, 128, 128], Pixel::rgb_to_ycbcr(255, 255, 255));
+        assert_eq!(vec![0, 128, 128], Pixel::rgb_to_ycbcr(0, 0, 0))
+    }
+
+    #[test]
+    fn test_sixteen_bit_to_eight_bit() {
+        let pixel = Pixel::with_bit_depth(ColorType::RGBA, 16, vec![0, 65535, 257 * 100, 128]);
+
+        assert_eq!(pixel.color_values, vec![0, 65535, 25700, 128]);
+        assert_eq!(pixel.get_color_values(), vec![0, 255, 100, 0]);
+        assert_eq!(pixel.to_rgb().bit_depth, 16);
+    }
+
+    #[test]
+    fn test_decode_plte() {
+        let plte = [10, 20, 30, 40, 50, 60];
+        let pixels : Pixels = vec![vec![Pixel::new(ColorType::GS, vec![1]), Pixel::new(ColorType::GS, vec![0])]].into();
+
+        let decoded = pixels.decode_plte(plte.to_vec(), vec![128]).unwrap();
+        assert_eq!(decoded[0][0].get_color_values(), vec![40, 50, 60, 255]);
+        assert_eq!(decoded[0][1].get_color_values(), vec![10, 20, 30, 128]);
+
+        let past_end : Pixels = vec![vec![Pixel::new(ColorType::GS, vec![2])]].into();
+        assert_eq!(past_end.decode_plte(plte.to_vec(), vec![]).err(), Some("Palette index is past the end of the palette!"));
+    }
+
+    #[test]
+    fn test_color_key() {
+        let key = [1000, 2000, 3000];
+        let keyed = Pixel::with_bit_depth(ColorType::RGB, 16, vec![1000, 2000, 3000]).apply_color_key(&key);
+        let opaque = Pixel::with_bit_depth(ColorType::RGB, 16, vec![1000, 2000, 3001]).apply_color_key(&key);
+
+        assert!(keyed.get_color_type() == ColorType::RGBA);
+        assert_eq!(keyed.color_values, vec![1000, 2000, 3000, 0]);
+        assert_eq!(opaque.color_values, vec![1000, 2000, 3001, 65535]);
+    }
+
+    #[test]
+    fn test_subsample_ycbcr() {
+        // 3x3 image whose luma is 10 times the pixel number and whose chroma is the pixel number
+        // and 100 plus the pixel number.
+        let pixels : Pixels = (0..3)
+            .map(|y| (0..3).map(|x| {
+                let i = (y * 3 + x) as u8;
+                Pixel::new(ColorType::YCbCr, vec![i * 10, i, 100 + i])
+            }).collect())
+            .collect::<Vec<Vec<Pixel>>>()
+            .into();
+
+        let subsampled = pixels.subsample_ycbcr(Subsampling::S420);
+        let values : Vec<Vec<u8>> = subsampled.iter().flatten().map(|x| x.get_color_values()).collect();
+
+        assert_eq!(values[0], vec![0, 2, 102]);
+        assert_eq!(values[4], vec![40, 2, 102]);
+        assert_eq!(values[2], vec![20, 4, 104]);
+        assert_eq!(values[8], vec![80, 8, 108]);
+        assert_eq!(values[7], vec![70, 7, 107]);
+
+        let unchanged = pixels.subsample_ycbcr(Subsampling::S444);
+        assert!(unchanged.iter().flatten().zip(pixels.iter().flatten()).all(|(a, b)| a.get_color_values() == b.get_color_values()));
+    }
+
+    #[test]
+    fn test_flatten_alpha() {
+        let half = Pixel::new(ColorType::RGBA, vec![0, 100, 255, 128]);
+        let clear_gray = Pixel::with_bit_depth(ColorType::GSA, 16, vec![1234, 0]);
+        let opaque_gray = Pixel::new(ColorType::GSA, vec![40, 255]);
+
+        assert_eq!(half.flatten_alpha([255, 255, 255]).get_color_values(), vec![127, 177, 255]);
+        assert_eq!(clear_gray.flatten_alpha([10, 20, 30]).get_color_values(), vec![10, 20, 30]);
+        assert_eq!(opaque_gray.flatten_alpha([10, 20, 30]).get_color_values(), vec![40, 40, 40]);
     }
 }
 
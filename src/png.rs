@@ -1,14 +1,28 @@
 use crate::pixel::{Pixel, Pixels, ColorType};
+use crate::jpeg::DecJpeg;
+use std::collections::HashMap;
 use std::fs;
+use std::io::{BufWriter, Read, Write};
 use crate::utils;
-use crate::utils::Defilter;
+use crate::utils::{Bits, ByteSource, CompressionLevel, Crc32, Defilter, Filter, FilterStrategy, Inflater};
 
+const PNG_HEADER: [u8; 8] = [137u8, 80u8, 78u8, 71u8, 13u8, 10u8, 26u8, 10u8];
+
+/// Most bytes of compressed image data written in a single IDAT chunk.
+const MAX_IDAT_LENGTH: usize = 65536;
+
+/// Most bytes of compressed image data read from an IDAT chunk at a time while streaming.
+const IDAT_READ_SIZE: usize = 8192;
+
+/// Most bytes a single scanline may take up, which keeps a corrupted width from allocating huge rows.
+const MAX_ROW_BYTES: usize = 1 << 26;
+
+#[allow(dead_code)]
 pub enum Png {
     Decoded(DecPng),
     Encoded(EncPng),
 }
 
-
 pub struct PngChunk {
     chunk_length: usize,
     chunk_type: ChunkType,
@@ -16,26 +30,26 @@ pub struct PngChunk {
     chunk_crc: [u8; 4],
 }
 
+// Variants are named after the chunk types in the PNG specification.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq)]
 pub enum ChunkType {
     IHDR,
     PLTE,
     IDAT,
     IEND,
+    TRNS,
+    BKGD,
     Unknown,
 }
 
 impl PngChunk {
     pub fn verify_crc(&self) -> Result<bool, &'static str> {
-        let chunk_data = self.get_data();
-        let mut crc_data = ChunkType::bytes_from_type(self.get_type())?.to_vec();
-        crc_data.append(&mut chunk_data.clone());
+        let mut crc = Crc32::new();
+        crc.update(&ChunkType::bytes_from_type(self.get_type())?);
+        crc.update(self.get_data());
 
-        match utils::png_crc(crc_data) {
-            Ok(x) if x == self.get_crc() => Ok(true),
-            Ok(_) => Ok(false),
-            Err(x) => Err(x),
-        }
+        Ok(crc.finalize() == self.get_crc())
     }
 
     pub fn new(c_length: usize, c_type: ChunkType, c_data: Vec<u8>, c_crc: [u8; 4]) -> PngChunk {
@@ -47,6 +61,26 @@ impl PngChunk {
         }
     }
 
+    /// Creates a chunk holding c_data, working out its length and CRC.
+    pub fn from_data(c_type: ChunkType, c_data: Vec<u8>) -> Result<PngChunk, &'static str> {
+        let mut crc = Crc32::new();
+        crc.update(&ChunkType::bytes_from_type(&c_type)?);
+        crc.update(&c_data);
+        let c_crc = crc.finalize();
+
+        Ok(PngChunk::new(c_data.len(), c_type, c_data, c_crc))
+    }
+
+    /// Appends the chunk to bytes the way it is stored in a file - length, type, data and CRC.
+    pub fn append_to(&self, bytes: &mut Vec<u8>) -> Result<(), &'static str> {
+        bytes.extend_from_slice(&(self.get_length() as u32).to_be_bytes());
+        bytes.extend_from_slice(&ChunkType::bytes_from_type(self.get_type())?);
+        bytes.extend_from_slice(self.get_data());
+        bytes.extend_from_slice(&self.get_crc());
+
+        Ok(())
+    }
+
     pub fn get_length(&self) -> usize {
         self.chunk_length
     }
@@ -71,6 +105,8 @@ impl ChunkType {
             [80u8, 76u8, 84u8, 69u8] => ChunkType::PLTE,
             [73u8, 68u8, 65u8, 84u8] => ChunkType::IDAT,
             [73u8, 69u8, 78u8, 68u8] => ChunkType::IEND,
+            [116u8, 82u8, 78u8, 83u8] => ChunkType::TRNS,
+            [98u8, 75u8, 71u8, 68u8] => ChunkType::BKGD,
             _ => ChunkType::Unknown,
         }
     }
@@ -80,6 +116,8 @@ impl ChunkType {
             ChunkType::PLTE => Ok([80u8, 76u8, 84u8, 69u8]),
             ChunkType::IDAT => Ok([73u8, 68u8, 65u8, 84u8]),
             ChunkType::IEND => Ok([73u8, 69u8, 78u8, 68u8]),
+            ChunkType::TRNS => Ok([116u8, 82u8, 78u8, 83u8]),
+            ChunkType::BKGD => Ok([98u8, 75u8, 71u8, 68u8]),
             ChunkType::Unknown => Err("ChunkType::Unknown has no defined bytes!"),
         }
     }
@@ -92,6 +130,8 @@ impl std::fmt::Display for ChunkType {
             ChunkType::IDAT => write!(f, "IDAT"),
             ChunkType::IEND => write!(f, "IEND"),
             ChunkType::PLTE => write!(f, "PLTE"),
+            ChunkType::TRNS => write!(f, "tRNS"),
+            ChunkType::BKGD => write!(f, "bKGD"),
             ChunkType::Unknown => write!(f, "Unknown"),
         }
     }
@@ -132,10 +172,153 @@ impl EncPng {
         }
     }
 
+    // Decodes the whole image at once. The command line streams PNGs through PngReader instead.
+    #[allow(dead_code)]
     pub fn decompress(self) -> Result<DecPng, &'static str> {
         DecPng::try_from(self)
     }
 
+    /// Encodes pixels as a PNG with the given color type (0, 2, 3, 4 or 6) and bit depth.
+    ///
+    /// Palette images get a palette of every color in the image, in the order they first appear,
+    /// and a tRNS chunk if any of them are transparent. Other color types convert the samples as
+    /// Pixel::to_samples() does. Scanlines are filtered as the strategy chooses, and the image
+    /// data is compressed at the given level.
+    pub fn from_pixels(pixels: &Pixels, color: u32, bit_depth: u32, filter: FilterStrategy, compression: CompressionLevel) -> Result<EncPng, &'static str> {
+        if !valid_bit_depths(color)?.contains(&bit_depth) {
+            return Err("INVALID BIT DEPTH FOR COLOR TYPE!");
+        }
+
+        let (height, width) = (pixels.len(), pixels.first().map_or(0, |x| x.len()));
+        if height == 0 || width == 0 {
+            return Err("Image must have at least one pixel to be written as a PNG!");
+        }
+
+        let mut palette : Vec<Vec<u16>> = vec![];
+        let mut rows : Vec<Vec<u16>> = vec![];
+
+        match color {
+            3 => {
+                let mut indices : HashMap<Vec<u16>, u16> = HashMap::new();
+
+                for row in pixels.iter() {
+                    let mut row_indices = vec![];
+
+                    for pixel in row {
+                        let rgba = pixel.to_samples(ColorType::RGBA, 8);
+                        let index = *indices.entry(rgba.clone()).or_insert_with(|| {
+                            palette.push(rgba);
+                            palette.len() as u16 - 1
+                        });
+
+                        row_indices.push(index);
+                    }
+
+                    rows.push(row_indices);
+                }
+
+                if palette.len() > 1 << bit_depth {
+                    return Err("Image has too many colors for a palette of this bit depth!");
+                }
+            },
+            _ => {
+                let color_type = ColorType::from_png_color_type(color as usize);
+
+                for row in pixels.iter() {
+                    rows.push(row.iter().flat_map(|x| x.to_samples(color_type, bit_depth)).collect());
+                }
+            },
+        };
+
+        let mut png = EncPng::new();
+        let mut ihdr = vec![];
+        ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+        // Bit depth, color type, compression method, filter method and interlace method
+        ihdr.extend_from_slice(&[bit_depth as u8, color as u8, 0, 0, 0]);
+        png.add_chunk(PngChunk::from_data(ChunkType::IHDR, ihdr)?);
+
+        if color == 3 {
+            let plte = palette.iter().flat_map(|x| x[0..3].iter().map(|&y| y as u8)).collect();
+            png.add_chunk(PngChunk::from_data(ChunkType::PLTE, plte)?);
+
+            // Entries past the end of tRNS are opaque, so it stops at the last transparent one.
+            let mut trns : Vec<u8> = palette.iter().map(|x| x[3] as u8).collect();
+            while trns.last() == Some(&255) {
+                trns.pop();
+            }
+
+            if !trns.is_empty() {
+                png.add_chunk(PngChunk::from_data(ChunkType::TRNS, trns)?);
+            }
+        }
+
+        // The PNG specification recommends no filtering for palette and sub-byte images, whose
+        // bytes are indices or packed samples that the filters can't predict well.
+        let filter = match filter {
+            FilterStrategy::MinSum if color == 3 || bit_depth < 8 => FilterStrategy::Fixed(0),
+            _ => filter,
+        };
+
+        let scanlines = rows.iter().map(|x| utils::samples_to_scanline(x, bit_depth)).collect();
+        let filtered = Filter::new(channel_count(color)?, bit_depth, scanlines).filter(filter)?;
+
+        for idat in utils::compress(&filtered, compression)?.chunks(MAX_IDAT_LENGTH) {
+            png.add_chunk(PngChunk::from_data(ChunkType::IDAT, idat.to_vec())?);
+        }
+
+        png.add_chunk(PngChunk::from_data(ChunkType::IEND, vec![])?);
+
+        Ok(png)
+    }
+
+    /// Encodes a decoded PNG again with the color type and bit depth it was decoded with.
+    pub fn from_dec_png(decpng: &DecPng, filter: FilterStrategy, compression: CompressionLevel) -> Result<EncPng, &'static str> {
+        let first_pixel = decpng.scanlines
+            .first()
+            .and_then(|x| x.first())
+            .ok_or("Image must have at least one pixel to be written as a PNG!")?;
+
+        let (color, bit_depth) = match first_pixel.get_color_type() {
+            ColorType::GS => (0, first_pixel.get_bit_depth()),
+            ColorType::RGB => (2, first_pixel.get_bit_depth()),
+            ColorType::GSA => (4, first_pixel.get_bit_depth()),
+            ColorType::RGBA => (6, first_pixel.get_bit_depth()),
+            _ => (2, 8),
+        };
+
+        EncPng::from_pixels(&decpng.scanlines, color, bit_depth, filter, compression)
+    }
+
+    /// Writes 8 bit grayscale or RGB, depending on whether the JPEG had any chroma.
+    pub fn from_dec_jpeg(decjpeg: &DecJpeg, filter: FilterStrategy, compression: CompressionLevel) -> Result<EncPng, &'static str> {
+        let color = match decjpeg.is_grayscale() {
+            true => 0,
+            false => 2,
+        };
+
+        EncPng::from_pixels(&decjpeg.get_rgb_pixels(), color, 8, filter, compression)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, &'static str> {
+        let mut bytes = PNG_HEADER.to_vec();
+
+        for chunk in self.chunks.iter() {
+            chunk.append_to(&mut bytes)?;
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn write_to_path(&self, path : String) -> Result<(), &'static str> {
+        let write_bytes = self.to_bytes()?;
+
+        match fs::write(path, write_bytes) {
+            Ok(_) => Ok(()),
+            Err(_) => Err("Unable to write file"),
+        }
+    }
+
     pub fn get_deflate_stream(&self) -> Vec<u8> {
         let mut deflate_stream: Vec<u8> = vec![];
 
@@ -160,6 +343,22 @@ impl EncPng {
         }
     }
     
+    /// Returns the contents of the tRNS chunk, if the image has one. The chunk is optional, so
+    /// unlike PLTE a missing chunk isn't an error.
+    pub fn get_trns_bytes(&self) -> Option<Vec<u8>> {
+        self.chunks
+            .iter()
+            .find(|x| *x.get_type() == ChunkType::TRNS)
+            .map(|x| x.get_data().clone())
+    }
+
+    pub fn get_bkgd_bytes(&self) -> Option<Vec<u8>> {
+        self.chunks
+            .iter()
+            .find(|x| *x.get_type() == ChunkType::BKGD)
+            .map(|x| x.get_data().clone())
+    }
+
     fn get_ihdr_info(&self, start: usize, bytes: usize) -> Result<u32, &'static str> {
         let info : Vec<u8> = self.chunks
             .iter()
@@ -209,8 +408,6 @@ impl TryFrom<Vec<u8>> for EncPng {
     fn try_from(buffer: Vec<u8>) -> Result<Self, Self::Error> {
         let mut out_png = EncPng::new();
 
-        const PNG_HEADER: [u8; 8] = [137u8, 80u8, 78u8, 71u8, 13u8, 10u8, 26u8, 10u8];
-
         if buffer.iter().take(8).eq(&PNG_HEADER) {
             println!("Valid PNG header.");
         } else {
@@ -249,13 +446,7 @@ impl TryFrom<Vec<u8>> for EncPng {
                 chunk_crc_bytes.try_into().unwrap(),
             );
             
-            match png_chunk.verify_crc() {
-                Ok(false) => return Err("Invalid CRC!"),
-                Ok(_) => (), 
-                Err(_) if *png_chunk.get_type() == ChunkType::Unknown => (),
-                Err(x) => return Err(x),
-            };
-
+            check_crc(&png_chunk)?;
             out_png.add_chunk(png_chunk);
 
             buffer_mut = &buffer_mut[12 + chunk_length..];
@@ -263,15 +454,31 @@ impl TryFrom<Vec<u8>> for EncPng {
         Ok(out_png)
     }
 }
+/// Errors if a chunk's CRC is wrong. Chunks of unknown types aren't checked.
+fn check_crc(chunk: &PngChunk) -> Result<(), &'static str> {
+    match chunk.verify_crc() {
+        Ok(false) => Err("Invalid CRC!"),
+        Ok(_) => Ok(()), 
+        Err(_) if *chunk.get_type() == ChunkType::Unknown => Ok(()),
+        Err(x) => Err(x),
+    }
+}
+
 pub struct DecPng {
     scanlines: Pixels,
+
+    /// 8 bit RGB color that transparent pixels are blended with - either taken from bKGD or
+    /// chosen by the user.
+    background: Option<[u8; 3]>,
 }
 
 impl DecPng {
+    #[allow(dead_code)]
     pub fn new() -> DecPng {
-        DecPng { scanlines: Pixels::new() }
+        DecPng { scanlines: Pixels::new(), background: None }
     }
 
+    #[allow(dead_code)]
     pub fn set_scanlines(&mut self, scanlines: Vec<Vec<Pixel>>) {
         self.scanlines = Pixels::from(scanlines);
     }
@@ -280,92 +487,935 @@ impl DecPng {
         self.scanlines.clone()
     }
 
+    /// Returns the color that transparent pixels should be blended with. Images without a
+    /// background are blended with white.
+    pub fn get_background(&self) -> [u8; 3] {
+        self.background.unwrap_or([255, 255, 255])
+    }
+
+    #[allow(dead_code)]
+    pub fn set_background(&mut self, background: [u8; 3]) {
+        self.background = Some(background);
+    }
 }
 
 impl From<Pixels> for DecPng {
     fn from(scanlines: Pixels) -> Self {
-        DecPng { scanlines }
+        DecPng { scanlines, background: None }
     }
 }
 
-impl TryFrom<EncPng> for DecPng {
-    type Error = &'static str;
+/// Converts the contents of a bKGD chunk into an 8 bit RGB color. Grayscale and RGB backgrounds
+/// are stored as 2 byte samples at the image bit depth, and palette backgrounds as a palette index.
+fn bkgd_to_rgb(bkgd_bytes: &[u8], color: u32, bit_depth: u32, plte_bytes: &[u8]) -> Result<[u8; 3], &'static str> {
+    let samples : Vec<u8> = match color {
+        3 => {
+            let plte_index = *bkgd_bytes.first().ok_or("INVALID bKGD CHUNK LENGTH!")? as usize * 3;
+            plte_bytes.get(plte_index..plte_index + 3).ok_or("bKGD PALETTE INDEX OUT OF RANGE!")?.to_vec()
+        },
+        _ => bkgd_bytes
+            .chunks_exact(2)
+            .map(|x| u16::from_be_bytes([x[0], x[1]]) as u32)
+            .map(|x| match bit_depth {
+                16 => ((x * 255 + 32767) / 65535) as u8,
+                8 => x as u8,
+                _ => utils::scale_gray_sample(x & ((1 << bit_depth) - 1), bit_depth),
+            })
+            .collect(),
+    };
+
+    match (color, samples.as_slice()) {
+        (0 | 4, &[gray]) => Ok([gray; 3]),
+        (2 | 3 | 6, &[r, g, b]) => Ok([r, g, b]),
+        _ => Err("INVALID bKGD CHUNK LENGTH!"),
+    }
+}
 
-    fn try_from(encpng: EncPng) -> Result<Self, Self::Error> {
-        //let scanlines = encpng.get_deflate_stream().decompress().scalines().defilter()
-        let (height, width, bit_depth, color, il) = (encpng.get_height()?, encpng.get_width()?, encpng.get_pixel_depth()?, encpng.get_color_type()?, encpng.get_interlace_type()?);
-        let channels : usize = match color {
-            0 => 1,
-            2 => 3,
-            3 => 1,
-            4 => 2,
-            6 => 4,
-            _ => return Err("INVALID COLOR TYPE!")
-        };
+/// What is needed from the chunks before the image data to turn scanlines into pixels.
+struct PngInfo {
+    width : u32,
+    height : u32,
+    bit_depth : u32,
+    color : u32,
+    interlace : u32,
+    channels : usize,
+    plte_bytes : Vec<u8>,
+    trns_bytes : Option<Vec<u8>>,
+    background : Option<[u8; 3]>,
+}
+
+impl PngInfo {
+    fn from_chunks(encpng: &EncPng) -> Result<PngInfo, &'static str> {
+        let (height, width, bit_depth, color, interlace) = (encpng.get_height()?, encpng.get_width()?, encpng.get_pixel_depth()?, encpng.get_color_type()?, encpng.get_interlace_type()?);
+        let channels = channel_count(color)?;
+
+        if !valid_bit_depths(color)?.contains(&bit_depth) {
+            return Err("INVALID BIT DEPTH FOR COLOR TYPE!");
+        }
+
+        if interlace > 1 {
+            return Err("INVALID INTERLACE METHOD!");
+        }
+
+        // Dimensions are limited to 2^31 - 1 so they always fit in a signed 32-bit integer.
+        if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+            return Err("INVALID IMAGE DIMENSIONS!");
+        }
 
         let plte_bytes : Vec<u8> = match color {
             3 => encpng.get_plte_bytes()?,
             _ => vec![],
         };
 
+        // A palette has 1 to 2^bit_depth entries of 3 bytes each.
+        if color == 3 && (plte_bytes.is_empty() || !plte_bytes.len().is_multiple_of(3) || plte_bytes.len() / 3 > 1 << bit_depth) {
+            return Err("INVALID PLTE CHUNK LENGTH!");
+        }
+
+        // tRNS holds one alpha value per palette entry for palette images, and a single color
+        // key of 2 byte samples for grayscale and RGB images. Images with an alpha channel
+        // can't have one.
+        let trns_bytes : Option<Vec<u8>> = match color {
+            0 | 2 | 3 => encpng.get_trns_bytes(),
+            _ => None,
+        };
+
+        if color == 3 && trns_bytes.as_ref().is_some_and(|x| x.len() > plte_bytes.len() / 3) {
+            return Err("tRNS CHUNK HAS MORE ENTRIES THAN THE PALETTE!");
+        }
+
+        // bKGD is only a suggestion, so a broken one leaves the image on the default background
+        // rather than failing the decode.
+        let background : Option<[u8; 3]> = match encpng.get_bkgd_bytes() {
+            Some(bkgd_bytes) => bkgd_to_rgb(&bkgd_bytes, color, bit_depth, &plte_bytes).ok(),
+            None => None,
+        };
+
         println!("PNG DIMENSIONS : width {} height {}", width, height);
-        println!("depth {} bpp {} color {} channels {} il {}", bit_depth, bit_depth / 8, color, channels, il);
+        println!("depth {} bpp {} color {} channels {} il {}", bit_depth, bit_depth / 8, color, channels, interlace);
+
+        let info = PngInfo { width, height, bit_depth, color, interlace, channels, plte_bytes, trns_bytes, background };
+        if info.row_bytes() > MAX_ROW_BYTES {
+            return Err("Image is too wide to decode!");
+        }
+
+        Ok(info)
+    }
+
+    /// Number of bytes in a scanline of the image, not counting the filter type.
+    fn row_bytes(&self) -> usize {
+        (self.width as usize * self.channels * self.bit_depth as usize).div_ceil(8)
+    }
+
+    /// Number of bytes in the decompressed data of an Adam7 interlaced image - the scanlines of
+    /// every pass, each with its filter type. Passes with no pixels have no scanlines.
+    fn adam7_bytes(&self) -> usize {
+        (0..7)
+            .map(|pass| utils::adam7_pass_size(pass, self.width as usize, self.height as usize))
+            .filter(|&(pass_width, pass_height)| pass_width > 0 && pass_height > 0)
+            .map(|(pass_width, pass_height)| ((pass_width * self.channels * self.bit_depth as usize).div_ceil(8) + 1) * pass_height)
+            .sum()
+    }
+
+    /// Number of bytes a pixel takes up, rounded up to 1 for bit depths below 8. Filters compare
+    /// each byte with the one this many bytes to its left.
+    fn bytes_per_pixel(&self) -> usize {
+        (self.channels * self.bit_depth as usize).div_ceil(8)
+    }
+
+    /// Turns the decompressed bytes of an Adam7 interlaced image into its pixels.
+    fn adam7_to_pixels(&self, decompressed_stream: Vec<u8>) -> Result<Pixels, &'static str> {
+        let (width, height) = (self.width, self.height);
+        let bits_per_pixel = self.channels as u32 * self.bit_depth;
+        let filtered_passes = utils::decompressed_to_adam7_scanlines(decompressed_stream, width, height, bits_per_pixel)?;
+        let mut pass_pixels = vec![];
+
+        // Each pass is filtered as its own image, so the first scanline of every pass has
+        // no scanline above it.
+        for (pass, filtered_scanlines) in filtered_passes.into_iter().enumerate() {
+            let (pass_width, _) = utils::adam7_pass_size(pass, width as usize, height as usize);
+            let mut defilter = Defilter::new(self.channels, self.bit_depth, filtered_scanlines);
+            let defiltered_scanlines : Vec<Vec<u8>> = defilter.defilter()?;
+            pass_pixels.push(utils::defiltered_to_pixels(&defiltered_scanlines, self.color as usize, self.bit_depth, pass_width)?);
+        }
+
+        utils::deinterlace_adam7(pass_pixels, width, height)
+    }
+
+    /// Returns the color type pixels have once decode_transparency() has been applied.
+    fn pixel_color_type(&self) -> ColorType {
+        let transparent = self.trns_bytes.as_ref().is_some_and(|x| !x.is_empty());
+
+        match (self.color, transparent) {
+            (0, false) => ColorType::GS,
+            (0, true) | (4, _) => ColorType::GSA,
+            (2 | 3, false) => ColorType::RGB,
+            _ => ColorType::RGBA,
+        }
+    }
+
+    /// Looks palette indices up in PLTE, and applies the tRNS chunk of the image if it has one.
+    fn decode_transparency(&self, scanlines: Pixels) -> Result<Pixels, &'static str> {
+        match (self.color, &self.trns_bytes) {
+            (3, trns_bytes) => scanlines.decode_plte(self.plte_bytes.clone(), trns_bytes.clone().unwrap_or_default()),
+            (0 | 2, Some(trns_bytes)) => {
+                if trns_bytes.len() != 2 * self.channels {
+                    return Err("INVALID tRNS CHUNK LENGTH!");
+                }
+
+                // Keys are compared against samples as they are stored, so keys for 1, 2 and 4
+                // bit grayscale are scaled up to 8 bits the same way the samples were.
+                let bit_depth = self.bit_depth;
+                let color_key : Vec<u16> = trns_bytes
+                    .chunks(2)
+                    .map(|x| u16::from_be_bytes([x[0], x[1]]))
+                    .map(|x| match bit_depth {
+                        1 | 2 | 4 => utils::scale_gray_sample(x as u32 & ((1 << bit_depth) - 1), bit_depth) as u16,
+                        _ => x,
+                    })
+                    .collect();
+
+                Ok(scanlines.apply_color_key(&color_key))
+            },
+            // Images with an alpha channel ignore any tRNS they have.
+            _ => Ok(scanlines),
+        }
+    }
+}
+
+impl TryFrom<EncPng> for DecPng {
+    type Error = &'static str;
+
+    fn try_from(encpng: EncPng) -> Result<Self, Self::Error> {
+        let info = PngInfo::from_chunks(&encpng)?;
 
         let compressed_stream : Vec<u8> = encpng.get_deflate_stream();
         let decompressed_stream : Vec<u8> = utils::decompress(compressed_stream)?;
 
         println!("Decompressed bytes {}", decompressed_stream.len());
         
-        let filtered_scanlines : Vec<Vec<u8>> = utils::decompressed_to_scanlines(decompressed_stream, height);
-        
-        let mut defilter = Defilter::new(channels, bit_depth, filtered_scanlines);
+        let scanlines = match info.interlace {
+            0 => {
+                let filtered_scanlines : Vec<Vec<u8>> = utils::decompressed_to_scanlines(decompressed_stream, info.height);
+
+                let mut defilter = Defilter::new(info.channels, info.bit_depth, filtered_scanlines);
+
+                let defiltered_scanlines : Vec<Vec<u8>> = defilter.defilter()?;
+
+                utils::defiltered_to_pixels(&defiltered_scanlines, info.color as usize, info.bit_depth, info.width as usize)?
+            },
+            _ => info.adam7_to_pixels(decompressed_stream)?,
+        };
+
+        Ok(DecPng { scanlines: info.decode_transparency(scanlines)?, background: info.background })
+    }
+}
+
+/// Reads a chunk's length and type from reader.
+fn read_chunk_header<R: Read>(reader: &mut R) -> Result<(usize, [u8; 4]), &'static str> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).map_err(|_| "PNG ended before the IEND chunk!")?;
+
+    let chunk_length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    if chunk_length > i32::MAX as u32 {
+        return Err("PNG chunk is longer than the 2^31 - 1 bytes allowed!");
+    }
+
+    Ok((chunk_length as usize, [header[4], header[5], header[6], header[7]]))
+}
+
+/// Reads the data and CRC of a chunk whose header has just been read, and checks the CRC.
+fn read_chunk_body<R: Read>(reader: &mut R, chunk_length: usize, chunk_type: [u8; 4]) -> Result<PngChunk, &'static str> {
+    // The data is read as it arrives rather than allocated up front, so a broken length can't
+    // ask for gigabytes.
+    let mut chunk_data = vec![];
+    reader.take(chunk_length as u64).read_to_end(&mut chunk_data).map_err(|_| "Couldn't read PNG chunk!")?;
+
+    let mut chunk_crc = [0u8; 4];
+    if chunk_data.len() < chunk_length || reader.read_exact(&mut chunk_crc).is_err() {
+        return Err("PNG ended in the middle of a chunk!");
+    }
+
+    let png_chunk = PngChunk::new(chunk_length, ChunkType::type_from_bytes(chunk_type), chunk_data, chunk_crc);
+    check_crc(&png_chunk)?;
+
+    Ok(png_chunk)
+}
+
+/// Hands the data of consecutive IDAT chunks to an Inflater as it is needed, checking the CRC of
+/// each chunk once all of its data has been read. The image data ends at the first chunk that
+/// isn't an IDAT.
+struct IdatReader<R: Read> {
+    reader : R,
+
+    /// Bytes of the current IDAT chunk's data that haven't been read yet.
+    remaining : usize,
+    crc : Crc32,
+    done : bool,
+}
+
+impl<R: Read> IdatReader<R> {
+    /// Starts reading the data of an IDAT chunk whose header has just been read.
+    fn new(reader: R, chunk_length: usize) -> IdatReader<R> {
+        let mut idat_reader = IdatReader { reader, remaining: 0, crc: Crc32::new(), done: false };
+        idat_reader.start_chunk(chunk_length);
+        idat_reader
+    }
+
+    fn start_chunk(&mut self, chunk_length: usize) {
+        self.remaining = chunk_length;
+        self.crc = Crc32::new();
+        self.crc.update(b"IDAT");
+    }
+}
+
+impl<R: Read> ByteSource for IdatReader<R> {
+    fn read_more(&mut self, buffer: &mut Vec<u8>) -> Result<usize, &'static str> {
+        while !self.done {
+            if self.remaining > 0 {
+                let start = buffer.len();
+                buffer.resize(start + self.remaining.min(IDAT_READ_SIZE), 0);
 
-        let defiltered_scanlines : Vec<Vec<u8>> = defilter.defilter()?;
+                if self.reader.read_exact(&mut buffer[start..]).is_err() {
+                    buffer.truncate(start);
+                    return Err("PNG ended in the middle of a chunk!");
+                }
+
+                self.crc.update(&buffer[start..]);
+                self.remaining -= buffer.len() - start;
+                return Ok(buffer.len() - start);
+            }
+
+            let mut chunk_crc = [0u8; 4];
+            self.reader.read_exact(&mut chunk_crc).map_err(|_| "PNG ended in the middle of a chunk!")?;
+            if chunk_crc != self.crc.finalize() {
+                return Err("Invalid CRC!");
+            }
+
+            let (chunk_length, chunk_type) = read_chunk_header(&mut self.reader)?;
+            match ChunkType::type_from_bytes(chunk_type) {
+                ChunkType::IDAT => self.start_chunk(chunk_length),
+                _ => self.done = true,
+            };
+        }
+
+        Ok(0)
+    }
+}
 
-        println!("depth {} bpp {} color {} il {}", bit_depth, bit_depth / 8, color, il);
+/// Decodes a PNG from any reader a scanline at a time, without reading the whole file first.
+///
+/// The chunks before the image data are read by new(), and each call to next() inflates and
+/// defilters one more scanline, so only the current and previous scanlines are held. Adam7
+/// interlaced images need every pass before any row is complete, so they are decoded whole on
+/// the first call to next().
+pub struct PngReader<'a> {
+    /// The chunks before the first IDAT.
+    header : EncPng,
+    info : PngInfo,
+    inflater : Inflater<'a>,
+
+    /// The last scanline, defiltered - which the next scanline may be filtered against.
+    previous : Vec<u8>,
+    next_row : u32,
+
+    /// Rows of an interlaced image once it has been decoded.
+    deinterlaced : Option<std::vec::IntoIter<Vec<Pixel>>>,
+}
+
+impl<'a> PngReader<'a> {
+    /// Reads the PNG signature and every chunk up to the image data from reader.
+    pub fn new<R: Read + 'a>(reader: R) -> Result<Self, &'static str> {
+        let mut reader = reader;
+        let mut signature = [0u8; 8];
+
+        if reader.read_exact(&mut signature).is_err() || signature != PNG_HEADER {
+            return Err("PNG header is invalid!");
+        }
 
-        let scanlines = utils::defiltered_to_pixels(defiltered_scanlines, color as usize);
+        let mut header = EncPng::new();
+        let idat_length = loop {
+            let (chunk_length, chunk_type) = read_chunk_header(&mut reader)?;
 
-        let scanlines_decoded_plte = match scanlines[0][0].get_color_type() {
-            ColorType::PLTE => scanlines.decode_plte(plte_bytes), 
-            _ => scanlines,
+            match ChunkType::type_from_bytes(chunk_type) {
+                ChunkType::IDAT => break chunk_length,
+                ChunkType::IEND => return Err("PNG has no image data!"),
+                ChunkType::IHDR => (),
+                _ if header.chunks.is_empty() => return Err("First chunk of a PNG must be IHDR!"),
+                _ => (),
+            };
+
+            header.add_chunk(read_chunk_body(&mut reader, chunk_length, chunk_type)?);
         };
 
-        Ok(DecPng::from(scanlines_decoded_plte))
+        if header.chunks.is_empty() {
+            return Err("First chunk of a PNG must be IHDR!");
+        }
+
+        let info = PngInfo::from_chunks(&header)?;
+        let idat_reader = IdatReader::new(reader, idat_length);
+        let inflater = Inflater::new(Bits::from_source(Box::new(idat_reader), true), &[])?;
+
+        Ok(PngReader { header, info, inflater, previous: vec![], next_row: 0, deinterlaced: None })
+    }
+
+    pub fn print_chunks(&self) {
+        self.header.print_chunks();
+    }
+
+    pub fn get_width(&self) -> u32 {
+        self.info.width
+    }
+
+    pub fn get_height(&self) -> u32 {
+        self.info.height
+    }
+
+    /// Returns the color type of the pixels the reader gives, which have had PLTE and tRNS
+    /// applied.
+    pub fn get_pixel_color_type(&self) -> ColorType {
+        self.info.pixel_color_type()
+    }
+
+    /// Returns the color that transparent pixels should be blended with, as
+    /// DecPng::get_background() does.
+    pub fn get_background(&self) -> [u8; 3] {
+        self.info.background.unwrap_or([255, 255, 255])
+    }
+
+    pub fn set_background(&mut self, background: [u8; 3]) {
+        self.info.background = Some(background);
+    }
+
+    /// Writes the image as a plain PPM one row at a time, flattening transparent pixels onto the
+    /// background like WriteToPPM::write_to_p3().
+    pub fn write_to_p3(self, path: String) -> Result<(), &'static str> {
+        let file = fs::File::create(path).map_err(|_| "Unable to write file")?;
+        let mut writer = BufWriter::new(file);
+        let background = self.get_background();
+        let mut char_count = 0;
+
+        writer.write_all(p3_header(self.get_width() as usize, self.get_height() as usize).as_bytes()).map_err(|_| "Unable to write file")?;
+
+        for row in self {
+            let rgb_row : Vec<Pixel> = row?.iter().map(|x| x.flatten_alpha(background)).collect();
+            let mut row_string = String::new();
+            push_p3_row(&rgb_row, &mut char_count, &mut row_string);
+            writer.write_all(row_string.as_bytes()).map_err(|_| "Unable to write file")?;
+        }
+
+        writer.flush().map_err(|_| "Unable to write file")
+    }
+
+    /// Decodes the next scanline of a non-interlaced image.
+    fn read_row(&mut self) -> Result<Vec<Pixel>, &'static str> {
+        let mut filter = [0u8];
+        let mut row = vec![];
+        row.try_reserve_exact(self.info.row_bytes()).map_err(|_| "Not enough memory for a scanline of the image!")?;
+        row.resize(self.info.row_bytes(), 0);
+        if self.inflater.inflate(&mut filter)? < 1 || self.inflater.inflate(&mut row)? < row.len() {
+            return Err("Image data ends before the last scanline!");
+        }
+
+        // The scanline is defiltered against the one above it, which is empty for the first.
+        Defilter::defilter_row(filter[0], &mut row, &self.previous, self.info.bytes_per_pixel())?;
+        self.next_row += 1;
+
+        // The checksum is only checked once the stream is read to its end.
+        if self.next_row == self.info.height && self.inflater.inflate(&mut [0u8])? > 0 {
+            return Err("Image data is longer than the image!");
+        }
+
+        let pixels = utils::defiltered_to_pixels(std::slice::from_ref(&row), self.info.color as usize, self.info.bit_depth, self.info.width as usize)?;
+        self.previous = row;
+        self.info.decode_transparency(pixels)?.pop().ok_or("Scanline is too short for the width of the image!")
+    }
+
+    /// Decodes a whole interlaced image, returning its rows. Every pass is needed before any row
+    /// is complete, so unlike other images all of the decompressed image data is held at once.
+    fn read_interlaced(&mut self) -> Result<std::vec::IntoIter<Vec<Pixel>>, &'static str> {
+        let expected_length = self.info.adam7_bytes();
+        let mut decompressed_stream = vec![];
+        decompressed_stream.try_reserve_exact(expected_length).map_err(|_| "Not enough memory for the interlaced image!")?;
+        let mut buffer = vec![0u8; 65536];
+
+        loop {
+            let length = self.inflater.inflate(&mut buffer)?;
+            if length == 0 {
+                break;
+            }
+
+            if decompressed_stream.len() + length > expected_length {
+                return Err("Image data is longer than the image!");
+            }
+
+            decompressed_stream.extend_from_slice(&buffer[..length]);
+        }
+
+        let mut pixels = self.info.decode_transparency(self.info.adam7_to_pixels(decompressed_stream)?)?;
+        Ok(std::mem::take(&mut *pixels).into_iter())
+    }
+}
+
+/// Yields each row of the image as it is decoded. Once a row fails to decode no more are given.
+impl Iterator for PngReader<'_> {
+    type Item = Result<Vec<Pixel>, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.info.interlace == 1 && self.deinterlaced.is_none() {
+            match self.read_interlaced() {
+                Ok(rows) => self.deinterlaced = Some(rows),
+                Err(e) => {
+                    self.deinterlaced = Some(vec![].into_iter());
+                    return Some(Err(e));
+                },
+            };
+        }
+
+        if let Some(rows) = self.deinterlaced.as_mut() {
+            return rows.next().map(Ok);
+        }
+
+        if self.next_row >= self.info.height {
+            return None;
+        }
+
+        let row = self.read_row();
+        if row.is_err() {
+            self.next_row = self.info.height;
+        }
+
+        Some(row)
+    }
+}
+
+/// Collects every row of the reader.
+impl TryFrom<PngReader<'_>> for DecPng {
+    type Error = &'static str;
+
+    fn try_from(pngreader: PngReader) -> Result<Self, Self::Error> {
+        let background = pngreader.info.background;
+        let scanlines : Vec<Vec<Pixel>> = pngreader.collect::<Result<_, _>>()?;
+
+        Ok(DecPng { scanlines: scanlines.into(), background })
+    }
+}
+
+/// Writes the pixels with the color type and bit depth they were decoded with, so nothing is lost.
+impl TryFrom<DecPng> for EncPng {
+    type Error = &'static str;
+
+    fn try_from(decpng: DecPng) -> Result<Self, Self::Error> {
+        EncPng::from_dec_png(&decpng, FilterStrategy::default(), CompressionLevel::default())
+    }
+}
+
+impl TryFrom<DecJpeg> for EncPng {
+    type Error = &'static str;
+
+    fn try_from(decjpeg: DecJpeg) -> Result<Self, Self::Error> {
+        EncPng::from_dec_jpeg(&decjpeg, FilterStrategy::default(), CompressionLevel::default())
+    }
+}
+
+/// Returns the number of samples in each pixel of a PNG color type.
+fn channel_count(color: u32) -> Result<usize, &'static str> {
+    match color {
+        0 => Ok(1),
+        2 => Ok(3),
+        3 => Ok(1),
+        4 => Ok(2),
+        6 => Ok(4),
+        _ => Err("INVALID COLOR TYPE!"),
+    }
+}
+
+/// Returns the bit depths allowed for a PNG color type.
+fn valid_bit_depths(color: u32) -> Result<&'static [u32], &'static str> {
+    match color {
+        0 => Ok(&[1, 2, 4, 8, 16]),
+        3 => Ok(&[1, 2, 4, 8]),
+        2 | 4 | 6 => Ok(&[8, 16]),
+        _ => Err("INVALID COLOR TYPE!"),
     }
 }
 
 impl WriteToPPM for DecPng {
+    fn get_rgb_pixels(&self) -> Pixels {
+        self.scanlines.flatten_alpha(self.get_background())
+    }
+}
+
+pub trait WriteToPPM {
+    /// Returns the image as 8 bit RGB pixels.
+    fn get_rgb_pixels(&self) -> Pixels;
+
     fn write_to_p3(&self, path: String) {
-        let rgb_pixels : Pixels = self.scanlines.to_rgb();
-        let mut write_string = format!("P3\n{} {}\n{}\n", rgb_pixels.len(), rgb_pixels[0].len(), 255);
+        let rgb_pixels : Pixels = self.get_rgb_pixels();
+        let mut write_string = p3_header(rgb_pixels[0].len(), rgb_pixels.len());
         let mut char_count = 0;
 
         for row in rgb_pixels.iter() {
-            for pixel in row {
-                let triple_str : String = match pixel.get_color_values().as_slice() { 
-                    &[r, g ,b] => format!("{r} {g} {b}  "),
-                    _ => String::from(" "),
-                };
-
-                if char_count + 13 > 70 {
-                    write_string.push_str("\n");
-                    write_string.push_str(&triple_str);
-                    char_count = 0;
-                    continue;
-                }
-
-                write_string.push_str(&triple_str);
-                char_count += 13;
-            }
-            write_string.push_str("\n");
+            push_p3_row(row, &mut char_count, &mut write_string);
         }
         fs::write(&path, write_string).expect("Unable to write file");
     }
 }
 
-pub trait WriteToPPM {
-    fn write_to_p3(&self, path: String);
+fn p3_header(width: usize, height: usize) -> String {
+    format!("P3\n{} {}\n{}\n", width, height, 255)
 }
 
+/// Appends a row of 8 bit RGB pixels to a plain PPM. Lines are kept to about 70 characters,
+/// counting from char_count characters already on the line.
+fn push_p3_row(row: &[Pixel], char_count: &mut usize, write_string: &mut String) {
+    for pixel in row {
+        let triple_str : String = match pixel.get_color_values().as_slice() { 
+            &[r, g ,b] => format!("{r} {g} {b}  "),
+            _ => String::from(" "),
+        };
+
+        if *char_count + 13 > 70 {
+            write_string.push('\n');
+            write_string.push_str(&triple_str);
+            *char_count = 0;
+            continue;
+        }
+
+        write_string.push_str(&triple_str);
+        *char_count += 13;
+    }
+    write_string.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_test_image(path: &str) -> DecPng {
+        let png_bytes = fs::read(path).expect("Couldn't read test image");
+        let png : EncPng = png_bytes.try_into().unwrap();
+        png.decompress().unwrap()
+    }
+
+    fn color_values(pixels: &Pixels) -> Vec<Vec<u8>> {
+        pixels.iter().flatten().map(|x| x.get_color_values()).collect()
+    }
+
+    fn encode_and_decode(png: EncPng) -> DecPng {
+        let png : EncPng = png.to_bytes().unwrap().try_into().unwrap();
+        png.decompress().unwrap()
+    }
+
+    /// Decodes a test image with chunk in place of any chunk of the same type, placed right
+    /// before the image data.
+    fn decode_with_chunk(path: &str, chunk: PngChunk) -> Result<DecPng, &'static str> {
+        let mut png : EncPng = fs::read(path).expect("Couldn't read test image").try_into().unwrap();
+        png.chunks.retain(|x| x.get_type() != chunk.get_type());
+
+        let idat = png.chunks.iter().position(|x| *x.get_type() == ChunkType::IDAT).unwrap();
+        png.chunks.insert(idat, chunk);
+        png.decompress()
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        for path in ["src/testimg/adam7.png", "src/testimg/rgba16.png", "src/testimg/gs4_trns.png", "src/testimg/plte2_trns.png"] {
+            let original = decode_test_image(path);
+            let pixels = original.get_scanlines();
+            let decoded = encode_and_decode(EncPng::try_from(original).unwrap()).get_scanlines();
+
+            assert_eq!(color_values(&decoded), color_values(&pixels), "{path}");
+            assert!(decoded[0][0].get_color_type() == pixels[0][0].get_color_type());
+            assert_eq!(decoded[0][0].get_bit_depth(), pixels[0][0].get_bit_depth());
+        }
+    }
+
+    #[test]
+    fn encode_palette_and_sub_byte() {
+        // Four colors, two of them transparent, fit in a 2 bit palette with a 2 entry tRNS
+        let pixels = decode_test_image("src/testimg/plte2_trns.png").get_scanlines();
+        let png = EncPng::from_pixels(&pixels, 3, 2, FilterStrategy::MinSum, CompressionLevel::Lazy).unwrap();
+
+        assert_eq!(png.get_plte_bytes().unwrap(), vec![0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255]);
+        assert_eq!(png.get_trns_bytes(), Some(vec![0, 128]));
+        assert_eq!(color_values(&encode_and_decode(png).get_scanlines()), color_values(&pixels));
+        assert!(EncPng::from_pixels(&pixels, 3, 1, FilterStrategy::MinSum, CompressionLevel::Lazy).is_err());
+
+        // 1 bit grayscale written back out at 1 and 16 bits
+        let pixels = decode_test_image("src/testimg/test_2.png").get_scanlines();
+        for bit_depth in [1, 16] {
+            let decoded = encode_and_decode(EncPng::from_pixels(&pixels, 0, bit_depth, FilterStrategy::Fixed(4), CompressionLevel::Fixed).unwrap()).get_scanlines();
+            assert_eq!(color_values(&decoded), color_values(&pixels));
+        }
+
+        assert!(EncPng::from_pixels(&pixels, 2, 4, FilterStrategy::MinSum, CompressionLevel::Lazy).is_err());
+        assert!(EncPng::from_pixels(&Pixels::new(), 2, 8, FilterStrategy::MinSum, CompressionLevel::Stored).is_err());
+    }
+
+    #[test]
+    fn decode_adam7() {
+        // 13x11 RGB image where each pixel is generated from its coordinates.
+        let pixels = decode_test_image("src/testimg/adam7.png").get_scanlines();
+
+        assert_eq!(pixels.len(), 11);
+        assert_eq!(pixels[0].len(), 13);
+
+        for (y, row) in pixels.iter().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                let expected = vec![((x * 19 + y * 7) % 256) as u8, ((x * x + y * 3) % 256) as u8, ((x * y * 5) % 256) as u8];
+                assert_eq!(pixel.get_color_values(), expected, "pixel ({x}, {y})");
+            }
+        }
+    }
+
+    /// Decodes a test image after edit has changed its decompressed image data, both whole and
+    /// streamed, returning the errors of each.
+    fn decode_edited_image_data(path: &str, edit: impl FnOnce(&mut Vec<u8>)) -> (Option<&'static str>, Option<&'static str>) {
+        let mut png : EncPng = fs::read(path).unwrap().try_into().unwrap();
+        let mut decompressed = utils::decompress(png.get_deflate_stream()).unwrap();
+        edit(&mut decompressed);
+
+        let idat = PngChunk::from_data(ChunkType::IDAT, utils::compress(&decompressed, CompressionLevel::Fixed).unwrap()).unwrap();
+        let first_idat = png.chunks.iter().position(|x| *x.get_type() == ChunkType::IDAT).unwrap();
+        png.chunks.retain(|x| *x.get_type() != ChunkType::IDAT);
+        png.chunks.insert(first_idat, idat);
+
+        let bytes = png.to_bytes().unwrap();
+        let streamed = PngReader::new(&bytes[..]).and_then(|x| x.collect::<Result<Vec<_>, _>>());
+        (png.decompress().err(), streamed.err())
+    }
+
+    #[test]
+    fn reject_bad_adam7_filter_type() {
+        // The first pass of adam7.png is 2x2, so its second scanline starts at byte 7.
+        let errors = decode_edited_image_data("src/testimg/adam7.png", |x| x[7] = 5);
+        assert_eq!(errors, (Some("INVALID FILTER TYPE!"), Some("INVALID FILTER TYPE!")));
+    }
+
+    #[test]
+    fn reject_long_adam7_image_data() {
+        let errors = decode_edited_image_data("src/testimg/adam7.png", |x| x.extend_from_slice(&[0; 100]));
+        assert_eq!(errors.1, Some("Image data is longer than the image!"));
+    }
+
+    #[test]
+    fn decode_one_bit_grayscale() {
+        let pixels = decode_test_image("src/testimg/test_2.png").get_scanlines();
+        let values : Vec<Vec<u8>> = pixels.iter().map(|row| row.iter().map(|x| x.get_color_values()[0]).collect()).collect();
+
+        assert_eq!(values, vec![vec![0, 0], vec![255, 255]]);
+    }
+
+    #[test]
+    fn decode_four_bit_palette_adam7() {
+        // 11x5 image using a 16 entry palette where entry i is (16i, 255 - 16i, 40i)
+        let pixels = decode_test_image("src/testimg/plte4_adam7.png").get_scanlines();
+
+        for (y, row) in pixels.iter().enumerate() {
+            assert_eq!(row.len(), 11);
+
+            for (x, pixel) in row.iter().enumerate() {
+                let i = (x + y * 3) % 16;
+                assert_eq!(pixel.get_color_values(), vec![(i * 16) as u8, (255 - i * 16) as u8, ((i * 40) % 256) as u8]);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_sixteen_bit_rgba() {
+        // 7x5 RGBA image with 16 bit samples, which are rounded to the nearest 8 bit value.
+        let pixels = decode_test_image("src/testimg/rgba16.png").get_scanlines();
+        let to_eight_bit = |x: usize| ((x * 255 + 32767) / 65535) as u8;
+
+        assert_eq!(pixels.len(), 5);
+
+        for (y, row) in pixels.iter().enumerate() {
+            assert_eq!(row.len(), 7);
+
+            for (x, pixel) in row.iter().enumerate() {
+                let expected = vec![
+                    to_eight_bit((x * 9000 + y * 300) % 65536),
+                    to_eight_bit((x * y * 4111) % 65536),
+                    to_eight_bit(65535 - x * 257),
+                    to_eight_bit((y * 13000) % 65536),
+                ];
+                assert_eq!(pixel.get_color_values(), expected, "pixel ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn write_ppm_header() {
+        // 7x5, so a swapped width and height shows up in the header.
+        let path = std::env::temp_dir().join("pngpeg_write_ppm_header.ppm");
+        decode_test_image("src/testimg/rgba16.png").write_to_p3(path.to_str().unwrap().to_string());
+
+        let ppm = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(ppm.starts_with("P3\n7 5\n255\n"), "{}", &ppm[..12]);
+    }
 
+    #[test]
+    fn decode_palette_transparency() {
+        // 6x3 two bit palette image of black, red, green and blue where tRNS only covers the
+        // first two entries.
+        let pixels = decode_test_image("src/testimg/plte2_trns.png").get_scanlines();
+        let palette = [[0, 0, 0, 0], [255, 0, 0, 128], [0, 255, 0, 255], [0, 0, 255, 255]];
+
+        for (y, row) in pixels.iter().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                assert_eq!(pixel.get_color_values(), palette[(x + y) % 4].to_vec());
+            }
+        }
+    }
+
+    #[test]
+    fn check_trns_length() {
+        // plte2_trns.png has a palette of 4 colors
+        let full_trns = PngChunk::from_data(ChunkType::TRNS, vec![0, 128, 255, 64]).unwrap();
+        let pixels = decode_with_chunk("src/testimg/plte2_trns.png", full_trns).unwrap().get_scanlines();
+        assert_eq!(pixels[0][3].get_color_values(), vec![0, 0, 255, 64]);
+
+        let long_trns = PngChunk::from_data(ChunkType::TRNS, vec![0; 5]).unwrap();
+        assert_eq!(decode_with_chunk("src/testimg/plte2_trns.png", long_trns).err(), Some("tRNS CHUNK HAS MORE ENTRIES THAN THE PALETTE!"));
+
+        // RGBA images already have alpha, so a tRNS of any length changes nothing
+        let rgba_trns = PngChunk::from_data(ChunkType::TRNS, vec![0]).unwrap();
+        let pixels = decode_with_chunk("src/testimg/rgba_bkgd.png", rgba_trns).unwrap().get_scanlines();
+        assert_eq!(color_values(&pixels), color_values(&decode_test_image("src/testimg/rgba_bkgd.png").get_scanlines()));
+    }
+
+    #[test]
+    fn check_plte_length() {
+        // plte2_trns.png is a 2 bit image that uses palette entries 0 to 3
+        for length in [0, 7, 15] {
+            let plte = PngChunk::from_data(ChunkType::PLTE, vec![0; length]).unwrap();
+            assert_eq!(decode_with_chunk("src/testimg/plte2_trns.png", plte).err(), Some("INVALID PLTE CHUNK LENGTH!"), "{length}");
+        }
+
+        let short_plte = PngChunk::from_data(ChunkType::PLTE, vec![0; 9]).unwrap();
+        assert_eq!(decode_with_chunk("src/testimg/plte2_trns.png", short_plte).err(), Some("Palette index is past the end of the palette!"));
+    }
+
+    #[test]
+    fn decode_grayscale_color_key() {
+        // 5x4 four bit grayscale image where the sample 7 is transparent.
+        let pixels = decode_test_image("src/testimg/gs4_trns.png").get_scanlines();
+
+        for (y, row) in pixels.iter().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                let sample = (x * 3 + y) % 16;
+                let alpha = if sample == 7 { 0 } else { 255 };
+                assert_eq!(pixel.get_color_values(), vec![(sample * 17) as u8, alpha]);
+            }
+        }
+    }
+
+    #[test]
+    fn flatten_onto_bkgd_background() {
+        // 4x4 RGBA image of (200, 100, 50) whose alpha is 85 times the column, with a blue bKGD.
+        let png = decode_test_image("src/testimg/rgba_bkgd.png");
+        let pixels = png.get_scanlines().flatten_alpha(png.get_background());
+
+        assert_eq!(png.get_background(), [0, 0, 255]);
+
+        for row in pixels.iter() {
+            assert_eq!(row[0].get_color_values(), vec![0, 0, 255]);
+            assert_eq!(row[1].get_color_values(), vec![67, 33, 187]);
+            assert_eq!(row[3].get_color_values(), vec![200, 100, 50]);
+        }
+    }
+
+    #[test]
+    fn ignore_broken_bkgd() {
+        // An RGB bKGD needs 6 bytes, and a palette index has to be in the palette
+        let short_bkgd = PngChunk::from_data(ChunkType::BKGD, vec![0, 0]).unwrap();
+        let png = decode_with_chunk("src/testimg/rgba_bkgd.png", short_bkgd).unwrap();
+        assert_eq!(png.get_background(), [255, 255, 255]);
+
+        let missing_entry = PngChunk::from_data(ChunkType::BKGD, vec![200]).unwrap();
+        let png = decode_with_chunk("src/testimg/plte2_trns.png", missing_entry).unwrap();
+        assert_eq!(png.get_background(), [255, 255, 255]);
+    }
+
+    #[test]
+    fn stream_rows() {
+        for entry in fs::read_dir("src/testimg").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|x| x != "png") {
+                continue;
+            }
+
+            let path = path.to_str().unwrap();
+            let whole = decode_test_image(path);
+            let reader = PngReader::new(fs::File::open(path).unwrap()).unwrap();
+
+            assert_eq!(reader.get_background(), whole.get_background(), "{path}");
+            assert_eq!(reader.get_height() as usize, whole.get_scanlines().len(), "{path}");
+
+            let streamed = DecPng::try_from(reader).unwrap().get_scanlines();
+            assert_eq!(color_values(&streamed), color_values(&whole.get_scanlines()), "{path}");
+        }
+    }
+
+    #[test]
+    fn stream_broken_files() {
+        let png_bytes = fs::read("src/testimg/horse.png").unwrap();
+        // Readers that borrow their bytes work as well as ones that own them
+        let stream = |bytes: Vec<u8>| PngReader::new(&bytes[..]).and_then(|x| x.collect::<Result<Vec<_>, _>>());
+
+        assert_eq!(stream(png_bytes[1..].to_vec()).err(), Some("PNG header is invalid!"));
+        assert_eq!(stream(png_bytes[..png_bytes.len() / 2].to_vec()).err(), Some("PNG ended in the middle of a chunk!"));
+
+        // The CRC of the first IDAT is checked once its data has been read
+        let idat = png_bytes.windows(4).position(|x| x == b"IDAT").unwrap();
+        let idat_length = u32::from_be_bytes(png_bytes[idat - 4..idat].try_into().unwrap()) as usize;
+        let mut broken = png_bytes.clone();
+        broken[idat + 4 + idat_length] ^= 1;
+        assert_eq!(stream(broken).err(), Some("Invalid CRC!"));
+
+        // Image data that stops after the zlib header
+        let iend = png_bytes.len() - 12;
+        let mut short = png_bytes[..idat - 4].to_vec();
+        PngChunk::from_data(ChunkType::IDAT, vec![0x78, 0x9C]).unwrap().append_to(&mut short).unwrap();
+        short.extend_from_slice(&png_bytes[iend..]);
+        assert_eq!(stream(short).err(), Some("Deflate stream header broken - couldn't read block final value!"));
+    }
+
+    #[test]
+    fn reject_bogus_dimensions() {
+        let png_bytes = fs::read("src/testimg/horse.png").unwrap();
+        let with_size = |width: u32, height: u32| {
+            // Swap the IHDR for one with the given size, keeping depth, color and interlace
+            let mut ihdr = png_bytes[16..29].to_vec();
+            ihdr[..4].copy_from_slice(&width.to_be_bytes());
+            ihdr[4..8].copy_from_slice(&height.to_be_bytes());
+
+            let mut bytes = png_bytes[..8].to_vec();
+            PngChunk::from_data(ChunkType::IHDR, ihdr).unwrap().append_to(&mut bytes).unwrap();
+            bytes.extend_from_slice(&png_bytes[33..]);
+            PngReader::new(std::io::Cursor::new(bytes)).err()
+        };
+
+        assert_eq!(with_size(0, 10), Some("INVALID IMAGE DIMENSIONS!"));
+        assert_eq!(with_size(10, 0), Some("INVALID IMAGE DIMENSIONS!"));
+        assert_eq!(with_size(0x8000_0000, 10), Some("INVALID IMAGE DIMENSIONS!"));
+        assert_eq!(with_size(0x7FFF_FFFF, 10), Some("Image is too wide to decode!"));
+    }
+
+    // Timing rather than correctness - run with "cargo test --release -- --ignored --nocapture".
+    #[test]
+    #[ignore]
+    fn bench_inflate_horse() {
+        let png : EncPng = fs::read("src/testimg/horse.png").unwrap().try_into().unwrap();
+        let stream = png.get_deflate_stream();
+        let start = std::time::Instant::now();
+
+        for _ in 0..10 {
+            utils::decompress(stream.clone()).unwrap();
+        }
+
+        println!("Inflating horse.png took {:?}", start.elapsed() / 10);
+    }
+}
@@ -1,18 +1,21 @@
 //! Utility algorthims and functions used while encoding or decoding
 
-use std::{collections::HashMap, ops::{Mul, Add}};
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap}, ops::{Mul, Add}};
 use crate::pixel::{Pixel, Pixels, ColorType};
 
 /// Bits 
 ///
 /// This struct is used to create bitstreams from a vector of bytes.
-pub struct Bits {
+///
+/// Reading is buffered - up to eight bytes at a time are loaded into a 64 bit accumulator, and
+/// bits are peeked and consumed from there instead of from the bytes one at a time.
+pub struct Bits<'a> {
     
     /// The bytes this stream is reading from
     bytes: Vec<u8>,
 
     /// Inner value used to record the next bit that will be read out. Note this value starts at 0.
-    position: u32,
+    position: usize,
 
     /// Total of bits in the stream
     bit_count : usize,
@@ -24,14 +27,133 @@ pub struct Bits {
     /// If this byte is read LSB first, it would be read 00001111. If it were read MSB first, it
     /// would be read 11110000.
     lsb: bool,
+
+    /// Whether each 0xFF byte is followed by a stuffed 0x00 that isn't part of the data, as in
+    /// JPEG entropy coded data.
+    unstuff: bool,
+
+    /// Bits loaded from bytes but not read yet. The next bit is the lowest bit when reading LSB
+    /// first and the highest bit when reading MSB first.
+    accumulator: u64,
+
+    /// Number of bits loaded into the accumulator.
+    accumulator_bits: u32,
+
+    /// Index of the next byte to load into the accumulator.
+    next_byte: usize,
+
+    /// Where more bytes come from once bytes runs low, for streams that are read as they arrive.
+    source: Option<Box<dyn ByteSource + 'a>>,
+
+    /// The error the source gave, after which no more bytes are asked for.
+    source_error: Option<&'static str>,
+}
+
+/// A supply of bytes for a Bits stream that doesn't hold all of its bytes up front.
+pub trait ByteSource {
+    /// Appends the next bytes to buffer and returns how many there were - 0 once there are no
+    /// more.
+    fn read_more(&mut self, buffer: &mut Vec<u8>) -> Result<usize, &'static str>;
 }
 
-impl Bits {
+impl<'a> Bits<'a> {
     
     /// Static method to initialize a new bitstream from "bytes" and read least-significant-bit
     /// first if lsb is set to true. 
-    pub fn new(bytes: Vec<u8>, lsb: bool, bit_count: usize) -> Bits {
-        Bits { bytes, position: 0 , lsb, bit_count}
+    pub fn new(bytes: Vec<u8>, lsb: bool, bit_count: usize) -> Self {
+        Bits { bytes, position: 0, bit_count, lsb, unstuff: false, accumulator: 0, accumulator_bits: 0, next_byte: 0, source: None, source_error: None }
+    }
+
+    /// Initializes a bitstream that asks source for bytes as it needs them, so only a few are
+    /// held at a time. These streams can only be read from, not written to.
+    pub fn from_source(source: Box<dyn ByteSource + 'a>, lsb: bool) -> Self {
+        Bits { source: Some(source), ..Bits::new(vec![], lsb, 0) }
+    }
+
+    /// Returns the error the source gave, if any. Reads past that point fail like reads past the
+    /// end of the stream.
+    pub fn get_source_error(&self) -> Option<&'static str> {
+        self.source_error
+    }
+
+    /// Asks the source for more bytes until at least eight are waiting to be loaded. Bytes
+    /// already loaded into the accumulator are dropped first.
+    fn read_from_source(&mut self) {
+        let Some(source) = self.source.as_mut() else {
+            return;
+        };
+
+        if self.source_error.is_some() || self.bytes.len() - self.next_byte >= 8 {
+            return;
+        }
+
+        self.bytes.drain(..self.next_byte);
+        self.next_byte = 0;
+
+        while self.bytes.len() < 8 {
+            match source.read_more(&mut self.bytes) {
+                Ok(0) => break,
+                Ok(count) => self.bit_count += count * 8,
+                Err(e) => {
+                    self.source_error = Some(e);
+                    break;
+                },
+            };
+        }
+    }
+
+    /// Initializes a bitstream reading JPEG entropy coded data MSB first, skipping the 0x00
+    /// stuffed after every 0xFF.
+    pub fn new_unstuffed(bytes: Vec<u8>) -> Self {
+        let stuffed_count = bytes.windows(2).filter(|x| x == &[0xFF, 0x00]).count();
+        let bit_count = (bytes.len() - stuffed_count) * 8;
+
+        Bits { unstuff: true, ..Bits::new(bytes, false, bit_count) }
+    }
+
+    /// Loads whole bytes into the accumulator until it holds more than 56 bits or the bytes run
+    /// out.
+    fn refill(&mut self) {
+        self.read_from_source();
+
+        // Without stuffing to look out for, the next eight bytes are read as one word and as many
+        // whole bytes of it kept as fit.
+        if !self.unstuff && self.next_byte + 8 <= self.bytes.len() {
+            let mut word = [0u8; 8];
+            word.copy_from_slice(&self.bytes[self.next_byte..self.next_byte + 8]);
+            let byte_count = (63 - self.accumulator_bits) / 8;
+
+            match self.lsb {
+                true => {
+                    let kept = u64::from_le_bytes(word) & ((1 << (8 * byte_count)) - 1);
+                    self.accumulator |= kept << self.accumulator_bits;
+                },
+                false => {
+                    let kept = u64::from_be_bytes(word) & !(u64::MAX >> (8 * byte_count));
+                    self.accumulator |= kept >> self.accumulator_bits;
+                },
+            };
+
+            self.accumulator_bits += 8 * byte_count;
+            self.next_byte += byte_count as usize;
+            return;
+        }
+
+        while self.accumulator_bits <= 56 && self.next_byte < self.bytes.len() {
+            let byte = self.bytes[self.next_byte];
+            self.next_byte += 1;
+
+            if self.unstuff && byte == 0xFF && self.bytes.get(self.next_byte) == Some(&0x00) {
+                self.next_byte += 1;
+            }
+
+            match self.lsb {
+                true => self.accumulator |= (byte as u64) << self.accumulator_bits,
+                false => self.accumulator |= (byte as u64) << (56 - self.accumulator_bits),
+            };
+
+            self.accumulator_bits += 8;
+        }
     }
     
     /// Method to read the next num bits in the stream - returns None if num is greater than the
@@ -48,30 +170,76 @@ impl Bits {
             return None;
         }
 
+        // Bits from a source are only counted once they have been asked for.
+        if self.accumulator_bits < num {
+            self.refill();
+        }
+
         if self.len() < num {
             return None;
         }
 
-        let mut value = 0u32;
-        for i in self.position..(self.position + num) {
-            let byte_index = (i / 8) as usize;
-            
-            // When read LSB first, the rightmost bit is the first (highest value) bit - so the
-            // byte is essentially read in reverse.
-            let byte = match self.lsb {
-                true => self.bytes[byte_index].reverse_bits(),
-                false => self.bytes[byte_index],
-            };
+        let value = self.peek_bits(num);
+        self.consume_bits(num);
+        Some(value)
+    }
 
-            // The nth bit is the (n % 8)th bit of the (n div 8)th byte. To read it, we shift it to
-            // the rightmost bit position and bitwise AND it with 00000....1. 
-            let shift = 7 - (i % 8);
-            let bit = (byte >> shift) as u32 & 1;
-            value = (value << 1) + bit;
+    /// Returns the next num bits like read_bits() without moving past them. Bits past the end of
+    /// the stream are read as 0s, so a whole huffman lookup can be peeked near the end.
+    pub fn peek_bits(&mut self, num: u32) -> u32 {
+        if num == 0 {
+            return 0;
         }
 
-        self.position += num;
-        Some(value)
+        if self.accumulator_bits < num {
+            self.refill();
+        }
+
+        // When read LSB first, the first bit read is the lowest bit of the accumulator, so the
+        // bits are reversed to put it on the left.
+        let value = match self.lsb {
+            true => (self.accumulator as u32).reverse_bits() >> (32 - num),
+            false => (self.accumulator >> (64 - num)) as u32,
+        };
+
+        // The last byte may hold bits past the end of the stream, which are cleared.
+        let available = num.min(self.len());
+        let mask = ((1u64 << available) - 1) << (num - available);
+        value & mask as u32
+    }
+
+    /// Returns the next num bits in the order they are held in the stream, so the first bit read
+    /// is the lowest bit when reading LSB first. Unlike peek_bits() the bits past the end of the
+    /// stream aren't cleared. num has to be between 1 and 32.
+    pub fn peek_raw_bits(&mut self, num: u32) -> u32 {
+        if self.accumulator_bits < num {
+            self.refill();
+        }
+
+        match self.lsb {
+            true => (self.accumulator & ((1u64 << num) - 1)) as u32,
+            false => (self.accumulator >> (64 - num)) as u32,
+        }
+    }
+
+    pub fn is_lsb(&self) -> bool {
+        self.lsb
+    }
+
+    /// Moves past the next num bits, which should already have been peeked.
+    pub fn consume_bits(&mut self, num: u32) {
+        if self.accumulator_bits < num {
+            self.refill();
+        }
+
+        let num = num.min(self.accumulator_bits);
+        match self.lsb {
+            true => self.accumulator = self.accumulator.checked_shr(num).unwrap_or(0),
+            false => self.accumulator = self.accumulator.checked_shl(num).unwrap_or(0),
+        };
+
+        self.accumulator_bits -= num;
+        self.position += num as usize;
     }
 
     /// This method reads n bits just like read_bits() but reverses the output u32 after being
@@ -82,581 +250,1544 @@ impl Bits {
     ///
     /// Example, reading 3 bits MSB first from 01101111 MSB first would produce the u32 - 0b110
     pub fn read_bits_reversed(&mut self, num: u32) -> Option<u32> {
-        match self.read_bits(num) {
-            Some(value) => Some(value.reverse_bits() >> (32 - num)),
-            None => None,
+        // Read LSB first, the bits already sit in the accumulator in reversed order.
+        if self.lsb && num <= 32 {
+            if self.accumulator_bits < num {
+                self.refill();
+            }
+
+            if self.len() < num {
+                return None;
+            }
+
+            let value = (self.accumulator & ((1u64 << num) - 1)) as u32;
+            self.consume_bits(num);
+            return Some(value);
         }
+
+        self.read_bits(num).map(|value| value.reverse_bits().checked_shr(32 - num).unwrap_or(0))
     }
 
-    /// This method writes <num : usize> bits LSB first from <input : u32> into the stream
-    pub fn write_bits(&mut self, input: u32, num : usize) {
+    /// This method writes the lowest num bits of input into the stream so that reading num bits
+    /// back out with read_bits() produces input - the leftmost of the num bits is written first.
+    ///
+    /// Bytes are added to the end of the stream as they are needed.
+    pub fn write_bits(&mut self, input: u32, num: u32) {
         if num > 32 {
             panic!("Cannot write more than 32 bits from a u32");
         }
-        
-        // Check if num bits can be fit into bytes that are present
-        let unwritten_bits = 8 * self.bytes.len() - self.bit_count; 
 
-        let extra_bytes = match num <= unwritten_bits {
-            true => 0,
-            false => ((num - unwritten_bits) / 8) + 1
-        };
-        
-        // Append bytes to ensure write is possible
-        self.bytes.append(&mut vec![0; extra_bytes]);
-        
-        // Write
-        for i in 0..num {
-            let bit_to_add = (input >> i) & 1;
-            println!("{bit_to_add}");
-            let last_pos = self.bit_count + 1;
-            
-            let byte = self.bytes[last_pos / 8]; 
-            
+        if self.source.is_some() {
+            panic!("Cannot write to a stream reading from a source");
+        }
+
+        for i in (0..num).rev() {
+            let bit_to_add = ((input >> i) & 1) as u8;
+            let byte_index = self.bit_count / 8;
+
+            if byte_index == self.bytes.len() {
+                self.bytes.push(0);
+            }
+
+            // The position of the bit within its byte depends on the order the stream is read in.
             let shift = match self.lsb {
-                true => i % 8,
-                false => 7 - i % 8,
+                true => self.bit_count % 8,
+                false => 7 - self.bit_count % 8,
             };
-            
-            //let bit = (byte >> shift) as u32 & 1;
-            self.bytes[last_pos / 8] =  byte | (bit_to_add as u8) << shift;
-            self.bit_count += 1; 
+
+            self.bytes[byte_index] |= bit_to_add << shift;
+            self.bit_count += 1;
         }
+
+        // Bytes already loaded for reading may have just changed.
+        if self.next_byte > 0 {
+            self.reload();
+        }
+    }
+
+    /// Empties the accumulator and loads it again from position. Only streams without byte
+    /// stuffing can be reloaded, since position is counted in unstuffed bits.
+    fn reload(&mut self) {
+        self.accumulator = 0;
+        self.accumulator_bits = 0;
+        self.next_byte = self.position / 8;
+
+        let skipped = (self.position % 8) as u32;
+        if skipped > 0 {
+            self.refill();
+            self.position -= skipped as usize;
+            self.consume_bits(skipped);
+        }
+    }
+
+    /// Skips the rest of the current byte, so the next bit read is the first bit of a byte.
+    pub fn align_to_byte(&mut self) {
+        let skipped = (8 - self.position % 8) % 8;
+        self.consume_bits(skipped as u32);
+    }
+
+    /// This method writes num bits just like write_bits() but with the lowest bit of input written
+    /// first, so that read_bits_reversed() reads input back out.
+    pub fn write_bits_reversed(&mut self, input: u32, num: u32) {
+        if num > 0 {
+            self.write_bits(input.reverse_bits() >> (32 - num), num);
+        }
+    }
+
+    /// Removes and returns every byte that has been completely written, keeping a partly
+    /// written last byte in the stream. This lets a stream that is only written to be flushed as
+    /// it goes.
+    pub fn take_written_bytes(&mut self) -> Vec<u8> {
+        let whole_bytes = self.bit_count / 8;
+        let partial = self.bytes.split_off(whole_bytes);
+        self.bit_count -= whole_bytes * 8;
+
+        std::mem::replace(&mut self.bytes, partial)
+    }
+
+    /// Returns the total number of bits in the stream, whether they have been read or not.
+    pub fn bit_count(&self) -> usize {
+        self.bit_count
+    }
+
+    /// Consumes the stream and returns the bytes it was reading from or writing to.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
     }
 
     /// Method used to debug functionality - simply prints the entire byte which the next bit will
     /// be read from.
+    #[allow(dead_code)]
     pub fn print_current_byte(&mut self) {
-        let byte_index = (self.position / 8) as usize;
-        println!("pos {} current byte {byte_index} in stream {:#010b}", self.position, self.bytes[byte_index]);
+        let byte_index = self.position / 8;
+        if let Some(byte) = self.bytes.get(byte_index) {
+            println!("pos {} current byte {byte_index} in stream {:#010b}", self.position, byte);
+        }
     }
     
     /// Returns the remaining bits in the stream.
     pub fn len(&self) -> u32 {
         // Subtract read bits from total bits.
-        match (self.bit_count as u32) < self.position  {
-            true => 0,
-            false => self.bit_count as u32 - self.position,
+        self.bit_count.saturating_sub(self.position).min(u32::MAX as usize) as u32
+    }
+}
+
+/// Lookup tables for CRC-32 with the reflected polynomial 0xEDB88320. CRC_TABLES[0][n] is the CRC
+/// register after shifting the byte n through it, and CRC_TABLES[k][n] is the same byte followed
+/// by k zero bytes - so eight bytes can be looked up at once.
+const CRC_TABLES : [[u32; 256]; 8] = generate_crc_tables();
+
+const fn generate_crc_tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0u32; 256]; 8];
+
+    let mut n = 0;
+    while n < 256 {
+        let mut register = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            register = match register & 1 {
+                1 => (register >> 1) ^ 0xEDB88320,
+                _ => register >> 1,
+            };
+            bit += 1;
+        }
+
+        tables[0][n] = register;
+        n += 1;
+    }
+
+    let mut k = 1;
+    while k < 8 {
+        let mut n = 0;
+        while n < 256 {
+            let previous = tables[k - 1][n];
+            tables[k][n] = (previous >> 8) ^ tables[0][(previous & 0xFF) as usize];
+            n += 1;
         }
+        k += 1;
     }
+
+    tables
 }
 
 /// Implementation of the CRC-32 algorithm used in PNG files. The specification is  
 /// POLY: 0x04C11DB7, XOROUT: 0xFFFFFFFF, INIT: 0xFFFFFFFF, REFIN: true, REFOUT: true. 
-pub fn png_crc(bytes: Vec<u8>) -> Result<[u8; 4], &'static str> {
-    const POLY: u32 = 0x04C11DB7;
-    const XOROUT: u32 = 0xFFFFFFFF;
-    const INIT: u32 = 0xFFFFFFFF;
-    const REFIN: bool = true;
-    const REFOUT: bool = true;
-    
-    // A 32 bit CRC is padded with 32 bits on the right.
-    let mut padding = vec![0u8; 4];
-    let mut padded_bytes = bytes;
-    padded_bytes.append(&mut padding);
-    let mut padded_bits = Bits::new(padded_bytes.clone(), REFIN, padded_bytes.len() * 8);
-    
-    // Read 32 bits into the register to begin, because our polynomial has 32 explicit bits
-    let mut register = match padded_bits.read_bits(32) {
-        Some(x) => x,
-        None => return Err("Error while reading initial 32 bits into register. It's likely that the padding failed."),
-    };
+///
+/// Bytes can be added a piece at a time with update(), so a chunk can be checksummed as it is
+/// read or written.
+pub struct Crc32 {
+    register : u32,
+}
 
-    //INIT value - see https://stackoverflow.com/questions/43823923/implementation-of-crc-8-what-does-the-init-parameter-do:
-    register ^= INIT;
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 { register: 0xFFFFFFFF }
+    }
 
-    while let Some(next_bit) = padded_bits.read_bits(1) {
-        // Pop one bit from the left, if it is a one XOR the register with poly. 
-        let popped_bit = register >> 31;
-        register = (register << 1) + next_bit;
+    /// Adds bytes to the checksum, eight at a time where possible (slice-by-8).
+    pub fn update(&mut self, bytes: &[u8]) {
+        let mut register = self.register;
+        let mut words = bytes.chunks_exact(8);
+
+        for word in words.by_ref() {
+            let low = register ^ u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            let high = u32::from_le_bytes([word[4], word[5], word[6], word[7]]);
+
+            register = CRC_TABLES[7][(low & 0xFF) as usize]
+                ^ CRC_TABLES[6][((low >> 8) & 0xFF) as usize]
+                ^ CRC_TABLES[5][((low >> 16) & 0xFF) as usize]
+                ^ CRC_TABLES[4][(low >> 24) as usize]
+                ^ CRC_TABLES[3][(high & 0xFF) as usize]
+                ^ CRC_TABLES[2][((high >> 8) & 0xFF) as usize]
+                ^ CRC_TABLES[1][((high >> 16) & 0xFF) as usize]
+                ^ CRC_TABLES[0][(high >> 24) as usize];
+        }
 
-        match popped_bit {
-            0 => (),
-            _ => register ^= POLY,
-        };
-    }
+        for &byte in words.remainder() {
+            register = (register >> 8) ^ CRC_TABLES[0][((register ^ byte as u32) & 0xFF) as usize];
+        }
 
-    if REFOUT {
-        register = register.reverse_bits();
+        self.register = register;
     }
 
-    register ^= XOROUT;
+    /// Returns the checksum of every byte added so far, in the big-endian order PNG stores it.
+    pub fn finalize(&self) -> [u8; 4] {
+        (self.register ^ 0xFFFFFFFF).to_be_bytes()
+    }
+}
 
-    Ok(register.to_be_bytes())
+/// Returns the PNG CRC-32 of bytes all at once. Crc32 does the same for bytes that arrive a piece
+/// at a time.
+#[allow(dead_code)]
+pub fn png_crc(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    u32::from_be_bytes(crc.finalize())
 }
 
-/// Takes in a compressed stream of bytes and returns a decompressed stream of bytes.
-///
-/// This method will error if the input stream does not meet the specification outlined in RFC
-/// 1950 and 1951. Note that this method requires the input stream to be encoded in ZLIB
-/// format.
-pub fn decompress(deflate_stream: Vec<u8>) -> Result<Vec<u8>, &'static str> {
-    let first_byte = match deflate_stream.get(0) {
-        Some(x) => x,
-        None => return Err("Deflate stream was empty!"),
-    };
-    
-    let cmf = first_byte & 0x0fu8;
-    let window_size = 2_u32.pow(((first_byte >> 4) + 8) as u32); 
-    
-    println!("Compression method (cmf): {cmf}");
-    println!("Compression window: {window_size}");
+/// Adler-32 checksum, which ends every ZLIB stream. Bytes can be added a piece at a time with
+/// update().
+pub struct Adler32 {
+    a : u32,
+    b : u32,
+}
 
-    let (comp, crc) = deflate_stream.split_at(deflate_stream.len() - 4);
-    
-    println!("CRC of uncompressed bytes {}", crc.iter().fold("".to_string(), |st, x| st + &format!("{} ", x) ));
-    let flag_byte = comp.get(1).expect("Deflate stream does not contain flags byte!");
+impl Adler32 {
+    pub fn new() -> Adler32 {
+        Adler32 { a: 1, b: 0 }
+    }
 
-    //TODO: FDICT FUNCTIONALITY
-    let _fdict = 0b1 & (flag_byte >> 5);
-    
-    // comp is the compressed bitstream and out is the vector in which decompressed bytes will
-    // be stored.
-    let mut comp = Bits::new(comp[2..].to_vec(), true, comp[2..].to_vec().len() * 8);
-    let mut out : Vec<u8> = vec![]; 
-    loop {    
-        // DEFLATE streams consist of multiple blocks of compressed bits each using one of
-        // three outlined compression methods. For each block, three bits are read which indicate 
-        // the compression type and whether the block is the final one in the stream. Then the
-        // block is decompressed using one of three methods and output into out
-        let bfinal = comp.read_bits(1).expect("Deflate stream header broken - couldn't read block final value!");
-        let btype = comp.read_bits_reversed(2).expect("Deflate stream header broken - couldn't read block type!");
-        
-        println!("bfinal {bfinal} btype {btype}");
+    pub fn update(&mut self, bytes: &[u8]) {
+        const MOD_ADLER: u32 = 65521;
 
-        out = match btype {
-            0b0 => decode_block_none(&mut comp, out)?,
-            0b01 => decode_block_fixed(&mut comp, out)?,
-            0b10 => decode_block_dynamic(&mut comp, out)?,
-            _ => return Err("Deflate stream is broken - read reserved btype!"),
-        };
+        // 5552 is the most bytes that can be added before b might overflow a u32, so the modulo is
+        // only taken once per chunk.
+        for chunk in bytes.chunks(5552) {
+            for &byte in chunk {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
 
-        if bfinal == 1 { 
-            break;
+            self.a %= MOD_ADLER;
+            self.b %= MOD_ADLER;
         }
     }
-       
-    Ok(out)
-}
-
-fn decode_block_dynamic(comp: &mut Bits, out : Vec<u8>)  -> Result<Vec<u8>, &'static str> {
-    //println!("Attempting to decode type 10 block!");
-    let mut out = out;
-    let literal_length_code_count = 257 + comp.read_bits_reversed(5).expect("Deflate stream is broken - couldn't read HLIT from stream!");
-    let distance_code_count = 1 + comp.read_bits_reversed(5).expect("Deflate stream is broken - couldn't read HDIST from stream!");
-    let code_length_code_length_count = 4 + comp.read_bits_reversed(4).expect("Deflate stream is broken couldn't read HCLEN from stream!");
 
-    //println!("There are {} encoded literals/lengths, {} encoded distances, and {} encoded CL codes", literal_length_code_count , distance_code_count , code_length_code_length_count );
-
-    let code_length_huff : Huffman = generate_code_length_huff(comp, code_length_code_length_count);
-    let ll_huff : Huffman = generate_dyn_huff(comp, code_length_huff.clone(), literal_length_code_count);
-    let dist_huff : Huffman = generate_dyn_huff(comp, code_length_huff, distance_code_count);
-
-    let length_table : HashMap<u32, (u32,u32)> = generate_length_table();
-    let dist_table : HashMap<u32, (u32, u32)> = generate_dist_table();
-    
-    loop {
-        let code : u32 = ll_huff.read_one_code(comp).expect("Couldn't read next length or literal code!");
-        //println!("Found literal or length code {}", code);
+    pub fn finalize(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
 
-        match code {
-            x if x < 256 => {
-                out.push(x as u8);
-                continue;
-                },
-            256 => break,
-            _ => (),
-        };
-        
-        //If the loop is still ongoing - decoded is a length - read distance and the read those
-        //literals into out
-
-        let (extra_length_bits, length) = *length_table.get(&code).expect("Invalid fixed length huffman code was read for length!");
-
-        //println!("Decoded length code {} ", length);
-        //Extra length bits are read MSB first instead of the usual LSB that all the other bytes
-        //are read...
-        let mut length_extra = 0u32;
-        if extra_length_bits != 0 {
-            length_extra = comp.read_bits_reversed(extra_length_bits).expect("Deflate stream is broken - couldn't read extra bits");
-        }
-        let length = length + length_extra;
-        //println!("Added extra bits to length {}", length); 
-        //Distance code is huffman coded.
-        //let dist_code = comp.read_bits().expect("Deflate stream is broken - couldn't read initial distance bits!"); 
-        let decoded_dist : u32 = dist_huff.read_one_code(comp).expect("Couldn't read next distance code");
-        
-        //println!("Read distance code from stream {}", decoded_dist);
+/// Adler-32 checksum of bytes in one go - see Adler32.
+pub fn adler32(bytes: &[u8]) -> u32 {
+    let mut checksum = Adler32::new();
+    checksum.update(bytes);
+    checksum.finalize()
+}
 
-        //Now that we have the distance code, use the hash table to read code and extra bits to
-        //find real distance value.
-    
-        let (extra_dist_bits, dist) = *dist_table.get(&decoded_dist).expect(&format!("Invalid dynamic huffman code was read for distance ({})", decoded_dist));
+/// How hard compress() works to shrink its input.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum CompressionLevel {
+    /// Stored blocks only - the bytes are copied into the stream as they are.
+    Stored,
+    /// Greedy LZ77 matching coded with the fixed huffman codes.
+    Fixed,
+    /// Greedy LZ77 matching coded with huffman codes built for each block.
+    Dynamic,
+    /// Lazy LZ77 matching over longer hash chains, coded like Dynamic.
+    #[default]
+    Lazy,
+}
 
-        let mut dist_extra = 0u32;
+impl TryFrom<&str> for CompressionLevel {
+    type Error = &'static str;
 
-        if extra_dist_bits != 0 {
-            dist_extra = comp.read_bits_reversed(extra_dist_bits).expect("Deflate stream is broken - couldn't read extra distance bits!");
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "stored" => Ok(CompressionLevel::Stored),
+            "fixed" => Ok(CompressionLevel::Fixed),
+            "dynamic" => Ok(CompressionLevel::Dynamic),
+            "lazy" => Ok(CompressionLevel::Lazy),
+            _ => Err("Compression level must be one of stored, fixed, dynamic or lazy!"),
         }
-        //println!("Found distance from table {}", dist);
-        let dist = dist + dist_extra;
-        //println!("Added extra bits to distance {}", dist);
-        
-        //println!("Pushing {} literals starting {} backwards onto output buffer of length {}", length, dist, out.len());
-        //Push <length> literals starting from <dist> bytes before.
-        out = zlss(out, length, dist);
-        //println!("Output buffer size {}", out.len());
     }
-    Ok(out)
 }
 
-fn generate_code_length_huff(comp: &mut Bits, code_count: u32) -> Huffman {
-    let order : Vec<u32> = vec![16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
-    let mut lengths : Vec<u32> = vec![];
-    
-    for _i in 0..code_count {
-        //Read one 3 bit CL code length - which is reversed because it is an integer - from the stream
-        let code_length = comp.read_bits_reversed(3).expect("Deflate stream is broken - couldn't read code length code lengths!");
-        lengths.push(code_length);
-    }
+/// Order the code length code lengths are written in the header of a dynamic block.
+const CODE_LENGTH_ORDER : [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Farthest back a match can start, which is also the size of the ZLIB window.
+const WINDOW_SIZE : usize = 32768;
+const MIN_MATCH : usize = 3;
+const MAX_MATCH : usize = 258;
+const HASH_BITS : usize = 15;
+
+/// Most LZ77 symbols put in one compressed block before starting the next, so the huffman codes
+/// can follow changes in the data.
+const MAX_BLOCK_SYMBOLS : usize = 16384;
+
+/// Takes in a stream of bytes and returns it as a ZLIB stream (RFC 1950) compressed at the given
+/// level.
+pub fn compress(bytes: &[u8], level: CompressionLevel) -> Result<Vec<u8>, &'static str> {
+    // CMF - deflate with a 32K window, FLG - no dictionary, the level and a check value that
+    // makes the header a multiple of 31.
+    let flags = match level {
+        CompressionLevel::Stored => 0x01,
+        CompressionLevel::Fixed => 0x5E,
+        CompressionLevel::Dynamic => 0x9C,
+        CompressionLevel::Lazy => 0xDA,
+    };
+    let mut out = vec![0x78, flags];
+    let mut stream = Bits::new(vec![], true, 0);
+
+    match level {
+        CompressionLevel::Stored => write_block_stored(&mut stream, bytes, true),
+        _ => {
+            let symbols = lz77(bytes, level);
+            let length_codes = invert_deflate_table(&LENGTH_CODES, 257, MAX_MATCH as u32);
+            let dist_codes = invert_deflate_table(&DIST_CODES, 0, WINDOW_SIZE as u32);
+
+            // An empty stream still needs one final block.
+            let blocks : Vec<&[Lz77Symbol]> = match symbols.is_empty() {
+                true => vec![&[]],
+                false => symbols.chunks(MAX_BLOCK_SYMBOLS).collect(),
+            };
 
-    let mut lengths_with_symbols : Vec<Vec<u32>> = vec![vec![]; 8];
-    
-    //Read input bits and find code length for each symbol in order
-    for i in 0..code_count {
-        let code_len = lengths[i as usize];
-        lengths_with_symbols[code_len as usize].push(order[i as usize]);
-    }    
-   
-    let code_length_huff : Huffman = match Huffman::generate_from_length_symbols(lengths_with_symbols) {
-        Ok(x) => x,
-        Err(e) => panic!("{}", e),
+            let mut block_start = 0;
+            for (index, block) in blocks.iter().enumerate() {
+                let block_length : usize = block.iter().map(|x| x.length()).sum();
+                let block_bytes = &bytes[block_start..block_start + block_length];
+                let is_final = index == blocks.len() - 1;
+
+                write_block_compressed(&mut stream, block, block_bytes, is_final, level, &length_codes, &dist_codes)?;
+                block_start += block_length;
+            }
+        },
     };
 
-    code_length_huff
+    out.append(&mut stream.into_bytes());
+    out.extend_from_slice(&adler32(bytes).to_be_bytes());
+    Ok(out)
 }
 
-fn decode_code_length_code(code: u32, last_pushed : u32, stream: &mut Bits) -> Vec<u32> {
-    let mut codes : Vec<u32> = vec![];
+/// One LZ77 symbol - either a literal byte or a copy of length bytes from distance bytes back.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Lz77Symbol {
+    Literal(u8),
+    Match(usize, usize),
+}
 
-    match code {
-        0..=15 => {
-            codes.push(code);
-            println!("Pushed {code} x1");
-        },
-        16 => {
-            let push_count = 3 + stream.read_bits_reversed(2).expect("err!");
-            for _i in 0..push_count {
-                codes.push(last_pushed);
-            }
-            println!("Pushed {last_pushed} x{push_count}");
-        },
-        17 => {
-            let zero_count = 3 + stream.read_bits_reversed(3).expect("err!");
-            for _i in 0..zero_count {
-                codes.push(0);
-            }
-            println!("Pushed 0 x{zero_count} ");
-        },
-        18 =>{
-            let zero_count = 11 + stream.read_bits_reversed(7).expect("err!");               
-            for _i in 0..zero_count {
-                codes.push(0);
-            }
-            println!("Pushed 0 x{zero_count} ");
+impl Lz77Symbol {
+    /// Number of bytes of the input this symbol stands for.
+    fn length(&self) -> usize {
+        match *self {
+            Lz77Symbol::Literal(_) => 1,
+            Lz77Symbol::Match(length, _) => length,
         }
-        _ => (),
     }
-    codes
 }
 
-fn generate_dyn_huff(comp: &mut Bits, cl_huff: Huffman, symbol_count: u32) -> Huffman {
-    let mut lengths_with_symbols : Vec<Vec<u32>> = vec![vec![]; 16];
-    let comp = comp;
-    let mut code_lengths_pushed = 0u32;
-    let mut last_pushed_length = 0u32;
+/// Finds earlier matches for a position using hash chains. head holds the latest position for
+/// each hash of three bytes and prev links each position in the window to the one before it with
+/// the same hash.
+struct MatchFinder {
+    head : Vec<usize>,
+    prev : Vec<usize>,
+    max_chain : usize,
+}
 
-    loop {
-        if code_lengths_pushed == symbol_count {
-            break;
-        }
+impl MatchFinder {
+    fn new(max_chain: usize) -> MatchFinder {
+        MatchFinder { head: vec![usize::MAX; 1 << HASH_BITS], prev: vec![usize::MAX; WINDOW_SIZE], max_chain }
+    }
 
-        let decoded : u32 = cl_huff.read_one_code(comp).expect("Couldn't decode LL huff code length using CL huff");
-        
-        let code_lengths = decode_code_length_code(decoded, last_pushed_length, comp);
-        
-        for code_length in code_lengths {
-            lengths_with_symbols[code_length as usize].push(code_lengths_pushed);
-            code_lengths_pushed += 1;
-            last_pushed_length = code_length;
+    fn hash(bytes: &[u8], position: usize) -> usize {
+        let value = ((bytes[position] as usize) << 10) ^ ((bytes[position + 1] as usize) << 5) ^ bytes[position + 2] as usize;
+        value & ((1 << HASH_BITS) - 1)
+    }
+
+    fn insert(&mut self, bytes: &[u8], position: usize) {
+        if position + MIN_MATCH > bytes.len() {
+            return;
         }
+
+        let hash = Self::hash(bytes, position);
+        self.prev[position % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = position;
     }
 
-    let huff = match Huffman::generate_from_length_symbols(lengths_with_symbols) {
-        Ok(x) => x,
-        Err(e) => panic!("{}", e),
-    };
+    /// Returns the length and distance of the longest match for position among the positions
+    /// inserted so far, or a length of 0 if there isn't one at least MIN_MATCH long.
+    fn longest_match(&self, bytes: &[u8], position: usize) -> (usize, usize) {
+        if position + MIN_MATCH > bytes.len() {
+            return (0, 0);
+        }
 
-    huff 
-}
+        let max_length = MAX_MATCH.min(bytes.len() - position);
+        let mut candidate = self.head[Self::hash(bytes, position)];
+        let mut best = (0, 0);
 
-fn decode_block_fixed(comp: &mut Bits, out : Vec<u8>) -> Result<Vec<u8>, &'static str> {
-    let mut out = out;
-   
-    let huff : Huffman = generate_fixed_huffman(); 
-    let length_table : HashMap<u32, (u32, u32)> = generate_length_table();
-    let dist_table : HashMap<u32, (u32, u32)> = generate_dist_table();
+        for _ in 0..self.max_chain {
+            // Chains run from newest to oldest, so the rest are out of the window too. A slot of
+            // prev is reused once its position leaves the window, which the check also catches.
+            if candidate >= position || position - candidate > WINDOW_SIZE {
+                break;
+            }
 
-    loop {
-        let code = huff.read_one_code(comp)?;
+            // Only matches longer than the best so far matter, so the byte that would make it
+            // longer is checked before the rest.
+            if bytes[candidate + best.0] == bytes[position + best.0] {
+                let length = (0..max_length)
+                    .take_while(|&i| bytes[candidate + i] == bytes[position + i])
+                    .count();
 
-        match code {
-            x if x < 256 => {
-                out.push(x as u8);
-                continue;
-                },
-            256 => break,
-            _ => (),
-        };
-        
-        //If the loop is still ongoing - decoded is a length - read distance and the read those
-        //literals into out
-        let (extra_len, length) = *length_table.get(&code).expect("Invalid fixed length huffman code was read for length!");
-        let mut extra = 0u32;
-
-        //Extra length bits are read MSB first instead of the usual LSB that all the other bytes
-        //are read...
-        if extra_len != 0 {
-            extra = comp.read_bits_reversed(extra_len).expect("Deflate stream is broken - couldn't read extra bits");
-        }
-        let length = length + extra;
-        //Distance code is not huffman coded. Just a 5 bit code.
-        let dist_value = comp.read_bits(5).expect("Deflate stream is broken - couldn't read initial distance bits!"); 
-        //Now that we have the distance code, use the hash table to read code and extra bits to
-        //find real distance value.
-    
-        let (extra_len, dist) = *dist_table.get(&dist_value).expect(&format!("Invalid fixed length huffman code was read for distance ({})", dist_value));
+                if length > best.0 {
+                    best = (length, position - candidate);
 
-        let mut extra = 0u32;
+                    if length == max_length {
+                        break;
+                    }
+                }
+            }
 
-        if extra_len != 0 {
-            extra = comp.read_bits_reversed(extra_len).expect("Deflate stream is broken - couldn't read extra distance bits!");
+            candidate = self.prev[candidate % WINDOW_SIZE];
         }
 
-        let dist = dist + extra;
-        
-        //Push <length> literals starting from <dist> bytes before.
-        out = zlss(out, length, dist);
+        match best.0 >= MIN_MATCH {
+            true => best,
+            false => (0, 0),
+        }
     }
-
-    Ok(out)
 }
 
-fn decode_block_none(comp: &mut Bits, out : Vec<u8>) -> Result<Vec<u8>, &'static str> {
-    let mut out = out;
+/// Splits bytes into LZ77 symbols. Lazy compression searches longer chains, and before taking a
+/// match checks whether the next byte starts a longer one, in which case this byte is written as
+/// a literal instead.
+fn lz77(bytes: &[u8], level: CompressionLevel) -> Vec<Lz77Symbol> {
+    let (max_chain, lazy) = match level {
+        CompressionLevel::Lazy => (256, true),
+        _ => (32, false),
+    };
 
-    comp.read_bits(5);
-    let block_len = comp.read_bits_reversed(16).expect("Couldn't read block length from stream"); // two bytes in reversed endianness
-    let block_len_compl = comp.read_bits_reversed(16).expect("Couldn't read block lenght complement from stream");
-    
+    let mut finder = MatchFinder::new(max_chain);
+    let mut symbols = vec![];
+    let mut position = 0;
 
-    if !block_len_compl << 16 != block_len << 16{
-        println!("Complement is invalid!");
-    } 
-    
-    for _i in 0..block_len {
-        let next_byte = comp.read_bits(8).expect(&format!("Expected {block_len} bytes in stream - but reached end before!"));
-        out.push((next_byte as u8).reverse_bits());
-    }
-    
-    Ok(out)
-}
+    while position < bytes.len() {
+        let (length, distance) = finder.longest_match(bytes, position);
+        finder.insert(bytes, position);
 
-fn generate_fixed_huffman() -> Huffman {
-    let mut bitmap : HashMap<String, u32> = HashMap::new();
+        let defer = lazy && (MIN_MATCH..MAX_MATCH).contains(&length)
+            && finder.longest_match(bytes, position + 1).0 > length;
 
-    //code length 7
-    let mut code = 0u32;
+        if length < MIN_MATCH || defer {
+            symbols.push(Lz77Symbol::Literal(bytes[position]));
+            position += 1;
+            continue;
+        }
 
-    for value in 256..=279u32 { 
-        let code_string = format!("{:#01$b}", code, 2 + 7);     
-        bitmap.insert(code_string, value);
-        code += 1;
+        symbols.push(Lz77Symbol::Match(length, distance));
+        for skipped in position + 1..position + length {
+            finder.insert(bytes, skipped);
+        }
+        position += length;
     }
 
-    code = code << 1;
-    //code length 8
-    for value in 0..=143u32 {
-        let code_string = format!("{:#01$b}", code, 2 + 8);
-        bitmap.insert(code_string, value);
-        code += 1;
-    }
+    symbols
+}
 
-    for value in 280..=287u32 {
-        let code_string = format!("{:#01$b}", code, 2 + 8);
-        bitmap.insert(code_string, value);
-        code += 1;
+/// Turns a length or distance table around, so that codes[value] holds the code for value, the
+/// number of extra bits and the value of the extra bits.
+fn invert_deflate_table(table: &[(u32, u32)], first_code: u32, max_value: u32) -> Vec<(u32, u32, u32)> {
+    // Each value ends up with the code that has the largest base not above it. Length 258 has a
+    // code of its own even though code 284 could reach it with extra bits. Bases go up with the
+    // codes, so later codes overwrite earlier ones.
+    let mut codes = vec![(0, 0, 0); max_value as usize + 1];
+    for (code, &(extra_bits, base)) in (first_code..).zip(table) {
+        for value in base..=max_value {
+            codes[value as usize] = (code, extra_bits, value - base);
+        }
     }
 
-    code = code << 1;
-    
-    //code length 9
-    for value in 144..=255u32 { 
-        let code_string = format!("{:#01$b}", code, 2 + 9);
-        bitmap.insert(code_string, value);
-        code += 1;
-    } 
-    
-    Huffman {bitmap}
-}
-
-//Push <length> literals starting from <dist> bytes before.
-fn zlss(out : Vec<u8>, length : u32, distance: u32) -> Vec<u8> {
-    let mut out = out;
-
-    for i in (out.len())..(out.len() + length as usize) {
-        let found_literal = *out.get(i - distance as usize).expect("output buffer was not long enough - attempting to read literals that don't exist");
-        out.push(found_literal);
-    }
-
-    out
-}
-
-
-fn generate_length_table() -> HashMap<u32, (u32,u32)> {
-    let length_table : HashMap<u32, (u32, u32)> = HashMap::from([
-        (257, (0,3)),
-        (258, (0,4)),
-        (259, (0,5)),
-        (260, (0,6)),
-        (261, (0,7)),
-        (262, (0,8)),
-        (263, (0,9)),
-        (264, (0,10)),
-        (265, (1,11)),
-        (266, (1,13)),
-        (267, (1,15)),
-        (268, (1,17)),
-        (269, (2,19)),
-        (270, (2,23)),
-        (271, (2,27)),
-        (272, (2,31)),
-        (273, (3,35)),
-        (274, (3,43)),
-        (275, (3,51)),
-        (276, (3,59)),
-        (277, (4,67)),
-        (278, (4,83)),
-        (279, (4,99)),
-        (280, (4,115)),
-        (281, (5,131)),
-        (282, (5,163)),
-        (283, (5,195)),
-        (284, (5,227)),
-        (285, (0,258))
-    ]);
-
-    length_table
-}
-
-fn generate_dist_table() -> HashMap<u32, (u32,u32)> {
-    let dist_table : HashMap<u32, (u32,u32)> = HashMap::from([
-         (0, (0,1)),
-         (1, (0,2)),
-         (2, (0,3)),
-         (3, (0,4)),
-         (4, (1,5)),
-         (5, (1,7)),
-         (6, (2,9)),
-         (7, (2,13)),
-         (8, (3,17)),
-         (9, (3,25)),
-         (10, (4,33)),
-         (11, (4,49)),
-         (12, (5,65)),
-         (13, (5,97)),
-         (14, (6,129)),
-         (15, (6,193)),
-         (16, (7,257)),
-         (17, (7,385)),
-         (18, (8,513)),
-         (19, (8,769)),
-         (20, (9,1025)),
-         (21, (9,1537)),
-         (22, (10,2049)),
-         (23, (10,3073)),
-         (24, (11,4097)),
-         (25, (11,6145)),
-         (26, (12,8193)),
-         (27, (12,12289)),
-         (28, (13,16385)),
-         (29, (13,24577))
-    ]);
-
-    dist_table
+    codes
 }
 
-pub fn decompressed_to_scanlines(decoded_stream: Vec<u8>, image_height: u32) -> Vec<Vec<u8>> {
-    let line_size = decoded_stream.len() / image_height as usize; 
-    decoded_stream
-        .chunks(line_size)
-        .map(|x| x.to_vec())
-        .collect()
+/// Finds code lengths for a block. zlib refuses a code length code with only one code, so at
+/// least two symbols always get one.
+fn deflate_code_lengths(frequencies: &[u32], max_length: usize) -> Vec<u32> {
+    let mut frequencies = frequencies.to_vec();
+    let mut used = frequencies.iter().filter(|&&x| x > 0).count();
+
+    for frequency in frequencies.iter_mut() {
+        if used >= 2 {
+            break;
+        }
+
+        if *frequency == 0 {
+            *frequency = 1;
+            used += 1;
+        }
+    }
+
+    Huffman::code_lengths_from_frequencies(&frequencies, max_length)
 }
 
+fn huffman_from_lengths(lengths: &[u32]) -> Result<Huffman, &'static str> {
+    let mut lengths_with_symbols : Vec<Vec<u32>> = vec![vec![]; MAX_HUFFMAN_LENGTH as usize + 1];
 
-#[derive(Clone)]
-pub struct Huffman {
-    bitmap : HashMap<String, u32>
+    for (symbol, &length) in lengths.iter().enumerate() {
+        lengths_with_symbols[length as usize].push(symbol as u32);
+    }
+
+    Huffman::generate_from_length_symbols(lengths_with_symbols)
 }
 
-impl Huffman {
-    pub fn generate_from_length_symbols(lengths_with_symbols: Vec<Vec<u32>>) -> Result<Huffman, &'static str> {
-        if lengths_with_symbols.len() == 0 {
-            return Err("Cannot generate huffman from empty vector!");
-        }
+/// Code lengths of the fixed literal/length and distance codes from section 3.2.6 of RFC 1951.
+fn fixed_code_lengths() -> (Vec<u32>, Vec<u32>) {
+    let literal_lengths = (0..288)
+        .map(|x| match x {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        })
+        .collect();
+
+    (literal_lengths, vec![5; 30])
+}
 
-        let mut bitmap : HashMap<String, u32> = HashMap::new();
-        let mut code = 0u32;    
+/// Run length codes the literal/length and distance code lengths with code length symbols 16, 17
+/// and 18. Returns each symbol with the value and number of its extra bits.
+fn code_length_symbols(lengths: &[u32]) -> Vec<(u32, u32, u32)> {
+    let mut symbols = vec![];
+    let mut index = 0;
+
+    while index < lengths.len() {
+        let length = lengths[index];
+        let run = lengths[index..].iter().take_while(|&&x| x == length).count();
+        let mut remaining = run;
+
+        if length == 0 {
+            while remaining >= 11 {
+                let count = remaining.min(138);
+                symbols.push((18, count as u32 - 11, 7));
+                remaining -= count;
+            }
 
-        for (code_length, symbols) in lengths_with_symbols.into_iter().enumerate().skip(1) { 
-            //We skip code length 0 when creating the table
-            let mut sorted = symbols;
-            sorted.sort();
-            
-            for symbol in sorted {
-                let code_string = format!("{:#01$b}", code, code_length + 2); 
-                bitmap.insert(code_string, symbol);
-                code += 1;
+            if remaining >= 3 {
+                symbols.push((17, remaining as u32 - 3, 3));
+                remaining = 0;
+            }
+        } else {
+            // 16 repeats the previous length, so the length itself is written first.
+            symbols.push((length, 0, 0));
+            remaining -= 1;
+
+            while remaining >= 3 {
+                let count = remaining.min(6);
+                symbols.push((16, count as u32 - 3, 2));
+                remaining -= count;
             }
-            code = code << 1;
-        }
-        let huffman = Huffman{bitmap};
-        Ok(huffman)
-    }
-
-    pub fn read_one_code(&self, stream: &mut Bits) -> Result<u32, & 'static str>{
-        let mut bits = 0u32;
-        let mut num_bits = 0usize;
-        loop{
-            let next_bit = stream.read_bits(1).expect("Deflate stream is broken - trying to read out of bounds!");
-            bits = (bits << 1) + next_bit;
-            num_bits += 1;
-            let bit_string = format!("{:#01$b}", bits, 2 + num_bits);     
-            match self.bitmap.get(&bit_string) {
-                Some(&x) => {return Ok(x)},
-                None => continue,
-            };
         }
-    }
-}
 
+        symbols.extend(std::iter::repeat_n((length, 0, 0), remaining));
+        index += run;
+    }
 
-pub struct Defilter {
-    channels : usize,
-    bit_depth: u32,
-    scanlines : Vec<Vec<u8>>,
-    defiltered : Vec<Vec<u8>>,
+    symbols
+}
+
+/// Writes bytes as stored blocks of at most 65535 bytes each.
+fn write_block_stored(stream: &mut Bits, bytes: &[u8], is_final: bool) {
+    let blocks : Vec<&[u8]> = match bytes.is_empty() {
+        true => vec![&[]],
+        false => bytes.chunks(65535).collect(),
+    };
+
+    for (index, block) in blocks.iter().enumerate() {
+        // BFINAL and BTYPE 00, padded out to a byte, followed by LEN and NLEN
+        stream.write_bits((is_final && index == blocks.len() - 1) as u32, 1);
+        stream.write_bits_reversed(0b00, 2);
+        stream.write_bits(0, ((8 - stream.bit_count() % 8) % 8) as u32);
+
+        stream.write_bits_reversed(block.len() as u32, 16);
+        stream.write_bits_reversed(!(block.len() as u16) as u32, 16);
+        for &byte in block.iter() {
+            stream.write_bits_reversed(byte as u32, 8);
+        }
+    }
+}
+
+/// Writes one block of LZ77 symbols. The fixed level always uses the fixed codes, the others
+/// write whichever of a stored, fixed or dynamic block comes out smallest.
+fn write_block_compressed(stream: &mut Bits, symbols: &[Lz77Symbol], bytes: &[u8], is_final: bool, level: CompressionLevel, length_codes: &[(u32, u32, u32)], dist_codes: &[(u32, u32, u32)]) -> Result<(), &'static str> {
+    let mut literal_frequencies = vec![0u32; 286];
+    let mut dist_frequencies = vec![0u32; 30];
+    let mut extra_bits = 0usize;
+
+    // End of block
+    literal_frequencies[256] = 1;
+
+    for symbol in symbols {
+        match *symbol {
+            Lz77Symbol::Literal(byte) => literal_frequencies[byte as usize] += 1,
+            Lz77Symbol::Match(length, distance) => {
+                let (length_code, length_extra, _) = length_codes[length];
+                let (dist_code, dist_extra, _) = dist_codes[distance];
+
+                literal_frequencies[length_code as usize] += 1;
+                dist_frequencies[dist_code as usize] += 1;
+                extra_bits += (length_extra + dist_extra) as usize;
+            },
+        }
+    }
+
+    let coded_bits = |literal_lengths: &[u32], dist_lengths: &[u32]| -> usize {
+        let literal_bits : usize = literal_frequencies.iter().zip(literal_lengths).map(|(&x, &y)| (x * y) as usize).sum();
+        let dist_bits : usize = dist_frequencies.iter().zip(dist_lengths).map(|(&x, &y)| (x * y) as usize).sum();
+        3 + literal_bits + dist_bits + extra_bits
+    };
+
+    let (fixed_literal_lengths, fixed_dist_lengths) = fixed_code_lengths();
+    let fixed_bits = coded_bits(&fixed_literal_lengths, &fixed_dist_lengths);
+
+    if level == CompressionLevel::Fixed {
+        return write_block_huffman(stream, symbols, is_final, None, &fixed_literal_lengths, &fixed_dist_lengths, length_codes, dist_codes);
+    }
+
+    // Dynamic block header - trailing unused codes are left out, down to the minimum counts.
+    let literal_lengths = deflate_code_lengths(&literal_frequencies, 15);
+    let dist_lengths = deflate_code_lengths(&dist_frequencies, 15);
+    let literal_count = 257.max(literal_lengths.iter().rposition(|&x| x > 0).map_or(0, |x| x + 1));
+    let dist_count = 1.max(dist_lengths.iter().rposition(|&x| x > 0).map_or(0, |x| x + 1));
+
+    let all_lengths : Vec<u32> = literal_lengths[..literal_count].iter().chain(&dist_lengths[..dist_count]).copied().collect();
+    let header_symbols = code_length_symbols(&all_lengths);
+    let mut code_length_frequencies = vec![0u32; 19];
+    for &(symbol, _, _) in header_symbols.iter() {
+        code_length_frequencies[symbol as usize] += 1;
+    }
+
+    let code_length_lengths = deflate_code_lengths(&code_length_frequencies, 7);
+    let code_length_count = 4.max(CODE_LENGTH_ORDER.iter().rposition(|&x| code_length_lengths[x] > 0).map_or(0, |x| x + 1));
+
+    let header_bits = 14 + 3 * code_length_count + header_symbols
+        .iter()
+        .map(|&(symbol, _, extra)| (code_length_lengths[symbol as usize] + extra) as usize)
+        .sum::<usize>();
+    let dynamic_bits = header_bits + coded_bits(&literal_lengths, &dist_lengths);
+
+    // Every stored block costs at most a byte of padding and header and four bytes of LEN and NLEN.
+    let stored_bits = 8 * (bytes.len() + 5 * bytes.len().div_ceil(65535).max(1));
+
+    if stored_bits < dynamic_bits.min(fixed_bits) {
+        write_block_stored(stream, bytes, is_final);
+        return Ok(());
+    }
+
+    if fixed_bits <= dynamic_bits {
+        return write_block_huffman(stream, symbols, is_final, None, &fixed_literal_lengths, &fixed_dist_lengths, length_codes, dist_codes);
+    }
+
+    let header = DynamicHeader {
+        literal_count,
+        dist_count,
+        code_length_count,
+        code_length_lengths,
+        symbols: header_symbols,
+    };
+
+    write_block_huffman(stream, symbols, is_final, Some(header), &literal_lengths, &dist_lengths, length_codes, dist_codes)
+}
+
+/// The parts of a dynamic block header that describe its huffman codes.
+struct DynamicHeader {
+    literal_count : usize,
+    dist_count : usize,
+    code_length_count : usize,
+    code_length_lengths : Vec<u32>,
+    symbols : Vec<(u32, u32, u32)>,
+}
+
+/// Writes a fixed block, or a dynamic block if there is a header to describe its codes.
+#[allow(clippy::too_many_arguments)]
+fn write_block_huffman(stream: &mut Bits, symbols: &[Lz77Symbol], is_final: bool, header: Option<DynamicHeader>, literal_lengths: &[u32], dist_lengths: &[u32], length_codes: &[(u32, u32, u32)], dist_codes: &[(u32, u32, u32)]) -> Result<(), &'static str> {
+    stream.write_bits(is_final as u32, 1);
+
+    match header {
+        None => stream.write_bits_reversed(0b01, 2),
+        Some(header) => {
+            stream.write_bits_reversed(0b10, 2);
+            stream.write_bits_reversed(header.literal_count as u32 - 257, 5);
+            stream.write_bits_reversed(header.dist_count as u32 - 1, 5);
+            stream.write_bits_reversed(header.code_length_count as u32 - 4, 4);
+
+            for &symbol in CODE_LENGTH_ORDER.iter().take(header.code_length_count) {
+                stream.write_bits_reversed(header.code_length_lengths[symbol], 3);
+            }
+
+            let code_length_huff = huffman_from_lengths(&header.code_length_lengths)?;
+            for (symbol, extra, extra_bits) in header.symbols {
+                code_length_huff.write_one_code(symbol, stream)?;
+                stream.write_bits_reversed(extra, extra_bits);
+            }
+        },
+    };
+
+    let literal_huff = huffman_from_lengths(literal_lengths)?;
+    let dist_huff = huffman_from_lengths(dist_lengths)?;
+
+    for symbol in symbols {
+        match *symbol {
+            Lz77Symbol::Literal(byte) => literal_huff.write_one_code(byte as u32, stream)?,
+            Lz77Symbol::Match(length, distance) => {
+                let (length_code, length_extra_bits, length_extra) = length_codes[length];
+                let (dist_code, dist_extra_bits, dist_extra) = dist_codes[distance];
+
+                literal_huff.write_one_code(length_code, stream)?;
+                stream.write_bits_reversed(length_extra, length_extra_bits);
+                dist_huff.write_one_code(dist_code, stream)?;
+                stream.write_bits_reversed(dist_extra, dist_extra_bits);
+            },
+        }
+    }
+
+    literal_huff.write_one_code(256, stream)
+}
+
+/// Takes in a compressed stream of bytes and returns a decompressed stream of bytes.
+///
+/// This method will error if the input stream does not meet the specification outlined in RFC
+/// 1950 and 1951. Note that this method requires the input stream to be encoded in ZLIB
+/// format.
+pub fn decompress(deflate_stream: Vec<u8>) -> Result<Vec<u8>, &'static str> {
+    decompress_with_dictionaries(deflate_stream, &[])
+}
+
+/// Decompresses a ZLIB stream like decompress(), but streams with a preset dictionary (FDICT)
+/// are also accepted. The dictionary is looked up among dictionaries by its Adler-32 checksum,
+/// which is the DICTID the stream names it by, and is used as the data before the stream.
+pub fn decompress_with_dictionaries(deflate_stream: Vec<u8>, dictionaries: &[&[u8]]) -> Result<Vec<u8>, &'static str> {
+    // Two header bytes and the four byte Adler-32 checksum surround the DEFLATE data.
+    if deflate_stream.len() < 6 {
+        return Err("Zlib stream is too short to hold a header and checksum!");
+    }
+
+    let bit_count = deflate_stream.len() * 8;
+    let mut inflater = Inflater::new(Bits::new(deflate_stream, true, bit_count), dictionaries)?;
+    let mut out = vec![];
+    let mut buffer = vec![0u8; 65536];
+
+    loop {
+        let length = inflater.inflate(&mut buffer)?;
+        if length == 0 {
+            break;
+        }
+
+        out.extend_from_slice(&buffer[..length]);
+    }
+
+    Ok(out)
+}
+
+/// The block of a DEFLATE stream an Inflater is part way through.
+enum InflateBlock {
+    /// The next bits are the header of a block.
+    Header,
+    /// A stored block with this many bytes left to copy.
+    Stored(u32),
+    /// A fixed or dynamic block, with its literal/length and distance codes.
+    Huffman(Box<Huffman>, Box<Huffman>),
+    /// The final block has ended and the checksum has been checked.
+    Done,
+}
+
+/// Decompresses a ZLIB stream a piece at a time, so the whole output never has to be held in
+/// memory. Only the last 32K of output is kept, since matches can't reach back any further.
+pub struct Inflater<'a> {
+    input : Bits<'a>,
+    block : InflateBlock,
+    final_block : bool,
+
+    /// The last WINDOW_SIZE bytes of output, indexed by output_count modulo WINDOW_SIZE.
+    window : Vec<u8>,
+
+    /// Number of bytes output so far, counting a preset dictionary.
+    output_count : usize,
+
+    /// Length and distance of a match that is still being copied.
+    copy_length : u32,
+    copy_distance : u32,
+
+    checksum : Adler32,
+}
+
+impl<'a> Inflater<'a> {
+    /// Reads the ZLIB header from the start of input. Streams with a preset dictionary find it
+    /// among dictionaries, as decompress_with_dictionaries() does.
+    pub fn new(input: Bits<'a>, dictionaries: &[&[u8]]) -> Result<Self, &'static str> {
+        let mut input = input;
+        let first_byte = input.read_bits_reversed(8).ok_or("Zlib stream is too short to hold a header and checksum!")?;
+        let flag_byte = input.read_bits_reversed(8).ok_or("Zlib stream is too short to hold a header and checksum!")?;
+
+        let cmf = first_byte & 0x0f;
+        let window_size = 2_u32.pow((first_byte >> 4) + 8); 
+
+        // FCHECK makes CMF and FLG, read as one big-endian number, a multiple of 31.
+        if ((first_byte << 8) | flag_byte) % 31 != 0 {
+            return Err("Zlib header check bits are wrong!");
+        }
+
+        if cmf != 8 {
+            return Err("Zlib stream doesn't use the deflate compression method!");
+        }
+
+        if window_size > WINDOW_SIZE as u32 {
+            return Err("Zlib window size is larger than 32K!");
+        }
+
+        let mut inflater = Inflater {
+            input,
+            block: InflateBlock::Header,
+            final_block: false,
+            window: vec![0; WINDOW_SIZE],
+            output_count: 0,
+            copy_length: 0,
+            copy_distance: 0,
+            checksum: Adler32::new(),
+        };
+
+        // With FDICT set, the four byte DICTID comes before the compressed data and the
+        // dictionary it names is put in the window first, so matches can reach back into it.
+        // The dictionary isn't part of the output, or its checksum.
+        if (flag_byte >> 5) & 0b1 == 1 {
+            let dict_id = inflater.read_big_endian_u32().ok_or("Zlib stream is too short to hold a dictionary id!")?;
+            let dictionary = dictionaries
+                .iter()
+                .find(|x| adler32(x) == dict_id)
+                .ok_or("Zlib stream needs a preset dictionary that wasn't given!")?;
+
+            for &byte in dictionary.iter() {
+                inflater.push_to_window(byte);
+            }
+        }
+
+        Ok(inflater)
+    }
+
+    /// Fills out with the next decompressed bytes and returns how many there were. This is less
+    /// than out.len() only once the stream has ended, after which it returns 0.
+    ///
+    /// Errors from reading the compressed bytes are returned in place of the error they caused.
+    pub fn inflate(&mut self, out: &mut [u8]) -> Result<usize, &'static str> {
+        self.inflate_into(out).map_err(|e| self.input.get_source_error().unwrap_or(e))
+    }
+
+    fn inflate_into(&mut self, out: &mut [u8]) -> Result<usize, &'static str> {
+        let mut written = 0;
+        let mut finished = false;
+
+        while written < out.len() {
+            // Matches are copied a byte at a time, since they can overlap the bytes they produce.
+            if self.copy_length > 0 {
+                let count = (self.copy_length as usize).min(out.len() - written);
+                for byte_out in &mut out[written..written + count] {
+                    let byte = self.window[(self.output_count - self.copy_distance as usize) % WINDOW_SIZE];
+                    self.window[self.output_count % WINDOW_SIZE] = byte;
+                    self.output_count += 1;
+                    *byte_out = byte;
+                }
+
+                written += count;
+                self.copy_length -= count as u32;
+                continue;
+            }
+
+            match self.block {
+                InflateBlock::Header if self.final_block => {
+                    finished = true;
+                    break;
+                },
+                InflateBlock::Header => self.read_block_header()?,
+                InflateBlock::Stored(0) => self.block = InflateBlock::Header,
+                InflateBlock::Stored(remaining) => {
+                    let byte = self.input.read_bits_reversed(8).ok_or("Deflate stream ended in the middle of a stored block!")? as u8;
+                    self.push_to_window(byte);
+                    out[written] = byte;
+                    written += 1;
+                    self.block = InflateBlock::Stored(remaining - 1);
+                },
+                InflateBlock::Huffman(ref literal_huff, ref dist_huff) => {
+                    let code = literal_huff.read_one_code(&mut self.input)?;
+
+                    match code {
+                        x if x < 256 => {
+                            self.window[self.output_count % WINDOW_SIZE] = x as u8;
+                            self.output_count += 1;
+                            out[written] = x as u8;
+                            written += 1;
+                        },
+                        256 => self.block = InflateBlock::Header,
+                        _ => {
+                            // Extra bits are integers, so they are read LSB first like the header
+                            // fields.
+                            let (extra_length_bits, length) = *LENGTH_CODES.get(code as usize - 257).ok_or("Deflate stream holds an invalid length code!")?;
+                            let length_extra = self.input.read_bits_reversed(extra_length_bits).ok_or("Deflate stream is broken - couldn't read extra length bits!")?;
+
+                            let dist_code = dist_huff.read_one_code(&mut self.input)?;
+                            let (extra_dist_bits, dist) = *DIST_CODES.get(dist_code as usize).ok_or("Deflate stream holds an invalid distance code!")?;
+                            let dist_extra = self.input.read_bits_reversed(extra_dist_bits).ok_or("Deflate stream is broken - couldn't read extra distance bits!")?;
+
+                            if (dist + dist_extra) as usize > self.output_count {
+                                return Err("Deflate stream copies from before the start of the output!");
+                            }
+
+                            self.copy_length = length + length_extra;
+                            self.copy_distance = dist + dist_extra;
+                        },
+                    };
+                },
+                InflateBlock::Done => break,
+            };
+        }
+
+        self.checksum.update(&out[..written]);
+
+        // The Adler-32 checksum of the output follows the final block, starting on a byte.
+        if finished {
+            self.input.align_to_byte();
+            let checksum = self.read_big_endian_u32().ok_or("Zlib stream is too short to hold a header and checksum!")?;
+
+            if checksum != self.checksum.finalize() {
+                return Err("Adler-32 checksum of the decompressed data doesn't match the zlib stream!");
+            }
+
+            self.block = InflateBlock::Done;
+        }
+
+        Ok(written)
+    }
+
+    fn push_to_window(&mut self, byte: u8) {
+        self.window[self.output_count % WINDOW_SIZE] = byte;
+        self.output_count += 1;
+    }
+
+    fn read_big_endian_u32(&mut self) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            value = (value << 8) | self.input.read_bits_reversed(8)?;
+        }
+
+        Some(value)
+    }
+
+    /// DEFLATE streams consist of multiple blocks of compressed bits each using one of three
+    /// outlined compression methods. For each block, three bits are read which indicate the
+    /// compression type and whether the block is the final one in the stream.
+    fn read_block_header(&mut self) -> Result<(), &'static str> {
+        let bfinal = self.input.read_bits(1).ok_or("Deflate stream header broken - couldn't read block final value!")?;
+        let btype = self.input.read_bits_reversed(2).ok_or("Deflate stream header broken - couldn't read block type!")?;
+
+        self.final_block = bfinal == 1;
+
+        self.block = match btype {
+            0b00 => {
+                // LEN and NLEN start on the next byte.
+                self.input.align_to_byte();
+                let block_len = self.input.read_bits_reversed(16).ok_or("Couldn't read block length from stream")?;
+                let block_len_compl = self.input.read_bits_reversed(16).ok_or("Couldn't read block length complement from stream")?;
+
+                if block_len ^ block_len_compl != 0xFFFF {
+                    return Err("Stored block length doesn't match its complement!");
+                }
+
+                InflateBlock::Stored(block_len)
+            },
+            0b01 => InflateBlock::Huffman(Box::new(generate_fixed_huffman()), Box::new(huffman_from_lengths(&[5; 30])?)),
+            0b10 => {
+                let (literal_huff, dist_huff) = read_dynamic_huffman(&mut self.input)?;
+                InflateBlock::Huffman(Box::new(literal_huff), Box::new(dist_huff))
+            },
+            _ => return Err("Deflate stream is broken - read reserved btype!"),
+        };
+
+        Ok(())
+    }
+}
+
+/// Reads the header of a dynamic block and returns its literal/length and distance codes.
+fn read_dynamic_huffman(comp: &mut Bits) -> Result<(Huffman, Huffman), &'static str> {
+    let literal_length_code_count = 257 + comp.read_bits_reversed(5).ok_or("Deflate stream is broken - couldn't read HLIT from stream!")?;
+    let distance_code_count = 1 + comp.read_bits_reversed(5).ok_or("Deflate stream is broken - couldn't read HDIST from stream!")?;
+    let code_length_code_length_count = 4 + comp.read_bits_reversed(4).ok_or("Deflate stream is broken couldn't read HCLEN from stream!")?;
+
+    let code_length_huff : Huffman = generate_code_length_huff(comp, code_length_code_length_count)?;
+
+    // The literal/length and distance code lengths are one sequence, so a repeat can run from
+    // one into the other.
+    let code_lengths = read_code_lengths(comp, &code_length_huff, literal_length_code_count + distance_code_count)?;
+    let (literal_lengths, dist_lengths) = code_lengths.split_at(literal_length_code_count as usize);
+
+    Ok((huffman_from_lengths(literal_lengths)?, huffman_from_lengths(dist_lengths)?))
+}
+
+fn generate_code_length_huff(comp: &mut Bits, code_count: u32) -> Result<Huffman, &'static str> {
+    let mut lengths = vec![0u32; 19];
+
+    for &symbol in CODE_LENGTH_ORDER.iter().take(code_count as usize) {
+        //Read one 3 bit CL code length - which is reversed because it is an integer - from the stream
+        lengths[symbol] = comp.read_bits_reversed(3).ok_or("Deflate stream is broken - couldn't read code length code lengths!")?;
+    }
+
+    huffman_from_lengths(&lengths)
+}
+
+/// Reads symbol_count code lengths coded with the code length code, expanding the repeats of
+/// symbols 16, 17 and 18.
+fn read_code_lengths(comp: &mut Bits, cl_huff: &Huffman, symbol_count: u32) -> Result<Vec<u32>, &'static str> {
+    let mut lengths : Vec<u32> = vec![];
+
+    while lengths.len() < symbol_count as usize {
+        let code = cl_huff.read_one_code(comp)?;
+
+        let (length, count) = match code {
+            0..=15 => (code, 1),
+            16 => {
+                let previous = *lengths.last().ok_or("Deflate stream repeats a code length before the first one!")?;
+                (previous, 3 + comp.read_bits_reversed(2).ok_or("Deflate stream is broken - couldn't read repeat count!")?)
+            },
+            17 => (0, 3 + comp.read_bits_reversed(3).ok_or("Deflate stream is broken - couldn't read repeat count!")?),
+            18 => (0, 11 + comp.read_bits_reversed(7).ok_or("Deflate stream is broken - couldn't read repeat count!")?),
+            _ => return Err("Deflate stream holds an invalid code length code!"),
+        };
+
+        lengths.extend(std::iter::repeat_n(length, count as usize));
+    }
+
+    if lengths.len() > symbol_count as usize {
+        return Err("Deflate stream repeats code lengths past the last code!");
+    }
+
+    Ok(lengths)
+}
+
+fn generate_fixed_huffman() -> Huffman {
+    let mut lengths_with_symbols : Vec<Vec<u32>> = vec![vec![]; 10];
+
+    lengths_with_symbols[7] = (256..=279).collect();
+    lengths_with_symbols[8] = (0..=143).chain(280..=287).collect();
+    lengths_with_symbols[9] = (144..=255).collect();
+
+    Huffman::generate_from_length_symbols(lengths_with_symbols).expect("Fixed huffman code lengths are invalid!")
+}
+
+/// Extra bits and base length of each DEFLATE length code, starting from code 257.
+const LENGTH_CODES : [(u32, u32); 29] = [
+    (0, 3), (0, 4), (0, 5), (0, 6), (0, 7), (0, 8), (0, 9), (0, 10),
+    (1, 11), (1, 13), (1, 15), (1, 17), (2, 19), (2, 23), (2, 27), (2, 31),
+    (3, 35), (3, 43), (3, 51), (3, 59), (4, 67), (4, 83), (4, 99), (4, 115),
+    (5, 131), (5, 163), (5, 195), (5, 227), (0, 258),
+];
+
+/// Extra bits and base distance of each DEFLATE distance code.
+const DIST_CODES : [(u32, u32); 30] = [
+    (0, 1), (0, 2), (0, 3), (0, 4), (1, 5), (1, 7), (2, 9), (2, 13),
+    (3, 17), (3, 25), (4, 33), (4, 49), (5, 65), (5, 97), (6, 129), (6, 193),
+    (7, 257), (7, 385), (8, 513), (8, 769), (9, 1025), (9, 1537), (10, 2049), (10, 3073),
+    (11, 4097), (11, 6145), (12, 8193), (12, 12289), (13, 16385), (13, 24577),
+];
+
+pub fn decompressed_to_scanlines(decoded_stream: Vec<u8>, image_height: u32) -> Vec<Vec<u8>> {
+    let line_size = decoded_stream.len() / image_height as usize; 
+    decoded_stream
+        .chunks(line_size)
+        .map(|x| x.to_vec())
+        .collect()
+}
+
+/// Starting column, starting row, column step and row step of each of the seven Adam7 passes.
+const ADAM7_PASSES : [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Returns the width and height of the reduced image that makes up one Adam7 pass.
+pub fn adam7_pass_size(pass: usize, image_width: usize, image_height: usize) -> (usize, usize) {
+    let (start_col, start_row, col_step, row_step) = ADAM7_PASSES[pass];
+    let pass_width = image_width.saturating_sub(start_col).div_ceil(col_step);
+    let pass_height = image_height.saturating_sub(start_row).div_ceil(row_step);
+
+    (pass_width, pass_height)
+}
+
+/// Splits the decompressed stream of an Adam7 interlaced image into the filtered scanlines of
+/// each of its seven passes. Every scanline keeps its filter type byte and passes that contain no
+/// pixels have no scanlines at all.
+pub fn decompressed_to_adam7_scanlines(decoded_stream: Vec<u8>, image_width: u32, image_height: u32, bits_per_pixel: u32) -> Result<Vec<Vec<Vec<u8>>>, &'static str> {
+    let mut passes = vec![];
+    let mut remaining = decoded_stream.as_slice();
+
+    for pass in 0..ADAM7_PASSES.len() {
+        let (pass_width, pass_height) = adam7_pass_size(pass, image_width as usize, image_height as usize);
+        let mut scanlines = vec![];
+
+        if pass_width != 0 {
+            let line_size = 1 + (pass_width * bits_per_pixel as usize).div_ceil(8);
+
+            for _row in 0..pass_height {
+                if remaining.len() < line_size {
+                    return Err("Decompressed stream is too short for an interlaced image of this size!");
+                }
+
+                let (line, rest) = remaining.split_at(line_size);
+                scanlines.push(line.to_vec());
+                remaining = rest;
+            }
+        }
+
+        passes.push(scanlines);
+    }
+
+    Ok(passes)
+}
+
+/// Scatters the pixels of the seven Adam7 passes back into their places in the full image.
+pub fn deinterlace_adam7(passes: Vec<Pixels>, image_width: u32, image_height: u32) -> Result<Pixels, &'static str> {
+    let mut grid : Vec<Vec<Option<Pixel>>> = vec![vec![None; image_width as usize]; image_height as usize];
+
+    for (pass, pass_pixels) in passes.iter().enumerate() {
+        let (start_col, start_row, col_step, row_step) = ADAM7_PASSES[pass];
+
+        for (pass_row, row) in pass_pixels.iter().enumerate() {
+            for (pass_col, pixel) in row.iter().enumerate() {
+                let y = start_row + pass_row * row_step;
+                let x = start_col + pass_col * col_step;
+
+                if y < image_height as usize && x < image_width as usize {
+                    grid[y][x] = Some(pixel.clone());
+                }
+            }
+        }
+    }
+
+    let mut pixels = Pixels::new();
+
+    for row in grid {
+        match row.into_iter().collect::<Option<Vec<Pixel>>>() {
+            Some(row) => pixels.push(row),
+            None => return Err("Adam7 passes did not cover every pixel in the image!"),
+        }
+    }
+
+    Ok(pixels)
+}
+
+
+/// Number of bits read at once when looking up a huffman code. Most codes are shorter than this,
+/// so they are found with a single lookup.
+const HUFFMAN_LOOKUP_BITS : u32 = 9;
+
+/// Longest code allowed in a JPEG huffman table - DEFLATE codes are at most 15 bits.
+const MAX_HUFFMAN_LENGTH : u32 = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum HuffmanEntry {
+    /// No code starts with these bits.
+    Invalid,
+    /// A symbol and the length of its code.
+    Symbol(u32, u32),
+    /// These bits start codes that are too long for the table - they continue in the overflow
+    /// table at this index.
+    Overflow(usize),
+}
+
+#[derive(Clone)]
+pub struct Huffman {
+    /// Indexed by the next HUFFMAN_LOOKUP_BITS bits of the stream - tells which symbol's code they
+    /// start with, or where to look in overflow for codes longer than that.
+    lookup : Vec<HuffmanEntry>,
+
+    /// The same entries as lookup, indexed by the next bits of an LSB first stream as they sit in
+    /// its accumulator - so the first bit read is the lowest bit of the index.
+    reversed_lookup : Vec<HuffmanEntry>,
+
+    /// Tables for codes longer than HUFFMAN_LOOKUP_BITS, each indexed by the max_length -
+    /// HUFFMAN_LOOKUP_BITS bits following the ones looked up first.
+    overflow : Vec<HuffmanEntry>,
+
+    /// Length of the longest code.
+    max_length : u32,
+
+    /// Maps each symbol to its code and the length of that code. Used while encoding.
+    codes : HashMap<u32, (u32, u32)>,
+}
+
+impl Huffman {
+    /// Generates a canonical huffman code where lengths_with_symbols[n] holds every symbol with a
+    /// code n bits long. Symbols of the same length are assigned codes in increasing order.
+    pub fn generate_from_length_symbols(lengths_with_symbols: Vec<Vec<u32>>) -> Result<Huffman, &'static str> {
+        let sorted = lengths_with_symbols
+            .into_iter()
+            .map(|mut symbols| {
+                symbols.sort();
+                symbols
+            })
+            .collect();
+
+        Self::generate_from_ordered_length_symbols(sorted)
+    }
+
+    /// Generates a huffman code like generate_from_length_symbols() but assigns codes to symbols of
+    /// the same length in the order they are given instead of sorting them - this is how JPEG
+    /// tables are defined.
+    pub fn generate_from_ordered_length_symbols(lengths_with_symbols: Vec<Vec<u32>>) -> Result<Huffman, &'static str> {
+        if lengths_with_symbols.is_empty() {
+            return Err("Cannot generate huffman from empty vector!");
+        }
+
+        let mut codes : HashMap<u32, (u32, u32)> = HashMap::new();
+        let mut code = 0u32;
+
+        for (code_length, symbols) in lengths_with_symbols.into_iter().enumerate().skip(1) { 
+            //We skip code length 0 when creating the table
+            for symbol in symbols {
+                if code_length > MAX_HUFFMAN_LENGTH as usize {
+                    return Err("Huffman codes can't be longer than 16 bits!");
+                }
+
+                if code >= 1 << code_length {
+                    return Err("Huffman code lengths don't leave room for every symbol!");
+                }
+
+                codes.insert(symbol, (code, code_length as u32));
+                code += 1;
+            }
+            code <<= 1;
+        }
+
+        let max_length = codes.values().map(|&(_, length)| length).max().unwrap_or(0);
+        let overflow_bits = max_length.saturating_sub(HUFFMAN_LOOKUP_BITS);
+        let mut lookup = vec![HuffmanEntry::Invalid; 1 << HUFFMAN_LOOKUP_BITS];
+        let mut overflow = vec![];
+
+        // A code fills every entry whose index starts with it. Codes too long for the first table
+        // fill entries in the overflow table of their first HUFFMAN_LOOKUP_BITS bits instead.
+        for (&symbol, &(code, length)) in codes.iter() {
+            let entry = HuffmanEntry::Symbol(symbol, length);
+
+            if length <= HUFFMAN_LOOKUP_BITS {
+                let start = (code << (HUFFMAN_LOOKUP_BITS - length)) as usize;
+                lookup[start..start + (1 << (HUFFMAN_LOOKUP_BITS - length))].fill(entry);
+                continue;
+            }
+
+            let prefix = (code >> (length - HUFFMAN_LOOKUP_BITS)) as usize;
+            let table_start = match lookup[prefix] {
+                HuffmanEntry::Overflow(table_start) => table_start,
+                _ => {
+                    lookup[prefix] = HuffmanEntry::Overflow(overflow.len());
+                    overflow.extend(std::iter::repeat_n(HuffmanEntry::Invalid, 1 << overflow_bits));
+                    overflow.len() - (1 << overflow_bits)
+                },
+            };
+
+            let rest = code & ((1 << (length - HUFFMAN_LOOKUP_BITS)) - 1);
+            let start = table_start + (rest << (max_length - length)) as usize;
+            overflow[start..start + (1 << (max_length - length))].fill(entry);
+        }
+
+        let reversed_lookup = (0..1u32 << HUFFMAN_LOOKUP_BITS)
+            .map(|index| lookup[(index.reverse_bits() >> (32 - HUFFMAN_LOOKUP_BITS)) as usize])
+            .collect();
+
+        Ok(Huffman { lookup, reversed_lookup, overflow, max_length, codes })
+    }
+
+    /// Finds the length of the code for every symbol, where frequencies[n] is the number of times
+    /// symbol n appears. Symbols that never appear get a length of 0 and no code.
+    ///
+    /// Lengths start out as those of an optimal huffman code. Codes longer than max_length are then
+    /// shortened the way Annex K.3 of the JPEG specification does it - two of the longest codes are
+    /// replaced by one code a bit shorter and a shorter code is split in two. The lengths are then
+    /// handed out again so the most frequent symbols get the shortest codes.
+    pub fn code_lengths_from_frequencies(frequencies: &[u32], max_length: usize) -> Vec<u32> {
+        let mut lengths = vec![0u32; frequencies.len()];
+        let mut used_symbols : Vec<usize> = (0..frequencies.len()).filter(|&x| frequencies[x] > 0).collect();
+
+        if used_symbols.len() == 1 {
+            lengths[used_symbols[0]] = 1;
+        }
+
+        if used_symbols.len() < 2 {
+            return lengths;
+        }
+
+        // Nodes 0 to frequencies.len() - 1 are the symbols and the rest are joined nodes. Each
+        // node keeps track of its parent so the depth of every symbol can be found at the end.
+        let mut parents : Vec<usize> = vec![usize::MAX; frequencies.len()];
+        let mut heap : BinaryHeap<Reverse<(u64, usize)>> = used_symbols
+            .iter()
+            .map(|&x| Reverse((frequencies[x] as u64, x)))
+            .collect();
+
+        while heap.len() > 1 {
+            let Reverse((first_frequency, first)) = heap.pop().unwrap();
+            let Reverse((second_frequency, second)) = heap.pop().unwrap();
+            let node = parents.len();
+
+            parents.push(usize::MAX);
+            parents[first] = node;
+            parents[second] = node;
+            heap.push(Reverse((first_frequency + second_frequency, node)));
+        }
+
+        let mut length_counts = vec![0u32; used_symbols.len() + 1];
+        for &symbol in used_symbols.iter() {
+            let mut depth = 0;
+            let mut node = symbol;
+
+            while parents[node] != usize::MAX {
+                node = parents[node];
+                depth += 1;
+            }
+
+            length_counts[depth] += 1;
+        }
+
+        // Annex K.3 - the deepest codes always come in pairs, so two of them can be replaced by
+        // one code a bit shorter, and the spare code is made by splitting the next shortest code.
+        let mut longest = length_counts.len() - 1;
+        while longest > max_length {
+            while length_counts[longest] > 0 {
+                let mut shorter = longest - 2;
+                while length_counts[shorter] == 0 {
+                    shorter -= 1;
+                }
+
+                length_counts[longest] -= 2;
+                length_counts[longest - 1] += 1;
+                length_counts[shorter + 1] += 2;
+                length_counts[shorter] -= 1;
+            }
+
+            longest -= 1;
+        }
+
+        used_symbols.sort_by_key(|&x| (Reverse(frequencies[x]), x));
+        let mut used_symbols = used_symbols.into_iter();
+
+        for (length, &count) in length_counts.iter().enumerate() {
+            for symbol in used_symbols.by_ref().take(count as usize) {
+                lengths[symbol] = length as u32;
+            }
+        }
+
+        lengths
+    }
+
+    /// Reads one code from the stream and returns its symbol. The next HUFFMAN_LOOKUP_BITS bits
+    /// find the symbol in one lookup, or the overflow table to find it in for longer codes.
+    pub fn read_one_code(&self, stream: &mut Bits) -> Result<u32, &'static str> {
+        // LSB first streams skip reversing the bits by looking them up in reversed_lookup.
+        let first_entry = match stream.is_lsb() {
+            true => self.reversed_lookup[stream.peek_raw_bits(HUFFMAN_LOOKUP_BITS) as usize],
+            false => self.lookup[stream.peek_bits(HUFFMAN_LOOKUP_BITS) as usize],
+        };
+
+        let entry = match first_entry {
+            HuffmanEntry::Overflow(table_start) => {
+                let overflow_bits = self.max_length - HUFFMAN_LOOKUP_BITS;
+                let rest = stream.peek_bits(self.max_length) & ((1 << overflow_bits) - 1);
+                self.overflow[table_start + rest as usize]
+            },
+            entry => entry,
+        };
+
+        match entry {
+            HuffmanEntry::Symbol(symbol, length) if length <= stream.len() => {
+                stream.consume_bits(length);
+                Ok(symbol)
+            },
+            HuffmanEntry::Symbol(_, _) => Err("Huffman code runs past the end of the stream!"),
+            _ => Err("Stream holds a code that isn't in the huffman table!"),
+        }
+    }
+
+    /// Writes the code for symbol into the stream - errors if the symbol has no code.
+    pub fn write_one_code(&self, symbol: u32, stream: &mut Bits) -> Result<(), &'static str> {
+        match self.codes.get(&symbol) {
+            Some(&(code, length)) => {
+                stream.write_bits(code, length);
+                Ok(())
+            },
+            None => Err("Symbol has no code in this huffman table!"),
+        }
+    }
+}
+
+
+/// How the PNG writer picks a filter type for each scanline.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum FilterStrategy {
+    /// Every scanline uses the same filter type, from 0 (None) to 4 (Paeth).
+    Fixed(u8),
+    /// Each scanline uses the filter whose bytes, read as signed values, have the smallest sum of
+    /// absolute values. This is the heuristic suggested by the PNG specification.
+    #[default]
+    MinSum,
+    /// Each scanline is compressed with every filter, after the scanlines before it, and uses
+    /// whichever comes out smallest.
+    BruteForce,
+}
+
+impl TryFrom<&str> for FilterStrategy {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "none" => Ok(FilterStrategy::Fixed(0)),
+            "sub" => Ok(FilterStrategy::Fixed(1)),
+            "up" => Ok(FilterStrategy::Fixed(2)),
+            "average" => Ok(FilterStrategy::Fixed(3)),
+            "paeth" => Ok(FilterStrategy::Fixed(4)),
+            "minsum" => Ok(FilterStrategy::MinSum),
+            "brute" => Ok(FilterStrategy::BruteForce),
+            _ => Err("Filter must be one of none, sub, up, average, paeth, minsum or brute!"),
+        }
+    }
+}
+
+/// Most bytes of already filtered scanlines compressed along with each trial scanline by
+/// FilterStrategy::BruteForce, so matches against the rows above are counted.
+const TRIAL_CONTEXT_LENGTH : usize = 8192;
+
+/// The inverse of Defilter - filters unfiltered scanlines for the PNG writer.
+pub struct Filter {
+    channels : usize,
+    bit_depth : u32,
+    scanlines : Vec<Vec<u8>>,
+}
+
+impl Filter {
+    pub fn new(channels : usize, bit_depth : u32, scanlines: Vec<Vec<u8>>) -> Filter {
+        Filter { channels, bit_depth, scanlines }
+    }
+
+    /// Filters every scanline and returns them one after another, each starting with its filter
+    /// type, ready to be compressed.
+    pub fn filter(&self, strategy: FilterStrategy) -> Result<Vec<u8>, &'static str> {
+        let mut out = vec![];
+
+        for line_num in 0..self.scanlines.len() {
+            let mut line = match strategy {
+                FilterStrategy::Fixed(filter) => self.filter_line(line_num, filter)?,
+                FilterStrategy::MinSum => (0..5)
+                    .map(|filter| self.filter_line(line_num, filter))
+                    .collect::<Result<Vec<Vec<u8>>, &'static str>>()?
+                    .into_iter()
+                    .min_by_key(|x| x[1..].iter().map(|&y| (y as i8).unsigned_abs() as u32).sum::<u32>())
+                    .ok_or("Couldn't choose a filter for scanline!")?,
+                FilterStrategy::BruteForce => {
+                    let context = &out[out.len().saturating_sub(TRIAL_CONTEXT_LENGTH)..];
+                    let mut best : Option<(usize, Vec<u8>)> = None;
+
+                    for filter in 0..5 {
+                        let line = self.filter_line(line_num, filter)?;
+                        let trial = [context, &line].concat();
+                        let size = compress(&trial, CompressionLevel::Lazy)?.len();
+
+                        if best.as_ref().is_none_or(|(best_size, _)| size < *best_size) {
+                            best = Some((size, line));
+                        }
+                    }
+
+                    best.ok_or("Couldn't choose a filter for scanline!")?.1
+                },
+            };
+
+            out.append(&mut line);
+        }
+
+        Ok(out)
+    }
+
+    fn get_bytes_per_pixel(&self) -> usize {
+        match self.bit_depth / 8 {
+            0 => self.channels,
+            bytes_per_sample => self.channels * bytes_per_sample as usize,
+        }
+    }
+
+    /// Filters one scanline with the given filter type, which is written as its first byte.
+    fn filter_line(&self, line_num: usize, filter: u8) -> Result<Vec<u8>, &'static str> {
+        let bytes_per_pixel = self.get_bytes_per_pixel();
+        let line = &self.scanlines[line_num];
+        let empty_line = vec![0; line.len()];
+        let above = match line_num {
+            0 => &empty_line,
+            _ => &self.scanlines[line_num - 1],
+        };
+
+        let mut out = vec![filter];
+
+        for (index, &byte) in line.iter().enumerate() {
+            let left = match index < bytes_per_pixel {
+                true => 0,
+                false => line[index - bytes_per_pixel],
+            };
+            let up = above[index];
+            let up_left = match index < bytes_per_pixel {
+                true => 0,
+                false => above[index - bytes_per_pixel],
+            };
+
+            let prediction = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u32 + up as u32) / 2) as u8,
+                4 => Defilter::get_paeth_predictor(left as u32, up as u32, up_left as u32) as u8,
+                _ => return Err("INVALID FILTER TYPE!"),
+            };
+
+            out.push(byte.wrapping_sub(prediction));
+        }
+
+        Ok(out)
+    }
+}
+
+pub struct Defilter {
+    channels : usize,
+    bit_depth: u32,
+    scanlines : Vec<Vec<u8>>,
+    defiltered : Vec<Vec<u8>>,
 }
 
 impl Defilter {
@@ -664,38 +1795,69 @@ impl Defilter {
         let scanline_count = scanlines.len();
 
         Defilter {
-            channels : channels,
-            bit_depth : bit_depth,
-            scanlines : scanlines,
+            channels,
+            bit_depth,
+            scanlines,
             defiltered : vec![vec![]; scanline_count]
         }
     } 
 
     pub fn defilter(&mut self) -> Result<Vec<Vec<u8>>, &'static str> {
         for scanline_num in 0..self.scanlines.len() {
-            self.defilter_line(scanline_num);
+            self.defilter_line(scanline_num)?;
         } 
         Ok(self.defiltered.clone())
     }
 
-    fn defilter_line(&mut self, line_num: usize) {
-        let line = self.scanlines[line_num].clone();
-        let filter = line.get(0).unwrap_or(&5);
-        match filter {
-            0 => self.defilter_line_by_none(line_num),
-            1 => self.defilter_line_by_sub(line_num),
-            2 => self.defilter_line_by_up(line_num),
-            3 => self.defilter_line_by_ave(line_num),
-            4 => self.defilter_line_by_paeth(line_num),
-            _ => panic!("INVALID FILTER TYPE!")
+    fn defilter_line(&mut self, line_num: usize) -> Result<(), &'static str> {
+        let filter = *self.scanlines[line_num].first().ok_or("INVALID FILTER TYPE!")?;
+        let mut line = self.get_filterless_line(line_num);
+        let above : &[u8] = match line_num {
+            0 => &[],
+            _ => &self.defiltered[line_num - 1],
+        };
+
+        Defilter::defilter_row(filter, &mut line, above, self.get_bytes_per_pixel())?;
+        self.defiltered[line_num] = line;
+
+        Ok(())
+    }
+
+    /// Defilters a scanline, without its filter type, in place against the already defiltered
+    /// scanline above it. The first scanline of an image has nothing above it, so above is empty
+    /// and read as zeros.
+    pub fn defilter_row(filter: u8, line: &mut [u8], above: &[u8], bytes_per_pixel: usize) -> Result<(), &'static str> {
+        if filter > 4 {
+            return Err("INVALID FILTER TYPE!");
+        }
+
+        for index in 0..line.len() {
+            let left = match index < bytes_per_pixel {
+                true => 0,
+                false => line[index - bytes_per_pixel],
+            };
+            let up = above.get(index).copied().unwrap_or(0);
+            let up_left = match index < bytes_per_pixel {
+                true => 0,
+                false => above.get(index - bytes_per_pixel).copied().unwrap_or(0),
+            };
+
+            let prediction = match filter {
+                1 => left,
+                2 => up,
+                3 => ((left as u32 + up as u32) / 2) as u8,
+                4 => Defilter::get_paeth_predictor(left as u32, up as u32, up_left as u32) as u8,
+                _ => 0,
+            };
+
+            line[index] = line[index].wrapping_add(prediction);
         }
+
+        Ok(())
     }
     
     fn get_filterless_line(&self, line_num : usize) -> Vec<u8> {
-        self.scanlines[line_num].clone()
-            .into_iter()
-            .skip(1)
-            .collect()
+        self.scanlines[line_num][1..].to_vec()
     }
 
     fn get_bytes_per_sample(&self) -> usize {
@@ -709,293 +1871,737 @@ impl Defilter {
         self.channels * self.get_bytes_per_sample()
     }
 
-    fn get_left_pixel_bytes(&self, origin_row : usize, origin_column: usize) -> Vec<u8> {
-        let bytes_per_pixel = self.get_bytes_per_pixel();
-        let mut out = vec![];
+    fn get_paeth_predictor(left: u32, up: u32, upleft: u32) -> u32 {
+        let inital : i32 = left as i32 + up as i32 - upleft as i32;
+        let pred_left = (inital - left as i32).abs();
+        let pred_up = (inital - up as i32).abs();
+        let pred_upleft = (inital - upleft as i32).abs();
+
+        if pred_left <= pred_up && pred_left <= pred_upleft {
+            return left;
+        }
+        else if pred_up <= pred_upleft {
+            return up;
+        }
+        upleft
+    }
+}
+
+/// Scales a grayscale sample that is bit_depth bits long up to the full range of a u8.
+pub fn scale_gray_sample(sample: u32, bit_depth: u32) -> u8 {
+    (sample * 255 / ((1 << bit_depth) - 1)) as u8
+}
 
-        for i in origin_column..(origin_column + bytes_per_pixel) {
-            match i < bytes_per_pixel {
-                true => out.push(0),
-                false => out.push(self.defiltered[origin_row][i - bytes_per_pixel])   
+/// Splits defiltered scanlines into rows of image_width pixels.
+///
+/// Images with a bit depth of 1, 2 or 4 pack several samples into each byte, MSB first, and pad
+/// the end of every scanline to a whole byte. These samples are unpacked one by one - grayscale
+/// samples are scaled up to 8 bits while palette indices are left as they are.
+pub fn defiltered_to_pixels(defiltered_scanlines : &[Vec<u8>], png_color_type : usize, bit_depth : u32, image_width : usize) -> Result<Pixels, &'static str> {
+    let pixel_color_type = ColorType::from_png_color_type(png_color_type);
+    let mut pixels = Pixels::new();
+    
+    for (idx, scanline) in defiltered_scanlines.iter().enumerate() {
+        pixels.push(vec![]);
+
+        match bit_depth {
+            1 | 2 | 4 => {
+                let bit_count = scanline.len() * 8;
+                let mut samples = Bits::new(scanline.clone(), false, bit_count);
+
+                for _pixel in 0..image_width {
+                    let sample = match samples.read_bits(bit_depth) {
+                        Some(x) => x,
+                        None => return Err("Scanline is too short for the width of the image!"),
+                    };
+
+                    let value = match pixel_color_type {
+                        ColorType::GS => scale_gray_sample(sample, bit_depth),
+                        _ => sample as u8,
+                    };
+
+                    pixels[idx].push(Pixel::new(pixel_color_type, vec![value]));
+                }
+            },
+            16 => {
+                // 16 bit samples are stored as two big endian bytes.
+                for pixel_data in scanline.chunks(2 * pixel_color_type.to_channels()).take(image_width) {
+                    let samples = pixel_data
+                        .chunks(2)
+                        .map(|x| u16::from_be_bytes([x[0], x[1]]))
+                        .collect();
+
+                    pixels[idx].push(Pixel::with_bit_depth(pixel_color_type, 16, samples));
+                }
+            },
+            _ => {
+                for pixel_data in scanline.chunks(pixel_color_type.to_channels()).take(image_width) {
+                    pixels[idx].push(Pixel::new(pixel_color_type, pixel_data.to_vec()));
+                }
+            },
+        }
+    }
+
+    Ok(pixels)
+}
+
+/// Inverse of defiltered_to_pixels() for one scanline - packs samples that are bit_depth bits
+/// long into bytes. Samples of 1, 2 or 4 bits are packed MSB first with the last byte padded, and
+/// 16 bit samples are stored as two big endian bytes.
+pub fn samples_to_scanline(samples: &[u16], bit_depth: u32) -> Vec<u8> {
+    match bit_depth {
+        16 => samples.iter().flat_map(|x| x.to_be_bytes()).collect(),
+        8 => samples.iter().map(|&x| x as u8).collect(),
+        _ => {
+            let mut scanline = Bits::new(vec![], false, 0);
+
+            for &sample in samples {
+                scanline.write_bits(sample as u32, bit_depth);
             }
+
+            scanline.into_bytes()
+        },
+    }
+}
+
+
+fn generate_dct_matrix() -> Vec<Vec<f64>> {
+    let mut dct_matrix : Vec<Vec<f64>> = vec![vec![0.0; 8]; 8];
+    
+    for (i, row) in dct_matrix.iter_mut().enumerate() {
+        for (j, elem) in row.iter_mut().enumerate() {
+            let coeff : f64 = (i as f64) * std::f64::consts::PI * (2.0 * (j as f64) + 1.0) / 16.0;
+            *elem = coeff.cos();
         }
+    }
+
+    dct_matrix
+}
 
-        out
+pub fn dct(block: Vec<Vec<u8>>) -> Vec<Vec<i32>> {
+    let zeroed_block : Vec<Vec<f64>> = block.subtract_amount(128);
+    let dct_matrix : Vec<Vec<f64>> = generate_dct_matrix();
+    let mut horizontal_block : Vec<Vec<f64>> = vec![];
+    let mut vertical_block = vec![];
+    let mut out_block : Vec<Vec<i32>> = vec![vec![0; 8]; 8];
+    
+    //Horizontal:
+    for row in zeroed_block.iter() {
+        horizontal_block.push(dct_matrix.matrix_multiply(row));
     }
     
-    fn get_up_pixel_bytes(&self, origin_row : usize, origin_column: usize) -> Vec<u8> {
-        let bytes_per_pixel = self.get_bytes_per_pixel();
-        let mut out = vec![];
-        let row_above = match origin_row < 1 {
-            true => vec![0; bytes_per_pixel],
-            false => self.defiltered[origin_row - 1].clone()
-        };
+    //Vertical
+    for row in horizontal_block.transpose().iter() {
+        vertical_block.push(dct_matrix.matrix_multiply(row))
+    }
+    
+    vertical_block = vertical_block.transpose();
+    
+    //Normalize values for orthonormality - every coefficient is scaled by C(u) * C(v) / 4 where
+    //C(0) = 1 / sqrt(2) and C(n) = 1 otherwise.
+    let scale = |n : usize| match n {
+        0 => std::f64::consts::FRAC_1_SQRT_2,
+        _ => 1.0,
+    };
+
+    for (i, row) in vertical_block.iter().enumerate() {
+        for (j, elem) in row.iter().enumerate() {
+            out_block[i][j] = (elem * scale(i) * scale(j) / 4.0).round() as i32;
+        }
+    }
 
-        for i in origin_column..(origin_column + bytes_per_pixel) {
-            out.push(row_above[i]);
-        }    
+    out_block
+}
 
-        out
-   }
+/// Inverse of dct() - turns a block of dequantized coefficients back into samples, adding the
+/// 128 back and clamping them between 0 and 255.
+pub fn idct(block: Vec<Vec<i32>>) -> Vec<Vec<u8>> {
+    let idct_matrix : Vec<Vec<f64>> = generate_dct_matrix().transpose();
+    let mut horizontal_block : Vec<Vec<f64>> = vec![];
+    let mut vertical_block = vec![];
 
-    fn get_upper_left_pixel_bytes(&self, origin_row : usize, origin_column: usize) -> Vec<u8> {
-        let bytes_per_pixel = self.get_bytes_per_pixel();
-        let mut out = vec![];
-        let row_above = match origin_row < 1 {
-            true => vec![0; bytes_per_pixel],
-            false => self.defiltered[origin_row - 1].clone()
-        };
+    // The orthonormal scaling is undone before the transform instead of after it.
+    let scale = |n : usize| match n {
+        0 => std::f64::consts::FRAC_1_SQRT_2,
+        _ => 1.0,
+    };
+
+    let scaled_block : Vec<Vec<f64>> = block
+        .iter()
+        .enumerate()
+        .map(|(i, row)| row.iter().enumerate().map(|(j, &x)| x as f64 * scale(i) * scale(j) / 4.0).collect())
+        .collect();
+
+    //Horizontal:
+    for row in scaled_block.iter() {
+        horizontal_block.push(idct_matrix.matrix_multiply(row));
+    }
+
+    //Vertical
+    for row in horizontal_block.transpose().iter() {
+        vertical_block.push(idct_matrix.matrix_multiply(row));
+    }
+
+    vertical_block
+        .transpose()
+        .iter()
+        .map(|row| row.iter().map(|x| (x + 128.0).round().clamp(0.0, 255.0) as u8).collect())
+        .collect()
+}
+
+/// IJG "good" quality luminance quantization table.
+pub fn luma_quant_table() -> Vec<Vec<i32>> {
+    vec![
+        vec![16,  11,  10,  16,  24,  40,  51,  61],
+        vec![12,  12,  14,  19,  26,  58,  60,  55],
+        vec![14,  13,  16,  24,  40,  57,  69,  56],
+        vec![14,  17,  22,  29,  51,  87,  80,  62],
+        vec![18,  22,  37,  56,  68, 109, 103,  77],
+        vec![24,  35,  55,  64,  81, 104, 113,  92],
+        vec![49,  64,  78,  87, 103, 121, 120, 101],
+        vec![72,  92,  95,  98, 112, 100, 103,  99]
+    ]
+}
+
+/// IJG "good" quality chrominance quantization table.
+pub fn chrom_quant_table() -> Vec<Vec<i32>> {
+    vec![
+        vec![17,  18,  24,  47,  99,  99,  99,  99],
+        vec![18,  21,  26,  66,  99,  99,  99,  99],
+        vec![24,  26,  56,  99,  99,  99,  99,  99],
+        vec![47,  66,  99,  99,  99,  99,  99,  99],
+        vec![99,  99,  99,  99,  99,  99,  99,  99],
+        vec![99,  99,  99,  99,  99,  99,  99,  99],
+        vec![99,  99,  99,  99,  99,  99,  99,  99],
+        vec![99,  99,  99,  99,  99,  99,  99,  99]
+    ]
+}
+
+/// Divides every DCT coefficient in block by the matching entry of table, rounding to the nearest
+/// integer.
+pub fn quantize(block: Vec<Vec<i32>>, table: &[Vec<i32>]) -> Vec<Vec<i32>> {
+    block
+        .iter()
+        .zip(table.iter())
+        .map(|(block, quant)| 
+            block
+                .iter()
+                .zip(quant.iter())
+                .map(|(x,y)| (*x as f64 / *y as f64).round() as i32)
+                .collect()
+        )
+        .collect()
+}
+
+/// Quantizes a block with the base IJG luminance table, as the encoder does at quality 50.
+#[allow(dead_code)]
+pub fn quantize_luma(block: Vec<Vec<i32>>) -> Vec<Vec<i32>> {
+    quantize(block, &luma_quant_table())
+}
+
+/// Quantizes a block with the base IJG chrominance table, as the encoder does at quality 50.
+#[allow(dead_code)]
+pub fn quantize_chrom(block: Vec<Vec<i32>>) -> Vec<Vec<i32>> {
+    quantize(block, &chrom_quant_table())
+}
+
+/// Scales a quantization table by an IJG quality from 1 to 100. Quality 50 leaves the table as it
+/// is, lower qualities make every entry larger and higher qualities make them smaller. Entries are
+/// kept between 1 and 255 so they fit in an 8 bit DQT table.
+pub fn scale_quant_table(table: Vec<Vec<i32>>, quality: u32) -> Vec<Vec<i32>> {
+    let quality = quality.clamp(1, 100) as i32;
+    let scale = match quality < 50 {
+        true => 5000 / quality,
+        false => 200 - 2 * quality,
+    };
+
+    table
+        .into_iter()
+        .map(|row| row.into_iter().map(|x| ((x * scale + 50) / 100).clamp(1, 255)).collect())
+        .collect()
+}
+
+
+/// Position in an 8x8 block (row * 8 + column) of each coefficient in zig-zag order.
+const ZIG_ZAG_ORDER : [usize; 64] = [
+     0, 1, 8,16, 9, 2, 3,10,
+    17,24,32,25,18,11, 4, 5,
+    12,19,26,33,40,48,41,34,
+    27,20,13, 6, 7,14,21,28,
+    35,42,49,56,57,50,43,36,
+    29,22,15,23,30,37,44,51,
+    58,59,52,45,38,31,39,46,
+    53,60,61,54,47,55,62,63,
+];
+
+pub fn zig_zag<T : Copy>(matrix : Vec<Vec<T>>) -> Vec<T> {
+    let mut zig_vec : Vec<T> = vec![]; 
+
+    for index in ZIG_ZAG_ORDER {
+        zig_vec.push(matrix[index / 8][index % 8]);
+    }
+
+    zig_vec
+}
+
+/// Inverse of zig_zag() - puts 64 values in zig-zag order back into an 8x8 block.
+pub fn un_zig_zag<T : Copy + Default>(zig_vec : &[T]) -> Vec<Vec<T>> {
+    let mut matrix = vec![vec![T::default(); 8]; 8];
+
+    for (&index, &value) in ZIG_ZAG_ORDER.iter().zip(zig_vec) {
+        matrix[index / 8][index % 8] = value;
+    }
+
+    matrix
+}
+
+/// Returns the number of bits needed to store the magnitude of value - called its category (SSSS)
+/// in the JPEG specification.
+fn jpeg_category(value: i32) -> u32 {
+    32 - value.unsigned_abs().leading_zeros()
+}
+
+/// Returns the bits that are written after a category to give the exact value. Negative values
+/// are stored as value - 1 in the low category bits, so they always begin with a 0 bit.
+fn jpeg_amplitude(value: i32, category: u32) -> u32 {
+    match value < 0 {
+        true => (value - 1) as u32 & ((1 << category) - 1),
+        false => value as u32,
+    }
+}
+
+/// Inverse of jpeg_amplitude() - returns the value that amplitude stands for in a category.
+/// Amplitudes that begin with a 0 bit are negative.
+pub fn jpeg_value(amplitude: u32, category: u32) -> i32 {
+    match category {
+        0 => 0,
+        _ if amplitude < 1 << (category - 1) => amplitude as i32 - (1 << category) + 1,
+        _ => amplitude as i32,
+    }
+}
+
+/// A huffman coded symbol of a JPEG scan followed by the extra bits that give the exact value of
+/// the coefficient it describes.
+#[derive(Debug, PartialEq)]
+pub struct JpegSymbol {
+    pub symbol : u8,
+    pub amplitude : u32,
+    pub amplitude_length : u32,
+}
+
+impl JpegSymbol {
+    fn new(symbol: u8, value: i32, category: u32) -> JpegSymbol {
+        JpegSymbol {
+            symbol,
+            amplitude : jpeg_amplitude(value, category),
+            amplitude_length : category,
+        }
+    }
+
+    /// Returns the symbol for a difference between two DC coefficients.
+    pub fn dc(diff: i32) -> JpegSymbol {
+        let category = jpeg_category(diff);
+        JpegSymbol::new(category as u8, diff, category)
+    }
+}
+
+/// One piece of a progressive JPEG scan.
+#[derive(Debug, PartialEq)]
+pub enum ScanCode {
+    /// A symbol coded with the DC table of the component.
+    Dc(JpegSymbol),
+    /// A symbol coded with the AC table of the component.
+    Ac(JpegSymbol),
+    /// Bits that are written as they are, such as refinement bits.
+    Bits(u32, u32),
+}
+
+/// Codes the AC coefficients of the blocks of one progressive scan, which covers the zig-zag
+/// coefficients from spectral_start to spectral_end. Coefficients are divided by
+/// 2 ^ approx_low, and a scan with a non zero approx_high refines coefficients that earlier scans
+/// sent with approx_low = approx_high.
+///
+/// Blocks that end in zeros are gathered into end of block runs that span several blocks, as in
+/// Annex G.1.2.2 of the JPEG specification. The typical huffman tables only have a code for a run
+/// of one block, so max_eob_run should be 1 when they are used.
+pub struct ProgressiveAcCoder {
+    spectral_start : usize,
+    spectral_end : usize,
+    approx_high : u32,
+    approx_low : u32,
+    max_eob_run : u32,
+    eob_run : u32,
+
+    /// Refinement bits of the blocks in the current end of block run. They are written after the
+    /// run itself.
+    eob_corrections : Vec<u32>,
+}
+
+impl ProgressiveAcCoder {
+    pub fn new(spectral_start: usize, spectral_end: usize, approx_high: u32, approx_low: u32, max_eob_run: u32) -> ProgressiveAcCoder {
+        ProgressiveAcCoder {
+            spectral_start,
+            spectral_end,
+            approx_high,
+            approx_low,
+            max_eob_run : max_eob_run.clamp(1, 0x7FFF),
+            eob_run : 0,
+            eob_corrections : vec![],
+        }
+    }
+
+    /// Codes one block of quantized, zig-zag ordered coefficients into codes.
+    pub fn code_block(&mut self, zig_zag: &[i32], codes: &mut Vec<ScanCode>) {
+        match self.approx_high {
+            0 => self.code_block_first(zig_zag, codes),
+            _ => self.code_block_refinement(zig_zag, codes),
+        }
+    }
+
+    /// Writes out the end of block run that is still open, which has to be done at the end of
+    /// every scan.
+    pub fn finish(&mut self, codes: &mut Vec<ScanCode>) {
+        if self.eob_run == 0 {
+            return;
+        }
+
+        // EOBn codes a run of 2 ^ n to 2 ^ (n + 1) - 1 blocks, with the low n bits of the run
+        // following the symbol.
+        let length = 31 - self.eob_run.leading_zeros();
+        codes.push(ScanCode::Ac(JpegSymbol {
+            symbol : (length << 4) as u8,
+            amplitude : self.eob_run & ((1 << length) - 1),
+            amplitude_length : length,
+        }));
+
+        codes.extend(self.eob_corrections.drain(..).map(|x| ScanCode::Bits(x, 1)));
+        self.eob_run = 0;
+    }
+
+    fn end_block_in_run(&mut self, codes: &mut Vec<ScanCode>) {
+        self.eob_run += 1;
+
+        if self.eob_run == self.max_eob_run {
+            self.finish(codes);
+        }
+    }
+
+    fn code_block_first(&mut self, zig_zag: &[i32], codes: &mut Vec<ScanCode>) {
+        let mut zero_run = 0u32;
+
+        for &coefficient in &zig_zag[self.spectral_start..=self.spectral_end] {
+            // Coefficients are divided by rounding towards zero, unlike the DC coefficient.
+            let value = coefficient.signum() * (coefficient.abs() >> self.approx_low);
+
+            if value == 0 {
+                zero_run += 1;
+                continue;
+            }
+
+            self.finish(codes);
+
+            while zero_run > 15 {
+                codes.push(ScanCode::Ac(JpegSymbol::new(0xF0, 0, 0)));
+                zero_run -= 16;
+            }
+
+            let category = jpeg_category(value);
+            codes.push(ScanCode::Ac(JpegSymbol::new(((zero_run << 4) + category) as u8, value, category)));
+            zero_run = 0;
+        }
+
+        if zero_run > 0 {
+            self.end_block_in_run(codes);
+        }
+    }
+
+    fn code_block_refinement(&mut self, zig_zag: &[i32], codes: &mut Vec<ScanCode>) {
+        let band = &zig_zag[self.spectral_start..=self.spectral_end];
+        let values : Vec<u32> = band.iter().map(|x| x.unsigned_abs() >> self.approx_low).collect();
+
+        // Coefficients with a value of 1 become non zero in this scan - the rest of the non zero
+        // coefficients already were and only get one more bit.
+        let last_new = values.iter().rposition(|&x| x == 1);
+        let mut zero_run = 0u32;
+        let mut corrections : Vec<u32> = vec![];
+
+        for (index, &value) in values.iter().enumerate() {
+            if value == 0 {
+                zero_run += 1;
+                continue;
+            }
+
+            // Runs of zeros are only split with ZRL while there are new coefficients left to
+            // code. Past the last one the zeros become part of the end of block run.
+            while zero_run > 15 && last_new.is_some_and(|x| index <= x) {
+                self.finish(codes);
+                codes.push(ScanCode::Ac(JpegSymbol::new(0xF0, 0, 0)));
+                codes.extend(corrections.drain(..).map(|x| ScanCode::Bits(x, 1)));
+                zero_run -= 16;
+            }
+
+            if value > 1 {
+                corrections.push(value & 1);
+                continue;
+            }
+
+            self.finish(codes);
+            codes.push(ScanCode::Ac(JpegSymbol {
+                symbol : ((zero_run << 4) + 1) as u8,
+                amplitude : (band[index] > 0) as u32,
+                amplitude_length : 1,
+            }));
+            codes.extend(corrections.drain(..).map(|x| ScanCode::Bits(x, 1)));
+            zero_run = 0;
+        }
+
+        if zero_run > 0 || !corrections.is_empty() {
+            self.eob_corrections.append(&mut corrections);
+            self.end_block_in_run(codes);
+        }
+    }
+}
+
+/// Inverse of ProgressiveAcCoder - reads the AC coefficients of the blocks of one progressive
+/// scan, or of one restart interval of it, into blocks that earlier scans have already filled in.
+pub struct ProgressiveAcDecoder {
+    spectral_start : usize,
+    spectral_end : usize,
+    approx_high : u32,
+    approx_low : u32,
+
+    /// Number of blocks left in the current end of band run, which have nothing coded for them
+    /// except refinement bits.
+    eob_run : u32,
+}
 
-        for i in origin_column..(origin_column + bytes_per_pixel) {
-            match i < bytes_per_pixel {
-                true => out.push(0),
-                false => out.push(row_above[i - bytes_per_pixel])   
-            }
+impl ProgressiveAcDecoder {
+    pub fn new(spectral_start: usize, spectral_end: usize, approx_high: u32, approx_low: u32) -> ProgressiveAcDecoder {
+        ProgressiveAcDecoder {
+            spectral_start,
+            spectral_end,
+            approx_high,
+            approx_low,
+            eob_run : 0,
         }
-
-        out
     }
 
-    fn defilter_line_by_none(&mut self, line_num : usize) {
-        let mut line = self.get_filterless_line(line_num);
-        self.defiltered[line_num].append(&mut line);
+    /// Reads the next block of the scan from stream into zig_zag, which holds the block's
+    /// coefficients in zig-zag order.
+    pub fn read_block(&mut self, stream: &mut Bits, ac_huff: &Huffman, zig_zag: &mut [i32]) -> Result<(), &'static str> {
+        match self.approx_high {
+            0 => self.read_block_first(stream, ac_huff, zig_zag),
+            _ => self.read_block_refinement(stream, ac_huff, zig_zag),
+        }
     }
 
-    fn defilter_line_by_sub(&mut self, line_num : usize) {
-        let line = self.get_filterless_line(line_num);
-        let bytes_per_pixel = self.get_bytes_per_pixel();
+    /// Reads the length of an end of band run from its EOBn symbol, counting the current block.
+    fn read_eob_run(stream: &mut Bits, length: u32) -> Result<u32, &'static str> {
+        let extra_bits = stream.read_bits(length).ok_or("JPEG scan ended in the middle of a block!")?;
+        Ok((1 << length) + extra_bits)
+    }
 
-        for (index, filtered_pixel_bytes) in line.chunks(bytes_per_pixel).enumerate() {
-            let left_pixel_bytes = self.get_left_pixel_bytes(line_num, index * bytes_per_pixel);
-            let mut defiltered_pixel_bytes : Vec<u8> = left_pixel_bytes
-                .into_iter()
-                .zip(filtered_pixel_bytes.into_iter())
-                .map(|(left, filtered)| ((*filtered as u32 + left as u32) % 256) as u8)
-                .collect();
-            
-            self.defiltered[line_num].append(&mut defiltered_pixel_bytes);
+    fn read_block_first(&mut self, stream: &mut Bits, ac_huff: &Huffman, zig_zag: &mut [i32]) -> Result<(), &'static str> {
+        if self.eob_run > 0 {
+            self.eob_run -= 1;
+            return Ok(());
         }
-    }
 
-    fn defilter_line_by_up(&mut self, line_num : usize) {
-        let line = self.get_filterless_line(line_num);
-        let bytes_per_pixel = self.get_bytes_per_pixel();
+        let mut index = self.spectral_start;
 
-        for (index, filtered_pixel_bytes) in line.chunks(bytes_per_pixel).enumerate() { 
-            let up_pixel_bytes = self.get_up_pixel_bytes(line_num, index * bytes_per_pixel);
-            let mut defiltered_pixel_bytes : Vec<u8> = up_pixel_bytes
-                .into_iter()
-                .zip(filtered_pixel_bytes.into_iter())
-                .map(|(up, filtered)| ((*filtered as u32 + up as u32) % 256) as u8)
-                .collect();
-            
-            self.defiltered[line_num].append(&mut defiltered_pixel_bytes); 
-        }
-    }
+        while index <= self.spectral_end {
+            let symbol = ac_huff.read_one_code(stream)?;
+            let (zero_run, category) = (symbol >> 4, symbol & 0x0F);
 
-    fn defilter_line_by_ave(&mut self, line_num : usize) {
-        let line = self.get_filterless_line(line_num);
-        let bytes_per_pixel = self.get_bytes_per_pixel();
+            match (zero_run, category) {
+                (15, 0) => {
+                    index += 16;
+                    continue;
+                },
+                (_, 0) => {
+                    self.eob_run = Self::read_eob_run(stream, zero_run)? - 1;
+                    break;
+                },
+                _ => (),
+            };
 
-        for (index, filtered_pixel_bytes) in line.chunks(bytes_per_pixel).enumerate() {
-            let left_pixel_bytes = self.get_left_pixel_bytes(line_num, index * bytes_per_pixel);
-            let up_pixel_bytes = self.get_up_pixel_bytes(line_num, index * bytes_per_pixel);
-            let mut defiltered_pixel_bytes : Vec<u8> = up_pixel_bytes
-                .into_iter()
-                .zip(left_pixel_bytes.into_iter())
-                .map(|(up, left)| ((up as f64 + left as f64) / 2.0).floor())
-                .zip(filtered_pixel_bytes.into_iter())
-                .map(|(ave, filtered)|  ((*filtered as u32 + ave as u32) % 256) as u8)
-                .collect();
+            index += zero_run as usize;
+            if index > self.spectral_end {
+                return Err("JPEG block has coefficients outside of its scan's band!");
+            }
 
-            self.defiltered[line_num].append(&mut defiltered_pixel_bytes);
+            let amplitude = stream.read_bits(category).ok_or("JPEG scan ended in the middle of a block!")?;
+            zig_zag[index] = jpeg_value(amplitude, category) * (1 << self.approx_low);
+            index += 1;
         }
+
+        Ok(())
     }
 
-    fn defilter_line_by_paeth(&mut self, line_num : usize) {
-        let line = self.get_filterless_line(line_num);
-        let bytes_per_pixel = self.get_bytes_per_pixel();
+    fn read_block_refinement(&mut self, stream: &mut Bits, ac_huff: &Huffman, zig_zag: &mut [i32]) -> Result<(), &'static str> {
+        let bit = 1 << self.approx_low;
+        let mut index = self.spectral_start;
 
-        for (index, filtered_pixel_bytes) in line.chunks(bytes_per_pixel).enumerate() { 
-            let left_pixel_bytes = self.get_left_pixel_bytes(line_num, index * bytes_per_pixel);
-            let up_pixel_bytes = self.get_up_pixel_bytes(line_num, index * bytes_per_pixel);
-            let upper_left_pixel_bytes = self.get_upper_left_pixel_bytes(line_num, index * bytes_per_pixel);
-            let mut defiltered_pixel_bytes : Vec<u8> = left_pixel_bytes
-                .into_iter()
-                .zip(up_pixel_bytes.into_iter())
-                .zip(upper_left_pixel_bytes.into_iter())
-                .map(|((left, up), up_left)| Self::get_paeth_predictor(left as u32, up as u32, up_left as u32))
-                .zip(filtered_pixel_bytes.into_iter())
-                .map(|(paeth, filtered)|  ((*filtered as u32 + paeth as u32) % 256) as u8)
-                .collect();
+        // Coefficients that are already non zero get one more bit, in the order they are passed
+        // over.
+        let refine = |stream: &mut Bits, coefficient: &mut i32| -> Result<(), &'static str> {
+            let correction = stream.read_bits(1).ok_or("JPEG scan ended in the middle of a block!")?;
 
-            self.defiltered[line_num].append(&mut defiltered_pixel_bytes);
-        }
-    }
+            if correction == 1 && *coefficient & bit == 0 {
+                *coefficient += coefficient.signum() * bit;
+            }
 
-    fn get_paeth_predictor(left: u32, up: u32, upleft: u32) -> u32 {
-        let inital : i32 = left as i32 + up as i32 - upleft as i32;
-        let pred_left = (inital - left as i32).abs();
-        let pred_up = (inital - up as i32).abs();
-        let pred_upleft = (inital - upleft as i32).abs();
+            Ok(())
+        };
 
-        if pred_left <= pred_up && pred_left <= pred_upleft {
-            return left as u32;
-        }
-        else if pred_up <= pred_upleft {
-            return up as u32;
+        if self.eob_run == 0 {
+            while index <= self.spectral_end {
+                let symbol = ac_huff.read_one_code(stream)?;
+                let (mut zero_run, category) = (symbol >> 4, symbol & 0x0F);
+
+                // New coefficients are always 1 or -1 before they are scaled.
+                let value = match (zero_run, category) {
+                    (_, 1) => match stream.read_bits(1).ok_or("JPEG scan ended in the middle of a block!")? {
+                        1 => bit,
+                        _ => -bit,
+                    },
+                    (15, 0) => 0,
+                    (_, 0) => {
+                        self.eob_run = Self::read_eob_run(stream, zero_run)?;
+                        break;
+                    },
+                    _ => return Err("JPEG refinement scan has a coefficient larger than 1!"),
+                };
+
+                // Skips zero_run zero coefficients, refining the non zero ones in between, and
+                // stops on the coefficient after them.
+                while index <= self.spectral_end {
+                    match zig_zag[index] {
+                        0 if zero_run == 0 => break,
+                        0 => zero_run -= 1,
+                        _ => refine(stream, &mut zig_zag[index])?,
+                    };
+
+                    index += 1;
+                }
+
+                if value != 0 {
+                    if index > self.spectral_end {
+                        return Err("JPEG block has coefficients outside of its scan's band!");
+                    }
+
+                    zig_zag[index] = value;
+                }
+
+                index += 1;
+            }
         }
-        upleft as u32
-    }
-}
 
-pub fn defiltered_to_pixels(defiltered_scanlines : Vec<Vec<u8>>, png_color_type : usize) -> Pixels {
-    let pixel_color_type = ColorType::from_png_color_type(png_color_type);
-    let mut pixels = Pixels::new();
-    
-    for (idx, scanline) in defiltered_scanlines.into_iter().enumerate() {
-        pixels.push(vec![]);
-        for pixel_data in scanline.chunks(pixel_color_type.to_channels())  {
-            pixels[idx].push(Pixel::new(pixel_color_type, pixel_data.to_vec()));
+        // The rest of a block in an end of band run only has refinement bits.
+        if self.eob_run > 0 {
+            for coefficient in zig_zag[index.min(self.spectral_end + 1)..=self.spectral_end].iter_mut() {
+                if *coefficient != 0 {
+                    refine(stream, coefficient)?;
+                }
+            }
+
+            self.eob_run -= 1;
         }
-    }
 
-    pixels
+        Ok(())
+    }
 }
 
-
-//TODO: using i32s for the DCT-2 Matrix could cause large errors because sin is in [0,1].
-fn generate_dct_matrix() -> Vec<Vec<f64>> {
-    let mut dct_matrix : Vec<Vec<f64>> = vec![vec![0.0; 8]; 8];
-    
-    for i in 0..8 {
-        for j in 0..8 {
-            let coeff : f64= (i as f64) * 3.14 * (2.0 * (j as f64) + 1.0) / 16.0;
-            dct_matrix[i][j] = coeff.cos();
+/// Turns one block of quantized, zig-zag ordered coefficients into the symbols that are huffman
+/// coded. The first symbol is always the DC symbol and the rest are AC symbols.
+///
+/// The DC coefficient is coded as the difference from prev_dc - the DC coefficient of the
+/// previous block of the same component - with its category as the symbol. The AC coefficients
+/// are coded as (zero run, category) symbols, with 0xF0 marking a run of 16 zeros and 0x00
+/// ending the block early.
+pub fn jpeg_block_symbols(zig_zag: &[i32], prev_dc: i32) -> Vec<JpegSymbol> {
+    let mut symbols = vec![JpegSymbol::dc(zig_zag[0] - prev_dc)];
+
+    let mut zero_run = 0u32;
+
+    for &coefficient in &zig_zag[1..] {
+        if coefficient == 0 {
+            zero_run += 1;
+            continue;
         }
-    }
 
-    dct_matrix
-}
+        while zero_run > 15 {
+            symbols.push(JpegSymbol::new(0xF0, 0, 0));
+            zero_run -= 16;
+        }
 
-pub fn dct(block: Vec<Vec<u8>>) -> Vec<Vec<i32>> {
-    let zeroed_block : Vec<Vec<f64>> = block.subtract_amount(128);
-    let dct_matrix : Vec<Vec<f64>> = generate_dct_matrix();
-    let mut horizontal_block : Vec<Vec<f64>> = vec![];
-    let mut vertical_block = vec![];
-    let mut out_block : Vec<Vec<i32>> = vec![vec![0; 8]; 8];
-    
-    //Horizontal:
-    for row in zeroed_block.iter() {
-        horizontal_block.push(dct_matrix.matrix_multiply(row));
-    }
-    
-    //Vertical
-    for row in horizontal_block.transpose().iter() {
-        vertical_block.push(dct_matrix.matrix_multiply(row))
+        let category = jpeg_category(coefficient);
+        symbols.push(JpegSymbol::new(((zero_run << 4) + category) as u8, coefficient, category));
+        zero_run = 0;
     }
-    
-    vertical_block = vertical_block.transpose();
-    
-    //Normalize values for orthnormality.
-    for i in 0..vertical_block.len() {
-        for j in 0..vertical_block[i].len() {
-            if (i,j) == (0,0) {
-                out_block[i][j] = (vertical_block[i][j] / 8.0) as i32;
-            }
-            else {
-                out_block[i][j] = (vertical_block[i][j] / 4.0) as i32;
-            }
-        }
+
+    if zero_run > 0 {
+        symbols.push(JpegSymbol::new(0x00, 0, 0));
     }
 
-    out_block
+    symbols
 }
 
-pub fn quantize_luma(block: Vec<Vec<i32>>) -> Vec<Vec<i32>> {
-    // IJG "good" quality values. 
-    let table : Vec<Vec<i32>> = vec![
-        vec![16,  11,  10,  16,  24,  40,  51,  61],
-        vec![12,  12,  14,  19,  26,  58,  60,  55],
-        vec![14,  13,  16,  24,  40,  57,  69,  56],
-        vec![14,  17,  22,  29,  51,  87,  80,  62],
-        vec![18,  22,  37,  56,  68, 109, 103,  77],
-        vec![24,  35,  55,  64,  81, 104, 113,  92],
-        vec![49,  64,  78,  87, 103, 121, 120, 101],
-        vec![72,  92,  95,  98, 112, 100, 103,  99]
-    ];
+/// Entropy codes the symbols of one block, made by jpeg_block_symbols(), into stream - using
+/// dc_huff for the DC symbol and ac_huff for the AC symbols.
+pub fn create_jpeg_stream(symbols: &[JpegSymbol], dc_huff: &Huffman, ac_huff: &Huffman, stream: &mut Bits) -> Result<(), &'static str> {
+    for (index, symbol) in symbols.iter().enumerate() {
+        let huffman = match index {
+            0 => dc_huff,
+            _ => ac_huff,
+        };
 
-    block
-        .iter()
-        .zip(table.iter())
-        .map(|(block, quant)| 
-            block
-                .iter()
-                .zip(quant.iter())
-                .map(|(x,y)| x / y)
-                .collect()
-        )
-        .collect()
+        huffman.write_one_code(symbol.symbol as u32, stream)?;
+        stream.write_bits(symbol.amplitude, symbol.amplitude_length);
+    }
+
+    Ok(())
 }
 
-pub fn quantize_chrom(block: Vec<Vec<i32>>) -> Vec<Vec<i32>> {
-    // IJG "good" quality values. 
-    let table : Vec<Vec<i32>> = vec![
-        vec![16,  18,  24,  47,  99,  99,  99,  99],
-        vec![18,  21,  26,  66,  99,  99,  99,  99],
-        vec![24,  26,  56,  99,  99,  99,  99,  99],
-        vec![47,  66,  99,  99,  99,  99,  99,  99],
-        vec![99,  99,  99,  99,  99,  99,  99,  99],
-        vec![99,  99,  99,  99,  99,  99,  99,  99],
-        vec![99,  99,  99,  99,  99,  99,  99,  99],
-        vec![99,  99,  99,  99,  99,  99,  99,  99]
-    ];
+/// Inverse of create_jpeg_stream() - reads one block of a baseline scan from stream and returns
+/// its 64 coefficients in zig-zag order. The DC coefficient is coded as a difference from
+/// prev_dc, the DC coefficient of the previous block of the same component.
+pub fn read_jpeg_block(stream: &mut Bits, dc_huff: &Huffman, ac_huff: &Huffman, prev_dc: i32) -> Result<Vec<i32>, &'static str> {
+    let mut zig_zag = vec![0i32; 64];
 
-    block
-        .iter()
-        .zip(table.iter())
-        .map(|(block, quant)| 
-            block
-                .iter()
-                .zip(quant.iter())
-                .map(|(x,y)| x / y)
-                .collect()
-        )
-        .collect()
-}
+    let category = dc_huff.read_one_code(stream)?;
+    if category > 11 {
+        return Err("JPEG DC difference category is too large!");
+    }
 
+    let amplitude = stream.read_bits(category).ok_or("JPEG scan ended in the middle of a block!")?;
+    zig_zag[0] = prev_dc + jpeg_value(amplitude, category);
 
-pub fn zig_zag<T : Copy>(matrix : Vec<Vec<T>>) -> Vec<T> {
-    let mut zig_vec : Vec<T> = vec![]; 
+    let mut index = 1;
+    while index < 64 {
+        let symbol = ac_huff.read_one_code(stream)?;
+        let (zero_run, category) = ((symbol >> 4) as usize, symbol & 0x0F);
 
-    let index_matrix : Vec<Vec<usize>> = vec![
-        vec![0, 1, 8,16, 9, 2, 3,10],
-        vec![17,24,32,25,18,11, 4, 5],
-        vec![12,19,26,33,40,48,41,34],
-        vec![27,20,13, 6, 7,14,21,28],
-        vec![35,42,49,56,57,50,43,36],
-        vec![29,22,15,23,30,37,44,51],
-        vec![58,59,52,45,38,31,39,46],
-        vec![53,60,61,54,47,55,62,63],
-    ];
+        match (zero_run, category) {
+            (0, 0) => break,
+            (15, 0) => {
+                index += 16;
+                continue;
+            },
+            _ => (),
+        };
 
-    for row in 0..8 {
-        for col in 0..8 {
-            let index = index_matrix[row][col];
-            zig_vec.push(matrix[index / 8][index % 8]);
+        index += zero_run;
+        if index > 63 {
+            return Err("JPEG block has more than 64 coefficients!");
         }
-    }
 
-    zig_vec
-}
-
-pub fn create_jpeg_stream(zig_zag: Vec<i32>, prev_dc : i32) -> Vec<u8> {
-    //make into symbols of triples : 
-    //
-    //
-    //encode
+        let amplitude = stream.read_bits(category).ok_or("JPEG scan ended in the middle of a block!")?;
+        zig_zag[index] = jpeg_value(amplitude, category);
+        index += 1;
+    }
 
-    vec![]
+    Ok(zig_zag)
 }
 
 
@@ -1029,7 +2635,7 @@ impl Transpose<f64> for Vec<Vec<f64>> {
 }
 
 impl MatrixMultiply<f64> for Vec<Vec<f64>> {
-    fn matrix_multiply(&self, other: &Vec<f64>) -> Vec<f64> {
+    fn matrix_multiply(&self, other: &[f64]) -> Vec<f64> {
         self.iter()
             .map(|x| Self::dot_product(x, other))
             .collect()
@@ -1041,12 +2647,12 @@ trait SubtractAmount<T> {
 }
 
 trait MatrixMultiply<T : Mul<Output = T> + Add<Output = T> +  From<u8> + Copy> {
-    fn dot_product(left: &Vec<T>, right: &Vec<T>) -> T{
-        left.into_iter()
-            .zip(right.into_iter())
+    fn dot_product(left: &[T], right: &[T]) -> T{
+        left.iter()
+            .zip(right)
             .fold(T::from(0), |dot_product, (x,y)| dot_product + (*x) * (*y))
     }
-    fn matrix_multiply(&self, other: &Vec<T>) -> Vec<T>;
+    fn matrix_multiply(&self, other: &[T]) -> Vec<T>;
 }
 
 trait Transpose<T> {
@@ -1058,6 +2664,188 @@ mod tests {
     use super::*;
     
     #[test]
+    fn check_crc() {
+        assert_eq!(png_crc(b"123456789"), 0xCBF43926);
+        assert_eq!(png_crc(b"IEND"), 0xAE426082);
+        assert_eq!(png_crc(&[]), 0);
+
+        // Any split of the bytes gives the same checksum as all of them at once.
+        let bytes : Vec<u8> = (0..1000u32).map(|x| (x * x % 256) as u8).collect();
+        for split in [0, 3, 8, 13, 999] {
+            let mut crc = Crc32::new();
+            crc.update(&bytes[..split]);
+            crc.update(&bytes[split..]);
+            assert_eq!(u32::from_be_bytes(crc.finalize()), png_crc(&bytes));
+        }
+    }
+
+    #[test]
+    fn check_adler32() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+        assert_eq!(adler32(&[]), 1);
+        assert_eq!(adler32(&[0xFF; 100000]), 0x149A302C);
+    }
+
+    #[test]
+    fn check_compress() {
+        let bytes : Vec<u8> = (0..70000u32).map(|x| (x * 7 % 251) as u8).collect();
+
+        for level in [CompressionLevel::Stored, CompressionLevel::Fixed, CompressionLevel::Dynamic, CompressionLevel::Lazy] {
+            assert_eq!(decompress(compress(&bytes, level).unwrap()), Ok(bytes.clone()), "{level:?}");
+            assert_eq!(decompress(compress(&[], level).unwrap()), Ok(vec![]), "{level:?}");
+        }
+
+        // Repetitive data should shrink, and the dynamic levels shouldn't do worse than fixed.
+        let text = b"the quick brown fox jumps over the lazy dog while the lazy dog sleeps. ".repeat(400);
+        let sizes : Vec<usize> = [CompressionLevel::Fixed, CompressionLevel::Dynamic, CompressionLevel::Lazy]
+            .iter()
+            .map(|&level| compress(&text, level).unwrap().len())
+            .collect();
+
+        assert!(sizes[0] < text.len() / 10);
+        assert!(sizes[1] <= sizes[0] && sizes[2] <= sizes[1]);
+        assert_eq!(decompress(compress(&text, CompressionLevel::Lazy).unwrap()), Ok(text));
+    }
+
+    #[test]
+    fn check_filter() {
+        // Two 3 pixel RGB scanlines - a gradient along the row, then the same row brightened
+        let scanlines = vec![vec![10, 20, 30, 12, 22, 32, 14, 24, 34], vec![20, 30, 40, 22, 32, 42, 24, 34, 44]];
+        let filter = Filter::new(3, 8, scanlines.clone());
+
+        assert_eq!(filter.filter_line(0, 1), Ok(vec![1, 10, 20, 30, 2, 2, 2, 2, 2, 2]));
+        assert_eq!(filter.filter_line(1, 2), Ok(vec![2, 10, 10, 10, 10, 10, 10, 10, 10, 10]));
+        assert_eq!(filter.filter_line(1, 3), Ok(vec![3, 15, 20, 25, 6, 6, 6, 6, 6, 6]));
+        assert_eq!(filter.filter_line(1, 4), Ok(vec![4, 10, 10, 10, 2, 2, 2, 2, 2, 2]));
+        assert!(filter.filter_line(0, 5).is_err());
+
+        // Sub wins the first line and Paeth the second on the sum of absolute differences
+        let filtered = filter.filter(FilterStrategy::MinSum).unwrap();
+        assert_eq!((filtered[0], filtered[10]), (1, 4));
+
+        for strategy in [FilterStrategy::Fixed(0), FilterStrategy::Fixed(4), FilterStrategy::MinSum, FilterStrategy::BruteForce] {
+            let filtered = filter.filter(strategy).unwrap();
+            let lines = filtered.chunks(10).map(|x| x.to_vec()).collect();
+            assert_eq!(Defilter::new(3, 8, lines).defilter(), Ok(scanlines.clone()), "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn check_zlib_validation() {
+        let stream = compress(b"checksummed", CompressionLevel::Fixed).unwrap();
+        assert_eq!(decompress(stream.clone()), Ok(b"checksummed".to_vec()));
+
+        let mut corrupt = stream.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(decompress(corrupt), Err("Adler-32 checksum of the decompressed data doesn't match the zlib stream!"));
+
+        let mut bad_check = stream.clone();
+        bad_check[1] ^= 1;
+        assert_eq!(decompress(bad_check), Err("Zlib header check bits are wrong!"));
+
+        // CM 7 and CINFO 8 with FCHECK fixed up so only the field itself is wrong
+        let mut bad_method = stream.clone();
+        bad_method[0] = 0x77;
+        bad_method[1] = 0x01 + (31 - (0x7701 % 31)) as u8;
+        assert_eq!(decompress(bad_method), Err("Zlib stream doesn't use the deflate compression method!"));
+
+        let mut bad_window = stream.clone();
+        bad_window[0] = 0x88;
+        bad_window[1] = 0x01 + (31 - (0x8801 % 31)) as u8;
+        assert_eq!(decompress(bad_window), Err("Zlib window size is larger than 32K!"));
+
+        assert!(decompress(vec![0x78, 0x01, 0x03]).is_err());
+    }
+
+    #[test]
+    fn check_preset_dictionary() {
+        // Compressed by zlib with the dictionary below, so most of it is copied from the dictionary
+        let stream = vec![120, 249, 119, 182, 14, 227, 195, 34, 4, 101, 1, 9, 0, 113, 100, 7, 131];
+        let dictionary = b"pngpeg preset dictionary: IHDR PLTE IDAT IEND";
+        let other : &[u8] = b"some other dictionary";
+
+        assert_eq!(decompress_with_dictionaries(stream.clone(), &[other, dictionary]), Ok(b"IHDR PLTE IDAT IEND IDAT IDAT".to_vec()));
+        assert_eq!(decompress_with_dictionaries(stream.clone(), &[other]), Err("Zlib stream needs a preset dictionary that wasn't given!"));
+        assert_eq!(decompress(stream), Err("Zlib stream needs a preset dictionary that wasn't given!"));
+    }
+
+    /// Gives its bytes one at a time, then fails with error if it has one.
+    struct TrickleSource {
+        bytes : Vec<u8>,
+        position : usize,
+        error : Option<&'static str>,
+    }
+
+    impl ByteSource for TrickleSource {
+        fn read_more(&mut self, buffer: &mut Vec<u8>) -> Result<usize, &'static str> {
+            match self.bytes.get(self.position) {
+                Some(&byte) => {
+                    buffer.push(byte);
+                    self.position += 1;
+                    Ok(1)
+                },
+                None => self.error.map_or(Ok(0), Err),
+            }
+        }
+    }
+
+    #[test]
+    fn check_inflater() {
+        // Longer than the window, so matches are copied from all around it
+        let bytes : Vec<u8> = (0..100000u32).map(|x| ((x / 7 % 251) ^ (x % 3)) as u8).collect();
+
+        for level in [CompressionLevel::Stored, CompressionLevel::Fixed, CompressionLevel::Lazy] {
+            let stream = compress(&bytes, level).unwrap();
+            let source = TrickleSource { bytes: stream, position: 0, error: None };
+            let mut inflater = Inflater::new(Bits::from_source(Box::new(source), true), &[]).unwrap();
+            let mut out = vec![];
+            let mut piece = [0u8; 1000];
+
+            loop {
+                let length = inflater.inflate(&mut piece).unwrap();
+                out.extend_from_slice(&piece[..length]);
+                if length < piece.len() {
+                    break;
+                }
+            }
+
+            assert_eq!(out, bytes, "{level:?}");
+            assert_eq!(inflater.inflate(&mut piece), Ok(0));
+        }
+
+        // Errors from the source come out in place of the stream looking too short
+        let mut stream = compress(&bytes, CompressionLevel::Lazy).unwrap();
+        stream.truncate(100);
+        let source = TrickleSource { bytes: stream, position: 0, error: Some("Source ran dry!") };
+        let mut inflater = Inflater::new(Bits::from_source(Box::new(source), true), &[]).unwrap();
+        assert_eq!(inflater.inflate(&mut vec![0u8; bytes.len()]), Err("Source ran dry!"));
+    }
+
+    #[test]
+    fn check_lz77() {
+        let bytes = b"abcabcabcabcx";
+        let symbols = lz77(bytes, CompressionLevel::Dynamic);
+
+        assert_eq!(symbols[..3], [Lz77Symbol::Literal(b'a'), Lz77Symbol::Literal(b'b'), Lz77Symbol::Literal(b'c')]);
+        assert_eq!(symbols[3], Lz77Symbol::Match(9, 3));
+        assert_eq!(symbols[4], Lz77Symbol::Literal(b'x'));
+
+        // Greedy matching takes "bcd" here, lazy matching waits a byte for "cdefg"
+        let bytes = b"bcdxcdefgyabcdefg";
+        let greedy = lz77(bytes, CompressionLevel::Dynamic);
+        let lazy = lz77(bytes, CompressionLevel::Lazy);
+        assert_eq!(greedy[11..], [Lz77Symbol::Match(3, 11), Lz77Symbol::Match(3, 8)]);
+        assert_eq!(lazy[11..], [Lz77Symbol::Literal(b'b'), Lz77Symbol::Match(5, 8)]);
+    }
+
+    #[test]
+    fn check_code_length_symbols() {
+        let lengths = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 8, 8, 8, 8, 8, 5, 0, 0];
+        assert_eq!(code_length_symbols(&lengths), vec![(18, 1, 7), (8, 0, 0), (16, 1, 2), (5, 0, 0), (0, 0, 0), (0, 0, 0)]);
+    }
+
+    #[test]
+    #[allow(clippy::needless_range_loop)]
     fn check_zig_zag() {
         let mut matrix : Vec<Vec<usize>> = vec![vec![0;8]; 8];
         let mut count : usize = 0;
@@ -1069,8 +2857,9 @@ mod tests {
             }
         }
 
-        let zig = zig_zag(matrix);
+        let zig = zig_zag(matrix.clone());
         assert_eq!(zig[2], 8);
+        assert_eq!(un_zig_zag(&zig), matrix);
     }
     
     #[test]
@@ -1079,22 +2868,53 @@ mod tests {
         assert_eq!(matrix.transpose()[0], vec![0.0, 3.0, 6.0])
     }
 
-    //#[test]
+    #[test]
     fn check_dct() {
-        let mut matrix : Vec<Vec<u8>> = vec![vec![0;8]; 8];
-        let mut count : u8 = 0;
+        let matrix : Vec<Vec<u8>> = vec![vec![0;8]; 8];
 
-        for row in 0..8 {
-            for col in 0..8 {
-                matrix[row][col] = count;
-                //count += 1;
+        // A flat block only has a DC coefficient - (0 - 128) * 8 = -1024.
+        let dct_matrix = dct(matrix);
+        assert_eq!(dct_matrix[0][0], -1024);
+        assert!(dct_matrix.iter().flatten().skip(1).all(|&x| x == 0));
+
+        assert_eq!(quantize_luma(dct_matrix.clone())[0][0], -64);
+        assert_eq!(quantize_chrom(dct_matrix.clone())[0][0], -60);
+        assert_eq!(quantize_luma(dct_matrix.clone()), quantize(dct_matrix, &scale_quant_table(luma_quant_table(), 50)));
+    }
+
+    #[test]
+    fn check_idct() {
+        let block : Vec<Vec<u8>> = (0..8)
+            .map(|row| (0..8).map(|col| ((row * 37 + col * col * 11) % 256) as u8).collect())
+            .collect();
+
+        // Coefficients are rounded to integers, so samples can be off by one after the round trip.
+        let samples = idct(dct(block.clone()));
+        for (row, expected) in samples.iter().zip(block.iter()) {
+            for (&x, &y) in row.iter().zip(expected.iter()) {
+                assert!(x.abs_diff(y) <= 1);
             }
         }
-        let subtracted = matrix.subtract_amount(5);
-        let dct_matrix = dct(matrix);
-        let quantized = quantize_luma(dct_matrix);
-        dbg!(quantized);
-        assert!(false)
+
+        assert_eq!(idct(vec![vec![0; 8]; 8]), vec![vec![128; 8]; 8]);
+    }
+
+    #[test]
+    fn check_scale_quant_table() {
+        assert_eq!(scale_quant_table(luma_quant_table(), 50), luma_quant_table());
+        assert_eq!(scale_quant_table(luma_quant_table(), 75)[0][..4], [8, 6, 5, 8]);
+        assert_eq!(scale_quant_table(luma_quant_table(), 10)[7][7], 255);
+        assert!(scale_quant_table(chrom_quant_table(), 100).iter().flatten().all(|&x| x == 1));
+    }
+
+    #[test]
+    fn check_sub_byte_unpacking() {
+        // Two 2 bit grayscale scanlines of 3 pixels each, padded to a byte.
+        let scanlines = vec![vec![0b00011011], vec![0b11100100]];
+        let pixels = defiltered_to_pixels(&scanlines, 0, 2, 3).unwrap();
+
+        let values : Vec<Vec<u8>> = pixels.iter().map(|row| row.iter().map(|x| x.get_color_values()[0]).collect()).collect();
+        assert_eq!(values, vec![vec![0, 85, 170], vec![255, 170, 85]]);
     }
 
     #[test]
@@ -1103,6 +2923,43 @@ mod tests {
         assert_eq!(dct_matrix[0], vec![1.0f64 ;8]);
     }
 
+    #[test]
+    fn check_bits_read() {
+        // Reads of every length, crossing the refills of the accumulator, in both bit orders
+        let bytes : Vec<u8> = (0..40u32).map(|x| (x * 37 + 11) as u8).collect();
+
+        for lsb in [true, false] {
+            let mut writer = Bits::new(vec![], lsb, 0);
+            let mut values = vec![];
+
+            for num in (1..=32u32).cycle().take(60) {
+                let value = (bytes.len() as u32 * num).wrapping_mul(0x9E3779B9) >> (32 - num);
+                writer.write_bits(value, num);
+                values.push((value, num));
+            }
+
+            let bit_count = writer.bit_count();
+            let mut reader = Bits::new(writer.into_bytes(), lsb, bit_count);
+            for &(value, num) in values.iter() {
+                assert_eq!(reader.peek_bits(num), value);
+                assert_eq!(reader.read_bits(num), Some(value));
+            }
+
+            assert_eq!(reader.len(), 0);
+            assert_eq!(reader.read_bits(1), None);
+        }
+
+        // Bits past the end are peeked as 0s, even when the last byte holds more.
+        let mut bits = Bits::new(vec![0b1011_1111], false, 3);
+        assert_eq!(bits.peek_bits(5), 0b10100);
+        assert_eq!(bits.read_bits_reversed(3), Some(0b101));
+
+        // The 0x00 after each 0xFF is skipped
+        let mut bits = Bits::new_unstuffed(vec![0x12, 0xFF, 0x00, 0xFF, 0x00, 0x34]);
+        assert_eq!(bits.len(), 32);
+        assert_eq!(bits.read_bits(32), Some(0x12FFFF34));
+    }
+
     #[test]
     fn check_bits_write() {
         let mut bits = Bits::new(vec![], false, 0);
@@ -1119,5 +2976,278 @@ mod tests {
         assert_eq!(0b110, res);
     }
 
+    #[test]
+    fn check_jpeg_block_coding() {
+        // Fixed length codes so every code is the symbol itself.
+        let luma_dc = Huffman::generate_from_length_symbols(vec![vec![], vec![], vec![], vec![], (0..12).collect()]).unwrap();
+        let luma_ac = Huffman::generate_from_length_symbols(vec![vec![], vec![], vec![], vec![], vec![], vec![], vec![], vec![], (0..=255).collect()]).unwrap();
+
+        let mut block = vec![0; 64];
+        block[0] = 5;
+        block[1] = -3;
+        block[20] = 1;
+
+        let mut stream = Bits::new(vec![], false, 0);
+        create_jpeg_stream(&jpeg_block_symbols(&block, 2), &luma_dc, &luma_ac, &mut stream).unwrap();
+
+        // DC diff 3 -> category 2, amplitude 0b11
+        assert_eq!(stream.read_bits(4), Some(2));
+        assert_eq!(stream.read_bits(2), Some(0b11));
+        // -3 -> run 0, category 2, amplitude 0b00
+        assert_eq!(stream.read_bits(8), Some(0x02));
+        assert_eq!(stream.read_bits(2), Some(0b00));
+        // 1 after 18 zeros -> ZRL, then run 2, category 1
+        assert_eq!(stream.read_bits(8), Some(0xF0));
+        assert_eq!(stream.read_bits(8), Some(0x21));
+        assert_eq!(stream.read_bits(1), Some(1));
+        // EOB
+        assert_eq!(stream.read_bits(8), Some(0x00));
+        assert_eq!(stream.len(), 0);
+    }
+
+    #[test]
+    fn check_code_lengths_from_frequencies() {
+        let lengths = Huffman::code_lengths_from_frequencies(&[10, 0, 1, 1, 2, 20], 16);
+        assert_eq!(lengths, vec![2, 0, 4, 4, 3, 1]);
+
+        assert_eq!(Huffman::code_lengths_from_frequencies(&[0, 5, 0], 16), vec![0, 1, 0]);
+
+        // Fibonacci frequencies make the deepest possible tree, 29 codes long here.
+        let mut fibonacci = vec![1u32, 1];
+        while fibonacci.len() < 30 {
+            fibonacci.push(fibonacci[fibonacci.len() - 1] + fibonacci[fibonacci.len() - 2]);
+        }
+
+        let lengths = Huffman::code_lengths_from_frequencies(&fibonacci, 16);
+        let kraft_sum : f64 = lengths.iter().map(|&x| 0.5f64.powi(x as i32)).sum();
+
+        assert_eq!(lengths.iter().max(), Some(&16));
+        assert_eq!(kraft_sum, 1.0);
+        assert!(lengths.windows(2).all(|x| x[0] >= x[1]));
+    }
+
+    #[test]
+    fn check_huffman_lookup() {
+        // Fibonacci frequencies give codes from 1 to 16 bits long, so most need the overflow table.
+        let mut fibonacci = vec![1u32, 1];
+        while fibonacci.len() < 30 {
+            fibonacci.push(fibonacci[fibonacci.len() - 1] + fibonacci[fibonacci.len() - 2]);
+        }
+
+        let huffman = huffman_from_lengths(&Huffman::code_lengths_from_frequencies(&fibonacci, 16)).unwrap();
+        let symbols : Vec<u32> = (0..30).rev().chain(0..30).collect();
+
+        // JPEG streams are read MSB first and DEFLATE streams LSB first
+        for lsb in [false, true] {
+            let mut stream = Bits::new(vec![], lsb, 0);
+            for &symbol in symbols.iter() {
+                huffman.write_one_code(symbol, &mut stream).unwrap();
+            }
+
+            let bit_count = stream.bit_count();
+            let mut stream = Bits::new(stream.into_bytes(), lsb, bit_count);
+            for &symbol in symbols.iter() {
+                assert_eq!(huffman.read_one_code(&mut stream), Ok(symbol));
+            }
+            assert_eq!(huffman.read_one_code(&mut stream), Err("Huffman code runs past the end of the stream!"));
+        }
+
+        // Two one bit codes leave no room for a third code.
+        assert!(huffman_from_lengths(&[1, 1, 0, 2]).is_err());
+
+        // The codes are 0 and 10, so 11 isn't the start of any code.
+        let huffman = huffman_from_lengths(&[1, 2]).unwrap();
+        assert_eq!(huffman.read_one_code(&mut Bits::new(vec![0xC0], false, 8)), Err("Stream holds a code that isn't in the huffman table!"));
+    }
+
+    #[test]
+    fn check_huffman_lookup_horse() {
+        // Codes for the bytes of the compressed image data in horse.png, which are spread out
+        // enough to give codes around 8 bits long - about as long as DEFLATE literal codes.
+        let png : crate::png::EncPng = std::fs::read("src/testimg/horse.png").unwrap().try_into().unwrap();
+        let bytes = png.get_deflate_stream();
+
+        let mut frequencies = vec![0u32; 256];
+        for &byte in bytes.iter() {
+            frequencies[byte as usize] += 1;
+        }
+
+        let huffman = huffman_from_lengths(&Huffman::code_lengths_from_frequencies(&frequencies, 15)).unwrap();
+        let mut stream = Bits::new(vec![], true, 0);
+        for &byte in bytes.iter() {
+            huffman.write_one_code(byte as u32, &mut stream).unwrap();
+        }
+
+        let bit_count = stream.bit_count();
+        let mut stream = Bits::new(stream.into_bytes(), true, bit_count);
+        let looked_up : Vec<u32> = bytes.iter().map(|_| huffman.read_one_code(&mut stream).unwrap()).collect();
+
+        assert_eq!(looked_up, bytes.iter().map(|&x| x as u32).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn check_progressive_ac_first() {
+        // Coefficients 1 to 5 with Al = 1
+        let mut coder = ProgressiveAcCoder::new(1, 5, 0, 1, 0x7FFF);
+        let mut codes = vec![];
+        let mut block = vec![0; 64];
+        block[10] = 50;
+
+        // Two blocks with nothing in the band join one end of block run
+        coder.code_block(&block, &mut codes);
+        coder.code_block(&block, &mut codes);
+        assert!(codes.is_empty());
+
+        // 5 >> 1 = 2 after one zero, and -3 becomes -1
+        block[2] = 5;
+        block[5] = -3;
+        coder.code_block(&block, &mut codes);
+        block[5] = 0;
+        coder.code_block(&block, &mut codes);
+        coder.finish(&mut codes);
+
+        assert_eq!(codes, vec![
+            ScanCode::Ac(JpegSymbol { symbol: 0x10, amplitude: 0, amplitude_length: 1 }),
+            ScanCode::Ac(JpegSymbol { symbol: 0x12, amplitude: 2, amplitude_length: 2 }),
+            ScanCode::Ac(JpegSymbol { symbol: 0x21, amplitude: 0, amplitude_length: 1 }),
+            ScanCode::Ac(JpegSymbol { symbol: 0x12, amplitude: 2, amplitude_length: 2 }),
+            ScanCode::Ac(JpegSymbol { symbol: 0x00, amplitude: 0, amplitude_length: 0 }),
+        ]);
+    }
+
+    #[test]
+    fn check_progressive_ac_refinement() {
+        // Refines coefficients 1 to 5 from Al = 1 to Al = 0, with single block EOB runs
+        let mut coder = ProgressiveAcCoder::new(1, 5, 1, 0, 1);
+        let mut codes = vec![];
+        let mut block = vec![0; 64];
+        block[1] = 3;
+        block[3] = 1;
+        block[5] = -2;
+
+        coder.code_block(&block, &mut codes);
+
+        // 3 and -2 were already sent and only get a correction bit. 1 is new, so it is coded
+        // with its sign and followed by the corrections before it. The last correction waits
+        // for the end of block.
+        assert_eq!(codes, vec![
+            ScanCode::Ac(JpegSymbol { symbol: 0x11, amplitude: 1, amplitude_length: 1 }),
+            ScanCode::Bits(1, 1),
+            ScanCode::Ac(JpegSymbol { symbol: 0x00, amplitude: 0, amplitude_length: 0 }),
+            ScanCode::Bits(0, 1),
+        ]);
+    }
+
+    #[test]
+    fn check_jpeg_value() {
+        for value in [-1025, -300, -17, -1, 0, 1, 2, 16, 255, 2047] {
+            let category = jpeg_category(value);
+            assert_eq!(jpeg_value(jpeg_amplitude(value, category), category), value);
+        }
+    }
+
+    #[test]
+    fn check_read_jpeg_block() {
+        let dc_table = crate::jpeg::JpegHuffmanTable::luma_dc();
+        let ac_table = crate::jpeg::JpegHuffmanTable::luma_ac();
+        let mut blocks = [vec![0; 64], vec![0; 64]];
+        blocks[0][0] = -20;
+        blocks[0][17] = 300;
+        blocks[0][63] = -1;
+        blocks[1][0] = 45;
+        blocks[1][5] = 3;
+
+        let mut stream = Bits::new(vec![], false, 0);
+        create_jpeg_stream(&jpeg_block_symbols(&blocks[0], 0), dc_table.get_huffman(), ac_table.get_huffman(), &mut stream).unwrap();
+        create_jpeg_stream(&jpeg_block_symbols(&blocks[1], -20), dc_table.get_huffman(), ac_table.get_huffman(), &mut stream).unwrap();
+
+        assert_eq!(read_jpeg_block(&mut stream, dc_table.get_huffman(), ac_table.get_huffman(), 0), Ok(blocks[0].clone()));
+        assert_eq!(read_jpeg_block(&mut stream, dc_table.get_huffman(), ac_table.get_huffman(), -20), Ok(blocks[1].clone()));
+        assert!(read_jpeg_block(&mut stream, dc_table.get_huffman(), ac_table.get_huffman(), 45).is_err());
+    }
+
+    #[test]
+    fn check_progressive_ac_decoder() {
+        let mut blocks = vec![vec![0; 64]; 6];
+        blocks[0][1] = 7;
+        blocks[0][40] = -3;
+        blocks[2][2] = -1;
+        blocks[2][3] = 12;
+        blocks[5][63] = 2;
+
+        // Coefficients 1 to 63 are sent with Al = 1 and then refined, with long end of band runs
+        let mut coded = vec![vec![0; 64]; 6];
+        for (approx_high, approx_low) in [(0, 1), (1, 0)] {
+            let mut coder = ProgressiveAcCoder::new(1, 63, approx_high, approx_low, 0x7FFF);
+            let mut codes = vec![];
+
+            for block in blocks.iter() {
+                coder.code_block(block, &mut codes);
+            }
+            coder.finish(&mut codes);
+
+            let mut frequencies = [0u32; 256];
+            for code in codes.iter() {
+                if let ScanCode::Ac(symbol) = code {
+                    frequencies[symbol.symbol as usize] += 1;
+                }
+            }
+
+            let table = crate::jpeg::JpegHuffmanTable::from_frequencies(&frequencies).unwrap();
+            let mut stream = Bits::new(vec![], false, 0);
+
+            for code in codes.iter() {
+                match code {
+                    ScanCode::Ac(symbol) => {
+                        table.get_huffman().write_one_code(symbol.symbol as u32, &mut stream).unwrap();
+                        stream.write_bits(symbol.amplitude, symbol.amplitude_length);
+                    },
+                    ScanCode::Bits(bits, length) => stream.write_bits(*bits, *length),
+                    ScanCode::Dc(_) => panic!("AC scans have no DC symbols"),
+                };
+            }
+
+            let mut decoder = ProgressiveAcDecoder::new(1, 63, approx_high, approx_low);
+            for block in coded.iter_mut() {
+                decoder.read_block(&mut stream, table.get_huffman(), block).unwrap();
+            }
+
+            assert_eq!(stream.len(), 0);
+        }
+
+        assert_eq!(coded, blocks);
+    }
+
+    #[test]
+    fn check_jpeg_block_symbols() {
+        let mut block = vec![0; 64];
+        block[0] = -20;
+        block[17] = 300;
+        block[63] = -1;
+
+        let symbols = jpeg_block_symbols(&block, -4);
+
+        assert_eq!(symbols, vec![
+            // DC diff -16 -> category 5, amplitude -17 in 5 bits
+            JpegSymbol { symbol: 0x05, amplitude: 0b01111, amplitude_length: 5 },
+            // 16 zeros then 300 -> ZRL, run 0, category 9
+            JpegSymbol { symbol: 0xF0, amplitude: 0, amplitude_length: 0 },
+            JpegSymbol { symbol: 0x09, amplitude: 300, amplitude_length: 9 },
+            // The last coefficient ends the block, so there's no EOB
+            JpegSymbol { symbol: 0xF0, amplitude: 0, amplitude_length: 0 },
+            JpegSymbol { symbol: 0xF0, amplitude: 0, amplitude_length: 0 },
+            JpegSymbol { symbol: 0xD1, amplitude: 0, amplitude_length: 1 },
+        ]);
+
+        // A flat block with the same DC as the last one is only a zero DC diff and an EOB
+        let mut flat = vec![0; 64];
+        flat[0] = 7;
+
+        assert_eq!(jpeg_block_symbols(&flat, 7), vec![
+            JpegSymbol { symbol: 0x00, amplitude: 0, amplitude_length: 0 },
+            JpegSymbol { symbol: 0x00, amplitude: 0, amplitude_length: 0 },
+        ]);
+    }
+
 
 }